@@ -0,0 +1,73 @@
+/// CRC-32 (IEEE 802.3) used to verify integrity of transferred data.
+/// The value is the same as the one calculated by common tools (e.g. `crc32` command or zlib).
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Crc32 {
+    const POLYNOMIAL: u32 = 0xedb88320;
+    const TABLE: [u32; 256] = Crc32::make_table();
+
+    pub fn new() -> Crc32 {
+        Crc32 { crc: 0xffffffff }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for x in data.iter() {
+            let i = ((self.crc ^ *x as u32) & 0xff) as usize;
+            self.crc = Crc32::TABLE[i] ^ (self.crc >> 8);
+        }
+    }
+
+    pub fn value(&self) -> u32 {
+        self.crc ^ 0xffffffff
+    }
+
+    const fn make_table() -> [u32; 256] {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut c = i as u32;
+            let mut k = 0;
+            while k < 8 {
+                c = if c & 1 != 0 {
+                    Crc32::POLYNOMIAL ^ (c >> 1)
+                } else {
+                    c >> 1
+                };
+                k += 1;
+            }
+            table[i] = c;
+            i += 1;
+        }
+        table
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Crc32::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        let mut crc = Crc32::new();
+        assert_eq!(crc.value(), 0);
+        crc.update(b"123456789");
+        assert_eq!(crc.value(), 0xcbf43926);
+    }
+
+    #[test]
+    fn test_crc32_with_multiple_updates() {
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"");
+        crc.update(b"56789");
+        assert_eq!(crc.value(), 0xcbf43926);
+    }
+}
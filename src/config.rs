@@ -0,0 +1,427 @@
+use crate::error::TftpError;
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+#[cfg(feature = "metrics")]
+use std::net::SocketAddr;
+use std::num::{NonZeroU16, NonZeroU32, NonZeroU64, NonZeroUsize};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Configuration of TftpServer and its handlers.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The maximum size of a datagram sent or received in transfers.
+    /// The negotiated blksize is clamped so that DATA packets fit in this size,
+    /// which avoids IP fragmentation when it is set based on the path MTU.
+    pub max_datagram_size: usize,
+    /// Messages sent in ERROR packets instead of the default ones.
+    pub error_messages: ErrorMessages,
+    /// Don't send ERROR packets in response to packets which are not well-formed TFTP.
+    /// This avoids confirming the service to scanners.
+    pub quiet_errors: bool,
+    /// Sync uploaded data to disk every this number of blocks in WRQ.
+    /// None means no intermediate sync, which is faster but loses all data on crash.
+    pub sync_interval: Option<NonZeroU32>,
+    /// Log CRC-32 of data sent by RRQ so that the integrity can be verified by operators.
+    pub rrq_checksum: bool,
+    /// How the server handles unexpected errors in its main loop.
+    pub error_policy: ErrorPolicy,
+    /// The maximum number of new transfers started per second (a burst up to this number is allowed).
+    /// Requests exceeding it are dropped silently. None means no limit.
+    pub max_new_transfers_per_sec: Option<NonZeroU32>,
+    /// The maximum number of ERROR packets sent from the server port per second
+    /// (a burst up to this number is allowed). Errors exceeding it are not sent.
+    /// This limits the traffic reflected to a spoofed source address. None means no limit.
+    pub max_errors_per_sec: Option<NonZeroU32>,
+    /// The maximum number of bytes sent by RRQ. Once it is used up, new RRQs are rejected
+    /// (transfers in progress are not stopped), e.g. to bound traffic on a metered link.
+    /// None means no limit.
+    pub egress_budget: Option<NonZeroU64>,
+    /// Renew `egress_budget` at this interval (e.g. daily). None means it is never renewed.
+    pub egress_budget_period: Option<Duration>,
+    /// The maximum number of transfers in progress, each of which runs in its own thread.
+    /// More requests are rejected with "Server busy". None means no limit.
+    pub max_active_transfers: Option<NonZeroUsize>,
+    /// Retransmit the last packet if no response comes in this interval.
+    pub retry_interval: Duration,
+    /// Give up a transfer after retransmitting a packet this number of times.
+    pub max_retries: NonZeroU16,
+    /// Serve (or overwrite) a symbolic link to a regular file.
+    /// Special files (e.g. FIFO or device) are never served regardless of this.
+    pub follow_symlinks: bool,
+    /// The maximum windowsize accepted in RRQ, which is capped at `Config::MAX_WINDOW_SIZE`.
+    /// DATA blocks in a window are kept in memory until they are acknowledged.
+    pub max_window_size: u16,
+    /// Start RRQ with a window of one block and adjust it by AIMD up to the negotiated windowsize.
+    /// This needs a client which acknowledges the blocks received so far once DATA stop arriving.
+    /// A client which acknowledges only after windowsize blocks (as in RFC 7440) stalls until
+    /// its timeout on every window smaller than the negotiated one.
+    pub adaptive_window: bool,
+    /// Set IP_FREEBIND to the server socket so that it can be bound to an address
+    /// not assigned yet (e.g. a virtual IP in failover). Only supported on Linux.
+    pub freebind: bool,
+    /// Log a summary of transfers at this interval. None means no periodic log.
+    pub announce_interval: Option<Duration>,
+    /// Reject all WRQs with AccessViolation, e.g. to serve boot images.
+    pub read_only: bool,
+    /// Reject all RRQs with AccessViolation, e.g. to collect uploads which must not be read back.
+    pub write_only: bool,
+    /// Reject WRQ with FileExists if the destination already exists, e.g. for write-once storage.
+    pub no_clobber: bool,
+    /// Stage uploads in the destination directory instead of the temporary directory.
+    /// This avoids copying across filesystems when the upload completes.
+    pub stage_in_place: bool,
+    /// Create missing directories of a WRQ destination (a subdirectory mapped by `filename_map`)
+    /// with this mode, which is masked by umask. None means such an upload fails with FileNotFound.
+    pub create_dirs: Option<u32>,
+    /// Reject requests in netascii mode with IllegalTftpOp.
+    pub octet_only: bool,
+    /// How netascii uploads are stored.
+    pub netascii_store: NetasciiStore,
+    /// How RRQ ends when the ACK for the last DATA doesn't arrive after retransmissions.
+    pub final_ack_policy: FinalAckPolicy,
+    /// Accept the nonstandard blksize2 option sent by some PXE stacks,
+    /// which requires blksize to be a power of two.
+    pub blksize2: bool,
+    /// Accept the nonstandard startblock option in RRQ to resume a download from the block.
+    /// The preceding blocks are read and discarded, so it works for any file served.
+    pub partial_get: bool,
+    /// The size of the receive buffer (SO_RCVBUF) of the server socket.
+    /// A larger buffer keeps more requests queued in a burst (e.g. many clients booting at once).
+    /// None means the default of OS.
+    pub recv_buffer_size: Option<usize>,
+    /// Discard an upload if its size doesn't match tsize sent by the client (only in octet mode),
+    /// which catches an upload truncated by the client.
+    pub verify_tsize: bool,
+    /// Copy each completed upload to these directories as well (best-effort), e.g. for backup.
+    pub mirror_dirs: Vec<PathBuf>,
+    /// Serve `<name>.gz` decompressed if a requested file `<name>` doesn't exist.
+    /// This saves disk for large images. The content must be smaller than 4 GiB for tsize.
+    pub serve_gzip: bool,
+    /// Stop the server after this number of transfers (and wait for them to finish),
+    /// e.g. to be restarted by a supervisor periodically. None means no limit.
+    pub max_transfers: Option<NonZeroU64>,
+    /// Glob patterns (`*` and `?`) of requested filenames whose misses are dropped without
+    /// replying FileNotFound, e.g. `pxelinux.cfg/*` probed by PXE clients one after another.
+    pub silent_miss_patterns: Vec<String>,
+    /// Static rewrites of requested filenames, e.g. to serve a file under another name without symlinks.
+    pub filename_map: FilenameMap,
+    /// Read files served by RRQ with O_DIRECT to bypass the page cache,
+    /// e.g. not to evict other data by large images on a server with little memory.
+    /// Only supported on Linux and filesystems supporting O_DIRECT.
+    pub direct_io: bool,
+    /// Serve stats in the Prometheus text format at `http://<addr>/metrics`.
+    /// None means no exporter.
+    #[cfg(feature = "metrics")]
+    pub metrics_addr: Option<SocketAddr>,
+}
+
+impl Config {
+    /// The maximum of blksize defined in RFC 2348.
+    pub const MAX_BLOCK_SIZE: u16 = 65464;
+    /// The size of opcode and block number in DATA packet.
+    pub const DATA_HEADER_SIZE: usize = 4;
+    /// The maximum windowsize accepted regardless of the configuration.
+    /// A window must be within half of the block number space to tell a stale ACK from a new one.
+    pub const MAX_WINDOW_SIZE: u16 = i16::MAX as u16;
+
+    /// The maximum blksize which can be accepted with this configuration.
+    pub fn max_block_size(&self) -> u16 {
+        let size = self
+            .max_datagram_size
+            .saturating_sub(Config::DATA_HEADER_SIZE);
+        u16::try_from(size)
+            .unwrap_or(Config::MAX_BLOCK_SIZE)
+            .min(Config::MAX_BLOCK_SIZE)
+    }
+
+    /// Whether a miss of the requested filename is dropped silently.
+    pub fn is_silent_miss(&self, filename: &str) -> bool {
+        self.silent_miss_patterns
+            .iter()
+            .any(|pattern| glob_match(pattern.as_bytes(), filename.as_bytes()))
+    }
+}
+
+/// Match `name` with `pattern`, where `*` matches any sequence (including '/')
+/// and `?` matches any single byte.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|i| glob_match(rest, &name[i..])),
+        Some((b'?', rest)) => !name.is_empty() && glob_match(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && glob_match(rest, &name[1..]),
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_datagram_size: Config::MAX_BLOCK_SIZE as usize + Config::DATA_HEADER_SIZE,
+            error_messages: ErrorMessages::default(),
+            quiet_errors: false,
+            sync_interval: None,
+            rrq_checksum: false,
+            error_policy: ErrorPolicy::Continue,
+            max_new_transfers_per_sec: None,
+            max_errors_per_sec: None,
+            egress_budget: None,
+            egress_budget_period: None,
+            max_active_transfers: NonZeroUsize::new(64),
+            retry_interval: Duration::from_secs(5),
+            max_retries: NonZeroU16::new(4).unwrap(),
+            follow_symlinks: true,
+            max_window_size: 64,
+            adaptive_window: false,
+            freebind: false,
+            announce_interval: None,
+            read_only: false,
+            write_only: false,
+            no_clobber: false,
+            stage_in_place: false,
+            create_dirs: None,
+            octet_only: false,
+            netascii_store: NetasciiStore::Unix,
+            final_ack_policy: FinalAckPolicy::Required,
+            blksize2: false,
+            partial_get: false,
+            recv_buffer_size: None,
+            verify_tsize: false,
+            mirror_dirs: vec![],
+            serve_gzip: false,
+            max_transfers: None,
+            silent_miss_patterns: vec![],
+            filename_map: FilenameMap::default(),
+            direct_io: false,
+            #[cfg(feature = "metrics")]
+            metrics_addr: None,
+        }
+    }
+}
+
+/// Policy for unexpected errors in the main loop of the server (e.g. failure of recv).
+/// Errors in handlers only fail the transfer regardless of the policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Log the error and continue to serve.
+    Continue,
+    /// Stop the server. This is useful when the server is restarted by a supervisor.
+    Abort,
+}
+
+/// How data uploaded in netascii is stored to files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetasciiStore {
+    /// Decode to the local format of unix (CR LF to LF, CR NUL to CR).
+    Unix,
+    /// Store the data as is on the wire.
+    Verbatim,
+    /// Decode only CR NUL to CR, which keeps CR LF as line endings.
+    Crlf,
+}
+
+impl FromStr for NetasciiStore {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unix" => Ok(NetasciiStore::Unix),
+            "verbatim" => Ok(NetasciiStore::Verbatim),
+            "crlf" => Ok(NetasciiStore::Crlf),
+            _ => Err(format!("unknown netascii store policy: {}", s)),
+        }
+    }
+}
+
+/// How RRQ ends when the last DATA is retransmitted up to the limit without being acknowledged.
+/// The last DATA is retransmitted on timeout in either case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinalAckPolicy {
+    /// Fail the transfer by timeout.
+    Required,
+    /// Complete the transfer. The client may have received all data and only its ACK was lost,
+    /// e.g. a client which exits right after sending the last ACK (RFC 1350 allows this).
+    Optional,
+}
+
+impl FromStr for FinalAckPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "required" => Ok(FinalAckPolicy::Required),
+            "optional" => Ok(FinalAckPolicy::Optional),
+            _ => Err(format!("unknown final ack policy: {}", s)),
+        }
+    }
+}
+
+/// Custom messages of ERROR packets for each TftpError.
+/// This is useful to localize messages or to hide details of errors from clients.
+#[derive(Debug, Clone, Default)]
+pub struct ErrorMessages {
+    messages: HashMap<TftpError, String>,
+}
+
+impl ErrorMessages {
+    /// Use `msg` for all errors.
+    pub fn generic(msg: &str) -> ErrorMessages {
+        let messages = (0..=7)
+            .filter_map(TftpError::from_u16)
+            .map(|err| (err, msg.to_string()))
+            .collect();
+        ErrorMessages { messages }
+    }
+
+    pub fn with(mut self, err: TftpError, msg: &str) -> ErrorMessages {
+        self.messages.insert(err, msg.to_string());
+        self
+    }
+
+    pub fn get(&self, err: TftpError) -> Option<&str> {
+        self.messages.get(&err).map(String::as_str)
+    }
+}
+
+/// Rewrites of requested filenames to files in the base directory.
+#[derive(Debug, Clone, Default)]
+pub struct FilenameMap {
+    mappings: HashMap<String, String>,
+}
+
+impl FilenameMap {
+    /// Serve (or upload to) `to` when `from` is requested (compared with the filename as requested).
+    pub fn with(mut self, from: &str, to: &str) -> FilenameMap {
+        self.mappings.insert(from.to_string(), to.to_string());
+        self
+    }
+
+    /// The path of the file relative to the base directory for a request.
+    /// `raw_filename` is looked up in the mappings, and `filename` is returned as is if not found.
+    /// A target may be in a subdirectory (e.g. `pxelinux.cfg/default`), but it is not validated here.
+    pub fn target(&self, raw_filename: &str, filename: &str) -> PathBuf {
+        match self.mappings.get(raw_filename) {
+            // normalize e.g. "a//./b" so that the same file always has the same target
+            Some(to) => Path::new(to).components().collect(),
+            None => PathBuf::from(filename),
+        }
+    }
+
+    /// The path of the file under `base_dir` for a request.
+    /// Fails with PermissionDenied if the target of a mapping is absolute, contains `..`
+    /// or leads outside `base_dir` via symlinks, and with NotFound if its directory doesn't exist.
+    pub fn resolve(
+        &self,
+        base_dir: &Path,
+        raw_filename: &str,
+        filename: &str,
+    ) -> io::Result<PathBuf> {
+        if !self.mappings.contains_key(raw_filename) {
+            return Ok(base_dir.join(filename));
+        }
+        let target = self.target(raw_filename, filename);
+        let reject = || {
+            io::Error::new(
+                ErrorKind::PermissionDenied,
+                format!("{:?} is mapped outside the base directory", raw_filename),
+            )
+        };
+        // an empty target (e.g. ".") would be the base directory itself
+        if target.file_name().is_none()
+            || target
+                .components()
+                .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            return Err(reject());
+        }
+        let path = base_dir.join(&target);
+        // the last component is left to the handlers, which check symlinks by themselves
+        let dir = path.parent().unwrap_or(base_dir).canonicalize()?;
+        if !dir.starts_with(base_dir.canonicalize()?) {
+            return Err(reject());
+        }
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_messages() {
+        let messages = ErrorMessages::default().with(TftpError::FileNotFound, "foo");
+        assert_eq!(messages.get(TftpError::FileNotFound), Some("foo"));
+        assert_eq!(messages.get(TftpError::AccessViolation), None);
+
+        let messages = ErrorMessages::generic("error").with(TftpError::FileNotFound, "foo");
+        assert_eq!(messages.get(TftpError::FileNotFound), Some("foo"));
+        assert_eq!(messages.get(TftpError::AccessViolation), Some("error"));
+        assert_eq!(messages.get(TftpError::NoSuchUser), Some("error"));
+    }
+
+    #[test]
+    fn test_is_silent_miss() {
+        let config = Config {
+            silent_miss_patterns: vec!["pxelinux.cfg/*".to_string(), "?.cfg".to_string()],
+            ..Config::default()
+        };
+        assert!(config.is_silent_miss("pxelinux.cfg/01-aa-bb-cc-dd-ee-ff"));
+        assert!(config.is_silent_miss("pxelinux.cfg/"));
+        assert!(config.is_silent_miss("a.cfg"));
+        assert!(!config.is_silent_miss("ab.cfg"));
+        assert!(!config.is_silent_miss("pxelinux.0"));
+        assert!(!Config::default().is_silent_miss("pxelinux.cfg/default"));
+    }
+
+    #[test]
+    fn test_filename_map() {
+        let base_dir = crate::temp::create_temp_dir().unwrap();
+        let base = base_dir.path();
+        std::fs::create_dir(base.join("pxelinux.cfg")).unwrap();
+        std::os::unix::fs::symlink("/etc", base.join("etc")).unwrap();
+        let map = FilenameMap::default()
+            .with("config", "default.cfg")
+            .with("pxe", "pxelinux.cfg//./default")
+            .with("missing", "nodir/default")
+            .with("escape", "../../etc/passwd")
+            .with("inner", "pxelinux.cfg/../default.cfg")
+            .with("absolute", "/etc/passwd")
+            .with("symlink", "etc/passwd")
+            .with("parent", "..")
+            .with("current", ".");
+
+        assert_eq!(
+            map.resolve(base, "config", "config").unwrap(),
+            base.join("default.cfg")
+        );
+        assert_eq!(
+            map.resolve(base, "pxe", "pxe").unwrap(),
+            base.join("pxelinux.cfg/default")
+        );
+        assert_eq!(
+            map.target("pxe", "pxe"),
+            PathBuf::from("pxelinux.cfg/default")
+        );
+        assert_eq!(
+            map.resolve(base, "foo/config", "config").unwrap(),
+            base.join("config")
+        );
+        assert_eq!(map.target("foo/config", "config"), PathBuf::from("config"));
+        assert_eq!(
+            map.resolve(base, "missing", "missing").unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        for name in [
+            "escape", "inner", "absolute", "symlink", "parent", "current",
+        ] {
+            assert_eq!(
+                map.resolve(base, name, name).unwrap_err().kind(),
+                ErrorKind::PermissionDenied,
+                "{}",
+                name
+            );
+        }
+    }
+}
@@ -1,12 +1,80 @@
+use crate::config::ErrorMessages;
 use crate::packet;
-use anyhow::Result;
+use crate::stats::ServerStats;
 use log::error;
 use std::fmt::Formatter;
 use std::io::ErrorKind;
 use std::net::{SocketAddr, UdpSocket};
 use std::{error, fmt, io};
 
+/// The error type of the public API.
 #[derive(Debug)]
+pub enum Error {
+    /// Failure of I/O on files or sockets.
+    Io(io::Error),
+    /// Unexpected behavior of the peer (e.g. a malformed packet).
+    Protocol(String),
+    /// The peer didn't respond to a packet even after it was retransmitted `retries` times.
+    Timeout { retries: u16 },
+    /// The transfer was cancelled by the operator.
+    Cancelled,
+    /// Invalid configuration or usage of the server.
+    Config(String),
+    /// Failure of changing users, groups or permissions.
+    Privilege(String),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::Protocol(msg) => write!(f, "protocol error: {}", msg),
+            Error::Timeout { retries } => write!(f, "timeout after {} retries", retries),
+            Error::Cancelled => f.write_str("cancelled"),
+            Error::Config(msg) => write!(f, "configuration error: {}", msg),
+            Error::Privilege(msg) => write!(f, "privilege error: {}", msg),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+/// Errors constructed by anyhow internally are classified by their root cause.
+/// Errors without a known cause are regarded as Protocol because most of them come from transfers.
+impl From<anyhow::Error> for Error {
+    fn from(err: anyhow::Error) -> Self {
+        let err = match err.downcast::<Error>() {
+            Ok(err) => return err,
+            Err(err) => err,
+        };
+        // keep the context in the message
+        let msg = format!("{:#}", err);
+        if let Some(io_err) = err.downcast_ref::<io::Error>() {
+            Error::Io(io::Error::new(io_err.kind(), msg))
+        } else if let Some(errno) = err.downcast_ref::<nix::errno::Errno>() {
+            Error::Io(io::Error::new(io::Error::from(*errno).kind(), msg))
+        } else {
+            Error::Protocol(msg)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum TftpError {
     Others,
     FileNotFound,
@@ -35,14 +103,14 @@ impl TftpError {
 
     pub fn error_code(&self) -> u16 {
         match self {
-            TftpError::Others => (0x0_u16),
-            TftpError::FileNotFound => (0x1_u16),
-            TftpError::AccessViolation => (0x2_u16),
-            TftpError::DiskNoSpace => (0x3_u16),
-            TftpError::IllegalTftpOp => (0x4_u16),
-            TftpError::UnknownTid => (0x5_u16),
-            TftpError::FileExists => (0x6_u16),
-            TftpError::NoSuchUser => (0x7_u16),
+            TftpError::Others => 0x0_u16,
+            TftpError::FileNotFound => 0x1_u16,
+            TftpError::AccessViolation => 0x2_u16,
+            TftpError::DiskNoSpace => 0x3_u16,
+            TftpError::IllegalTftpOp => 0x4_u16,
+            TftpError::UnknownTid => 0x5_u16,
+            TftpError::FileExists => 0x6_u16,
+            TftpError::NoSuchUser => 0x7_u16,
         }
     }
 }
@@ -65,47 +133,117 @@ impl fmt::Display for TftpError {
 impl error::Error for TftpError {}
 
 pub trait TftpErrorNotifier<T, E> {
-    fn notify_error(self, sock: &UdpSocket, client_addr: &SocketAddr) -> Result<T, E>;
+    fn notify_error(
+        self,
+        sock: &UdpSocket,
+        client_addr: &SocketAddr,
+        messages: &ErrorMessages,
+        stats: &ServerStats,
+    ) -> Result<T, E>;
 }
 
 impl<T> TftpErrorNotifier<T, io::Error> for Result<T, io::Error> {
-    fn notify_error(self, sock: &UdpSocket, client_addr: &SocketAddr) -> Result<T, io::Error> {
-        self.map_err(|err| match err.kind() {
-            ErrorKind::NotFound => {
-                send_error_packet(
-                    sock,
-                    client_addr,
-                    TftpError::FileNotFound,
-                    "File not found".to_string(),
-                );
-                err
-            }
-            ErrorKind::PermissionDenied => {
-                send_error_packet(
-                    sock,
-                    client_addr,
-                    TftpError::AccessViolation,
-                    "Permission denied".to_string(),
-                );
-                err
-            }
-            _ => {
-                send_error_packet(
-                    sock,
-                    client_addr,
-                    TftpError::Others,
-                    "Unexpected error".to_string(),
-                );
-                err
-            }
+    fn notify_error(
+        self,
+        sock: &UdpSocket,
+        client_addr: &SocketAddr,
+        messages: &ErrorMessages,
+        stats: &ServerStats,
+    ) -> Result<T, io::Error> {
+        self.inspect_err(|err| send_io_error_packet(sock, client_addr, err, messages, stats))
+    }
+}
+
+/// The error packet is decided by the inner io::Error if exists.
+impl<T> TftpErrorNotifier<T, Error> for Result<T, Error> {
+    fn notify_error(
+        self,
+        sock: &UdpSocket,
+        client_addr: &SocketAddr,
+        messages: &ErrorMessages,
+        stats: &ServerStats,
+    ) -> Result<T, Error> {
+        self.inspect_err(|err| match err {
+            Error::Io(io_err) => send_io_error_packet(sock, client_addr, io_err, messages, stats),
+            _ => send_error_packet(
+                sock,
+                client_addr,
+                TftpError::Others,
+                "Unexpected error".to_string(),
+                messages,
+                stats,
+            ),
         })
     }
 }
 
-fn send_error_packet(sock: &UdpSocket, client_addr: &SocketAddr, tftp_err: TftpError, msg: String) {
+/// The error packet is decided by the inner io::Error if exists.
+impl<T> TftpErrorNotifier<T, anyhow::Error> for Result<T, anyhow::Error> {
+    fn notify_error(
+        self,
+        sock: &UdpSocket,
+        client_addr: &SocketAddr,
+        messages: &ErrorMessages,
+        stats: &ServerStats,
+    ) -> Result<T, anyhow::Error> {
+        self.inspect_err(|err| match err.downcast_ref::<io::Error>() {
+            Some(io_err) => send_io_error_packet(sock, client_addr, io_err, messages, stats),
+            None => send_error_packet(
+                sock,
+                client_addr,
+                TftpError::Others,
+                "Unexpected error".to_string(),
+                messages,
+                stats,
+            ),
+        })
+    }
+}
+
+fn send_io_error_packet(
+    sock: &UdpSocket,
+    client_addr: &SocketAddr,
+    err: &io::Error,
+    messages: &ErrorMessages,
+    stats: &ServerStats,
+) {
+    let (tftp_err, msg) = match err.kind() {
+        ErrorKind::NotFound => (TftpError::FileNotFound, "File not found"),
+        ErrorKind::PermissionDenied => (TftpError::AccessViolation, "Permission denied"),
+        ErrorKind::AlreadyExists => (TftpError::FileExists, "File already exists"),
+        _ if is_no_space(err) => (TftpError::DiskNoSpace, "Disk full or allocation exceeded"),
+        _ => (TftpError::Others, "Unexpected error"),
+    };
+    send_error_packet(
+        sock,
+        client_addr,
+        tftp_err,
+        msg.to_string(),
+        messages,
+        stats,
+    );
+}
+
+fn is_no_space(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(nix::libc::ENOSPC) | Some(nix::libc::EFBIG) | Some(nix::libc::EDQUOT)
+    )
+}
+
+pub(crate) fn send_error_packet(
+    sock: &UdpSocket,
+    client_addr: &SocketAddr,
+    tftp_err: TftpError,
+    msg: String,
+    messages: &ErrorMessages,
+    stats: &ServerStats,
+) {
+    // the configured message takes precedence over the default one
+    let msg = messages.get(tftp_err).map(str::to_string).unwrap_or(msg);
     let pkt = packet::Error::new(tftp_err, msg);
     match sock.send_to(&pkt.encode(), client_addr) {
-        Ok(_) => (),
+        Ok(_) => stats.error_sent(tftp_err),
         Err(err) => error!(
             "Failed to send an error packet ({:?}), but ignore it: {:?}",
             pkt, err
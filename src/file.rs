@@ -1,79 +1,322 @@
+use crate::config::NetasciiStore;
+use crate::gzip::{self, GzipDecoder};
 use crate::packet;
 use std::io::{Read, Write};
-use std::path::Path;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Component, Path};
 use std::{fs, io};
 
 /// This is a wrapper of std::fs::File.
 /// The main purpose is parse and encode file content based on netascii if requested.
-pub struct File {
-    inner: fs::File,
+/// The inner can be any reader or writer for testing.
+pub struct File<T = fs::File> {
+    inner: T,
     read_buf: Vec<u8>,
     write_buf: Vec<u8>,
     mode: packet::Mode,
+    netascii_store: NetasciiStore,
     is_started: bool,
     is_finished: bool,
 }
 
 impl File {
-    pub fn open(path: impl AsRef<Path>, mode: packet::Mode) -> io::Result<File> {
-        let inner = fs::File::open(path)?;
-        let read_buf = vec![];
-        let write_buf = vec![];
-        Ok(File {
+    pub fn create(path: impl AsRef<Path>, mode: packet::Mode) -> io::Result<File> {
+        let inner = fs::File::create(path)?;
+        Ok(File::from_inner(inner, mode))
+    }
+
+    /// Reserve disk space for the file in advance without changing its size.
+    /// It does nothing if the underlying filesystem or platform doesn't support it.
+    pub fn allocate(&self, len: u64) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            use nix::fcntl::{fallocate, FallocateFlags};
+            use std::os::unix::io::AsRawFd;
+
+            let len =
+                i64::try_from(len).map_err(|_| io::Error::from_raw_os_error(nix::libc::EFBIG))?;
+            match fallocate(
+                self.inner.as_raw_fd(),
+                FallocateFlags::FALLOC_FL_KEEP_SIZE,
+                0,
+                len,
+            ) {
+                Ok(_) | Err(nix::errno::Errno::EOPNOTSUPP) => Ok(()),
+                Err(err) => Err(err.into()),
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = len;
+            Ok(())
+        }
+    }
+
+    /// Sync data written to the underlying file to disk.
+    /// Note that a trailing '\r' of netascii is not written yet until the next byte comes.
+    pub fn sync_data(&self) -> io::Result<()> {
+        self.inner.sync_data()
+    }
+}
+
+impl File<Box<dyn Read>> {
+    /// Open a regular file to be served and return it with the size of its content.
+    /// If the file doesn't exist and `gzip_fallback` is true, `<path>.gz` is served
+    /// decompressed instead (the size is taken from its trailer).
+    /// If `direct_io` is true, the file (but not `<path>.gz`) is read bypassing the page cache.
+    pub fn open_to_serve(
+        path: &Path,
+        mode: packet::Mode,
+        follow_symlinks: bool,
+        gzip_fallback: bool,
+        direct_io: bool,
+    ) -> io::Result<(File<Box<dyn Read>>, u64)> {
+        let err = match open_regular_file(path, follow_symlinks, direct_io) {
+            Ok(inner) => {
+                let size = inner.metadata()?.len();
+                let inner: Box<dyn Read> = if direct_io {
+                    Box::new(DirectReader::new(inner))
+                } else {
+                    Box::new(inner)
+                };
+                return Ok((File::from_inner(inner, mode), size));
+            }
+            Err(err) if gzip_fallback && err.kind() == io::ErrorKind::NotFound => err,
+            Err(err) => return Err(err),
+        };
+        let mut gz_path = path.as_os_str().to_owned();
+        gz_path.push(".gz");
+        let mut inner = match open_regular_file(Path::new(&gz_path), follow_symlinks, false) {
+            Ok(inner) => inner,
+            // report that the requested file is not found
+            Err(gz_err) if gz_err.kind() == io::ErrorKind::NotFound => return Err(err),
+            Err(gz_err) => return Err(gz_err),
+        };
+        let size = gzip::uncompressed_size(&mut inner)?;
+        let decoder = GzipDecoder::new(inner);
+        Ok((File::from_inner(Box::new(decoder), mode), size))
+    }
+}
+
+fn open_regular_file(path: &Path, follow_symlinks: bool, direct_io: bool) -> io::Result<fs::File> {
+    let mut flags = nix::libc::O_NONBLOCK;
+    if !follow_symlinks {
+        flags |= nix::libc::O_NOFOLLOW;
+    }
+    if direct_io {
+        flags |= o_direct()?;
+    }
+    let inner = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(flags)
+        .open(path)
+        .map_err(|err| match err.raw_os_error() {
+            // returned by O_NOFOLLOW for a symbolic link
+            Some(nix::libc::ELOOP) => not_regular_file_error(),
+            _ => err,
+        })?;
+    if !inner.metadata()?.is_file() {
+        return Err(not_regular_file_error());
+    }
+    // O_NONBLOCK is just for opening a FIFO, and has no effect to a regular file
+    Ok(inner)
+}
+
+#[cfg(target_os = "linux")]
+fn o_direct() -> io::Result<i32> {
+    Ok(nix::libc::O_DIRECT)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn o_direct() -> io::Result<i32> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "direct IO is not supported on this platform",
+    ))
+}
+
+/// The alignment of the buffer, offset and length of reads required by O_DIRECT.
+/// This is a multiple of the logical block size of most devices.
+const DIRECT_IO_ALIGN: usize = 4096;
+/// The size of a read from a file opened with O_DIRECT.
+/// It is larger than blocks of TFTP since every read goes to the device.
+const DIRECT_IO_CHUNK_SIZE: usize = 256 * 1024;
+
+/// A reader of a file opened with O_DIRECT, which reads the file in aligned chunks
+/// and serves the data in reads of any size.
+struct DirectReader {
+    inner: fs::File,
+    buf: Vec<u8>,
+    /// The offset of the aligned chunk in buf.
+    start: usize,
+    pos: usize,
+    len: usize,
+    is_eof: bool,
+}
+
+impl DirectReader {
+    fn new(inner: fs::File) -> DirectReader {
+        let buf = vec![0; DIRECT_IO_CHUNK_SIZE + DIRECT_IO_ALIGN];
+        let start = buf.as_ptr().align_offset(DIRECT_IO_ALIGN);
+        DirectReader {
             inner,
-            read_buf,
-            write_buf,
-            mode,
-            is_started: false,
-            is_finished: false,
-        })
+            buf,
+            start,
+            pos: 0,
+            len: 0,
+            is_eof: false,
+        }
     }
+}
 
-    pub fn create(path: impl AsRef<Path>, mode: packet::Mode) -> io::Result<File> {
-        let inner = fs::File::create(path)?;
-        let read_buf = vec![];
-        let write_buf = vec![];
-        Ok(File {
+impl Read for DirectReader {
+    fn read(&mut self, data: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.len {
+            if self.is_eof {
+                return Ok(0);
+            }
+            let chunk = &mut self.buf[self.start..self.start + DIRECT_IO_CHUNK_SIZE];
+            let n = self.inner.read(chunk)?;
+            // a short read happens only at the end of file,
+            // after which the offset is not aligned anymore
+            self.is_eof = n < DIRECT_IO_CHUNK_SIZE;
+            self.pos = 0;
+            self.len = n;
+        }
+        let n = data.len().min(self.len - self.pos);
+        let from = self.start + self.pos;
+        data[..n].copy_from_slice(&self.buf[from..from + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Check if a file can be overwritten by an upload.
+/// It is ok if the file doesn't exist, otherwise it must be a regular file
+/// (or a symbolic link to it if `follow_symlinks` is true).
+pub fn check_writable_destination(path: impl AsRef<Path>, follow_symlinks: bool) -> io::Result<()> {
+    let path = path.as_ref();
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    if metadata.file_type().is_symlink() {
+        if !follow_symlinks {
+            return Err(not_regular_file_error());
+        }
+        return match fs::metadata(path) {
+            Ok(metadata) if metadata.is_file() => Ok(()),
+            Ok(_) => Err(not_regular_file_error()),
+            // a dangling link is created as a regular file
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        };
+    }
+    if metadata.is_file() {
+        Ok(())
+    } else {
+        Err(not_regular_file_error())
+    }
+}
+
+/// Create missing parent directories of `path` in `base_dir` with `mode`, e.g. for an upload to a subdirectory.
+/// A directory is never created outside `base_dir`, even through a symbolic link in the middle.
+pub fn create_parent_dirs(base_dir: &Path, path: &Path, mode: u32) -> io::Result<()> {
+    let outside_error = || {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "outside the base directory",
+        )
+    };
+    let parent = match path.parent() {
+        Some(parent) => parent,
+        None => return Ok(()),
+    };
+    let relative = parent.strip_prefix(base_dir).map_err(|_| outside_error())?;
+    let base_dir = base_dir.canonicalize()?;
+    let mut dir = base_dir.clone();
+    for component in relative.components() {
+        match component {
+            Component::Normal(name) => dir.push(name),
+            _ => return Err(outside_error()),
+        }
+        match fs::DirBuilder::new().mode(mode).create(&dir) {
+            Ok(()) => (),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => (),
+            Err(err) => return Err(err),
+        }
+        // an existing one may be a symbolic link, so check where it is before going into it
+        dir = dir.canonicalize()?;
+        if !dir.starts_with(&base_dir) {
+            return Err(outside_error());
+        }
+    }
+    Ok(())
+}
+
+fn not_regular_file_error() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "not a regular file")
+}
+
+impl<T> File<T> {
+    pub fn from_inner(inner: T, mode: packet::Mode) -> File<T> {
+        File {
             inner,
-            read_buf,
-            write_buf,
+            read_buf: vec![],
+            write_buf: vec![],
             mode,
+            netascii_store: NetasciiStore::Unix,
             is_started: false,
             is_finished: false,
-        })
+        }
     }
 
-    fn read_data_from_inner(&mut self) -> io::Result<usize> {
-        let mut buf = [0; 512];
-        let n_buf = self.inner.read(&mut buf)?;
+    /// Set how netascii data is decoded on write.
+    pub fn with_netascii_store(mut self, netascii_store: NetasciiStore) -> File<T> {
+        self.netascii_store = netascii_store;
+        self
+    }
+
+    pub fn has_next(&self) -> bool {
+        // FIXME: this is just for read
+        !self.is_started || !self.is_finished
+    }
+}
 
+impl<T: Read> File<T> {
+    /// Read at most `max` bytes from inner and append them to read_buf.
+    /// netascii may double the size in read_buf (e.g. all bytes are '\n').
+    fn read_data_from_inner(&mut self, max: usize) -> io::Result<usize> {
         let initial_len = self.read_buf.len();
 
-        for x in buf[..n_buf].iter() {
-            #[allow(clippy::collapsible_else_if)]
-            if self.mode == packet::Mode::OCTET {
-                self.read_buf.push(*x);
-            } else {
-                if *x == b'\r' {
-                    self.read_buf.append(&mut vec![b'\r', b'\0']);
-                } else if *x == b'\n' {
-                    self.read_buf.append(&mut vec![b'\r', b'\n']);
-                } else {
-                    self.read_buf.push(*x);
+        // read into read_buf directly, so that a large block costs a single read from inner
+        self.read_buf.resize(initial_len + max, 0);
+        let n_buf = match self.inner.read(&mut self.read_buf[initial_len..]) {
+            Ok(n) => n,
+            Err(err) => {
+                self.read_buf.truncate(initial_len);
+                return Err(err);
+            }
+        };
+        self.read_buf.truncate(initial_len + n_buf);
+
+        if self.mode == packet::Mode::NETASCII {
+            let raw = self.read_buf.split_off(initial_len);
+            for x in raw {
+                match x {
+                    b'\r' => self.read_buf.extend_from_slice(b"\r\x00"),
+                    b'\n' => self.read_buf.extend_from_slice(b"\r\n"),
+                    _ => self.read_buf.push(x),
                 }
             }
         }
 
         Ok(self.read_buf.len() - initial_len)
     }
-
-    pub fn has_next(&self) -> bool {
-        // FIXME: this is just for read
-        !self.is_started || !self.is_finished
-    }
 }
 
-impl Read for File {
+impl<T: Read> Read for File<T> {
     fn read(&mut self, data: &mut [u8]) -> io::Result<usize> {
         if self.is_finished {
             return Ok(0);
@@ -83,15 +326,23 @@ impl Read for File {
             self.is_started = true;
         }
 
-        self.read_data_from_inner()?;
+        // The length of data is regarded as the block size.
+        let block_size = data.len();
 
-        let n = std::cmp::min(512, self.read_buf.len());
-        // FIXME: is it efficient enough?
-        for (i, x) in self.read_buf.drain(0..n).enumerate() {
-            data[i] = x;
+        // Fill read_buf only up to one block so that memory usage doesn't depend on the file size.
+        // This also covers short reads from inner.
+        // Reading only the shortage keeps read_buf within two blocks even if netascii expands data.
+        while self.read_buf.len() < block_size {
+            if self.read_data_from_inner(block_size - self.read_buf.len())? == 0 {
+                break;
+            }
         }
 
-        if n < 512 {
+        let n = std::cmp::min(block_size, self.read_buf.len());
+        data[..n].copy_from_slice(&self.read_buf[..n]);
+        self.read_buf.drain(..n);
+
+        if n < block_size {
             self.is_finished = true;
         }
 
@@ -99,11 +350,11 @@ impl Read for File {
     }
 }
 
-impl Write for File {
+impl<T: Write> Write for File<T> {
     fn write(&mut self, data: &[u8]) -> io::Result<usize> {
         self.is_started = true;
 
-        if self.mode == packet::Mode::OCTET {
+        if self.mode == packet::Mode::OCTET || self.netascii_store == NetasciiStore::Verbatim {
             return self.inner.write(data);
         }
 
@@ -124,12 +375,16 @@ impl Write for File {
                     if following_byte == b'\0' {
                         out_buf.push(b'\r');
                     } else if following_byte == b'\n' {
+                        if self.netascii_store == NetasciiStore::Crlf {
+                            out_buf.push(b'\r');
+                        }
                         out_buf.push(b'\n');
                     } else {
-                        panic!(
-                            "Failed to parse data: unexpected byte after '\\r': 0x{:x}",
-                            following_byte
-                        );
+                        // the data is sent by a client, so it must not panic
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("unexpected byte after '\\r': 0x{:x}", following_byte),
+                        ));
                     }
                 } else {
                     self.write_buf.push(cur_byte);
@@ -140,8 +395,9 @@ impl Write for File {
             i += 1;
         }
 
-        // FIXME: there is difference between the length of data and bytes written
-        // returns data.len() here otherwise write_all of this file doesn't finish
+        // All of data is consumed (a trailing '\r' is kept in write_buf),
+        // so data.len() is returned even though the decoded length is different.
+        // out_buf must be written entirely because it cannot be returned to the caller.
         self.inner.write_all(&out_buf)?;
         Ok(data.len())
     }
@@ -158,6 +414,7 @@ impl Write for File {
 mod tests {
     use super::*;
     use crate::temp;
+    use std::path::PathBuf;
 
     fn do_test_read(content: &[u8], expected: &[u8], mode: packet::Mode) {
         //
@@ -171,7 +428,9 @@ mod tests {
         //
         // exercise
         //
-        let mut my_file = File::open(&file_path, mode).unwrap();
+        let mut my_file = File::open_to_serve(&file_path, mode, true, false, false)
+            .unwrap()
+            .0;
         let mut my_buf = [0; 512];
         let my_n = my_file.read(&mut my_buf).unwrap();
 
@@ -206,7 +465,10 @@ mod tests {
         //
         // exercise and verify
         //
-        let mut my_file = File::open(&file_path, packet::Mode::NETASCII).unwrap();
+        let mut my_file =
+            File::open_to_serve(&file_path, packet::Mode::NETASCII, true, false, false)
+                .unwrap()
+                .0;
         let mut my_buf = [0; 512];
         assert!(my_file.has_next());
         assert_eq!(my_file.read(&mut my_buf).unwrap(), 512);
@@ -217,6 +479,85 @@ mod tests {
         assert!(!my_file.has_next());
     }
 
+    #[test]
+    fn test_read_large_sparse_file_with_bounded_buffer() {
+        //
+        // setup
+        //
+        let temp_dir = temp::create_temp_dir().unwrap();
+        let file_path = temp_dir.path().join("test_read.img");
+        let fs_file = fs::File::create(&file_path).unwrap();
+        // 4 GiB sparse file, which doesn't consume disk space actually
+        fs_file.set_len(4 * 1024 * 1024 * 1024).unwrap();
+
+        //
+        // exercise and verify
+        //
+        for mode in [packet::Mode::OCTET, packet::Mode::NETASCII] {
+            let mut my_file = File::open_to_serve(&file_path, mode, true, false, false)
+                .unwrap()
+                .0;
+            let mut my_buf = [0; 512];
+            for _ in 0..10000 {
+                assert_eq!(my_file.read(&mut my_buf).unwrap(), 512);
+                assert!(my_file.read_buf.len() < 512 * 2);
+                assert!(my_file.has_next());
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_large_block_at_once() {
+        /// Count reads from the inner reader.
+        struct CountingReader<R> {
+            inner: R,
+            count: usize,
+        }
+        impl<R: Read> Read for CountingReader<R> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.count += 1;
+                self.inner.read(buf)
+            }
+        }
+
+        let content: Vec<u8> = (0..65464 * 2).map(|i| (i % 251) as u8).collect();
+        let reader = CountingReader {
+            inner: io::Cursor::new(&content),
+            count: 0,
+        };
+        let mut my_file = File::from_inner(reader, packet::Mode::OCTET);
+        let mut my_buf = vec![0; 65464];
+        assert_eq!(my_file.read(&mut my_buf).unwrap(), 65464);
+        assert_eq!(my_buf, content[..65464]);
+        assert_eq!(my_file.inner.count, 1);
+        assert_eq!(my_file.read(&mut my_buf).unwrap(), 65464);
+        assert_eq!(my_buf, content[65464..]);
+        assert_eq!(my_file.inner.count, 2);
+    }
+
+    #[test]
+    fn test_read_with_netascii_expansion() {
+        // every byte is expanded to two bytes
+        let content = vec![b'\n'; 1500];
+        let expected = b"\r\n".repeat(1500);
+        for block_size in [512, 8] {
+            let mut my_file = File::from_inner(io::Cursor::new(&content), packet::Mode::NETASCII);
+            let mut my_buf = vec![0; block_size];
+            let mut actual = vec![];
+            loop {
+                let n = my_file.read(&mut my_buf).unwrap();
+                assert!(my_file.read_buf.len() <= block_size * 2);
+                actual.extend_from_slice(&my_buf[..n]);
+                if n < block_size {
+                    break;
+                }
+            }
+            // blocks are full until the last one, so the order is kept if it matches
+            assert_eq!(actual, expected);
+            assert!(!my_file.has_next());
+        }
+    }
+
     fn do_test_write(content: &[u8], expected: &[u8], mode: packet::Mode) {
         //
         // setup
@@ -250,6 +591,78 @@ mod tests {
         do_test_write(b"a\r\0a\r\na", b"a\r\0a\r\na", packet::Mode::OCTET);
     }
 
+    #[test]
+    fn test_write_with_netascii_store() {
+        let content = b"a\r\nb\r\x00c\r\n";
+        for (netascii_store, expected) in [
+            (NetasciiStore::Unix, &b"a\nb\rc\n"[..]),
+            (NetasciiStore::Verbatim, &b"a\r\nb\r\x00c\r\n"[..]),
+            (NetasciiStore::Crlf, &b"a\r\nb\rc\r\n"[..]),
+        ] {
+            let mut my_file = File::from_inner(vec![], packet::Mode::NETASCII)
+                .with_netascii_store(netascii_store);
+            // split at '\r' to check the state across writes
+            my_file.write_all(&content[..2]).unwrap();
+            my_file.write_all(&content[2..]).unwrap();
+            my_file.flush().unwrap();
+            assert_eq!(my_file.inner, expected, "{:?}", netascii_store);
+        }
+    }
+
+    #[test]
+    fn test_write_with_malformed_netascii() {
+        let mut my_file = File::from_inner(vec![], packet::Mode::NETASCII);
+        let err = my_file.write_all(b"a\rb").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // a control character after '\r'
+        let mut my_file = File::from_inner(vec![], packet::Mode::NETASCII);
+        let err = my_file.write_all(b"\r\x07").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // split at '\r'
+        let mut my_file = File::from_inner(vec![], packet::Mode::NETASCII);
+        my_file.write_all(b"a\r").unwrap();
+        let err = my_file.write_all(b"b").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    /// A writer which accepts at most 3 bytes per call.
+    struct ShortWriter {
+        buf: Vec<u8>,
+    }
+
+    impl Write for ShortWriter {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            let n = data.len().min(3);
+            self.buf.extend_from_slice(&data[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_with_short_writer() {
+        let blocks: [&[u8]; 3] = [b"abcdefg\r", b"\nhij\r\x00klm", b"nopqrstu\r"];
+        for (mode, expected) in [
+            (
+                packet::Mode::OCTET,
+                &b"abcdefg\r\nhij\r\x00klmnopqrstu\r"[..],
+            ),
+            (packet::Mode::NETASCII, &b"abcdefg\nhij\rklmnopqrstu\r"[..]),
+        ] {
+            let mut my_file = File::from_inner(ShortWriter { buf: vec![] }, mode);
+            for block in blocks.iter() {
+                my_file.write_all(block).unwrap();
+            }
+            my_file.flush().unwrap();
+            assert_eq!(my_file.inner.buf, expected);
+        }
+    }
+
     #[test]
     fn test_write_with_newlines() {
         //
@@ -280,4 +693,72 @@ mod tests {
         assert_eq!(fs_file.read(&mut fs_buf).unwrap(), 512);
         assert_eq!(fs_file.read(&mut fs_buf).unwrap(), 511);
     }
+
+    #[test]
+    fn test_open_to_serve_only_regular_file() {
+        let temp_dir = temp::create_temp_dir().unwrap();
+        let file_path = temp_dir.path().join("file.txt");
+        fs::write(&file_path, b"hello").unwrap();
+        let link_path = temp_dir.path().join("link.txt");
+        std::os::unix::fs::symlink(&file_path, &link_path).unwrap();
+        let fifo_path = temp_dir.path().join("fifo");
+        nix::unistd::mkfifo(&fifo_path, nix::sys::stat::Mode::S_IRWXU).unwrap();
+
+        assert!(File::open_to_serve(&file_path, packet::Mode::OCTET, false, false, false).is_ok());
+        assert!(File::open_to_serve(&link_path, packet::Mode::OCTET, true, false, false).is_ok());
+        for (path, follow_symlinks) in [(&link_path, false), (&fifo_path, true)] {
+            let err = File::open_to_serve(path, packet::Mode::OCTET, follow_symlinks, false, false)
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        }
+        let err = File::open_to_serve(temp_dir.path(), packet::Mode::OCTET, true, false, false)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_check_writable_destination() {
+        let temp_dir = temp::create_temp_dir().unwrap();
+        let file_path = temp_dir.path().join("file.txt");
+        fs::write(&file_path, b"hello").unwrap();
+        let link_path = temp_dir.path().join("link.txt");
+        std::os::unix::fs::symlink(&file_path, &link_path).unwrap();
+        let fifo_path = temp_dir.path().join("fifo");
+        nix::unistd::mkfifo(&fifo_path, nix::sys::stat::Mode::S_IRWXU).unwrap();
+
+        assert!(check_writable_destination(&file_path, false).is_ok());
+        assert!(check_writable_destination(temp_dir.path().join("new.txt"), false).is_ok());
+        assert!(check_writable_destination(&link_path, true).is_ok());
+        assert!(check_writable_destination(&link_path, false).is_err());
+        assert!(check_writable_destination(&fifo_path, true).is_err());
+        assert!(check_writable_destination(temp_dir.path(), true).is_err());
+    }
+
+    #[test]
+    fn test_create_parent_dirs() {
+        let base_dir = temp::create_temp_dir().unwrap();
+        let outside_dir = temp::create_temp_dir().unwrap();
+        let base = base_dir.path();
+        std::os::unix::fs::symlink(outside_dir.path(), base.join("outside")).unwrap();
+
+        create_parent_dirs(base, &base.join("a/b/c.txt"), 0o750).unwrap();
+        assert!(base.join("a/b").is_dir());
+        assert!(!base.join("a/b/c.txt").exists());
+        // existing directories are fine
+        create_parent_dirs(base, &base.join("a/d/c.txt"), 0o750).unwrap();
+        assert!(base.join("a/d").is_dir());
+        create_parent_dirs(base, &base.join("c.txt"), 0o750).unwrap();
+
+        for path in [
+            base.join("outside/a/c.txt"),
+            base.join("../a/c.txt"),
+            PathBuf::from("/tmp/a/c.txt"),
+        ] {
+            let err = create_parent_dirs(base, &path, 0o750).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied, "{:?}", path);
+        }
+        assert_eq!(fs::read_dir(outside_dir.path()).unwrap().count(), 0);
+    }
 }
@@ -0,0 +1,488 @@
+use crate::checksum::Crc32;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+
+/// The maximum distance of back-references in DEFLATE.
+const WINDOW_SIZE: usize = 32768;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// The order of code lengths for the code length alphabet in a dynamic block.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+const FLAG_HCRC: u8 = 0x02;
+const FLAG_EXTRA: u8 = 0x04;
+const FLAG_NAME: u8 = 0x08;
+const FLAG_COMMENT: u8 = 0x10;
+const FLAG_RESERVED: u8 = 0xe0;
+
+/// Decompress gzip (RFC 1952) on read. Only the first member is decoded.
+/// The content is verified by CRC-32 and the size in the trailer at the end.
+pub struct GzipDecoder<R> {
+    input: BitReader<R>,
+    state: State,
+    is_final_block: bool,
+    /// A back-reference which is not copied to the output yet (length, distance).
+    pending: Option<(usize, usize)>,
+    /// The last output, which is referred by back-references.
+    window: Vec<u8>,
+    window_pos: usize,
+    total_out: u64,
+    crc: Crc32,
+}
+
+enum State {
+    Header,
+    BlockStart,
+    Stored { remaining: usize },
+    Huffman { lit: Huffman, dist: Huffman },
+    Finished,
+}
+
+impl<R: Read> GzipDecoder<R> {
+    pub fn new(inner: R) -> GzipDecoder<R> {
+        GzipDecoder {
+            input: BitReader::new(inner),
+            state: State::Header,
+            is_final_block: false,
+            pending: None,
+            window: vec![0; WINDOW_SIZE],
+            window_pos: 0,
+            total_out: 0,
+            crc: Crc32::new(),
+        }
+    }
+
+    fn read_header(&mut self) -> io::Result<()> {
+        let id = self.input.bits(16)?;
+        let method = self.input.bits(8)?;
+        if id != 0x8b1f || method != 8 {
+            return Err(invalid_data("not a gzip file with deflate"));
+        }
+        let flags = self.input.bits(8)? as u8;
+        if flags & FLAG_RESERVED != 0 {
+            return Err(invalid_data("reserved flags are set in gzip header"));
+        }
+        // MTIME, XFL and OS
+        for _ in 0..6 {
+            self.input.bits(8)?;
+        }
+        if flags & FLAG_EXTRA != 0 {
+            let len = self.input.bits(16)?;
+            for _ in 0..len {
+                self.input.bits(8)?;
+            }
+        }
+        for flag in [FLAG_NAME, FLAG_COMMENT] {
+            if flags & flag != 0 {
+                // zero-terminated
+                while self.input.bits(8)? != 0 {}
+            }
+        }
+        if flags & FLAG_HCRC != 0 {
+            self.input.bits(16)?;
+        }
+        Ok(())
+    }
+
+    fn read_block_header(&mut self) -> io::Result<State> {
+        self.is_final_block = self.input.bits(1)? == 1;
+        match self.input.bits(2)? {
+            0 => {
+                self.input.align();
+                let len = self.input.bits(16)?;
+                let nlen = self.input.bits(16)?;
+                if len != !nlen & 0xffff {
+                    return Err(invalid_data("broken length of stored block"));
+                }
+                Ok(State::Stored {
+                    remaining: len as usize,
+                })
+            }
+            1 => Ok(fixed_huffman()),
+            2 => self.read_dynamic_huffman(),
+            _ => Err(invalid_data("invalid block type")),
+        }
+    }
+
+    fn read_dynamic_huffman(&mut self) -> io::Result<State> {
+        let nlen = self.input.bits(5)? as usize + 257;
+        let ndist = self.input.bits(5)? as usize + 1;
+        let ncode = self.input.bits(4)? as usize + 4;
+        if nlen > 286 || ndist > 30 {
+            return Err(invalid_data("too many codes in dynamic block"));
+        }
+
+        let mut code_lengths = [0_u8; 19];
+        for &i in CODE_LENGTH_ORDER[..ncode].iter() {
+            code_lengths[i] = self.input.bits(3)? as u8;
+        }
+        let code = Huffman::new(&code_lengths)?;
+
+        let mut lengths = vec![0_u8; nlen + ndist];
+        let mut i = 0;
+        while i < lengths.len() {
+            let symbol = code.decode(&mut self.input)?;
+            let (value, repeat) = match symbol {
+                0..=15 => (symbol as u8, 1),
+                16 if i > 0 => (lengths[i - 1], 3 + self.input.bits(2)? as usize),
+                17 => (0, 3 + self.input.bits(3)? as usize),
+                18 => (0, 11 + self.input.bits(7)? as usize),
+                _ => return Err(invalid_data("invalid code length")),
+            };
+            if i + repeat > lengths.len() {
+                return Err(invalid_data("too many code lengths"));
+            }
+            lengths[i..i + repeat].fill(value);
+            i += repeat;
+        }
+        if lengths[256] == 0 {
+            return Err(invalid_data("no end-of-block code"));
+        }
+
+        Ok(State::Huffman {
+            lit: Huffman::new(&lengths[..nlen])?,
+            dist: Huffman::new(&lengths[nlen..])?,
+        })
+    }
+
+    fn read_trailer(&mut self) -> io::Result<()> {
+        self.input.align();
+        let crc = self.input.bits(16)? | (self.input.bits(16)? << 16);
+        let size = self.input.bits(16)? | (self.input.bits(16)? << 16);
+        if crc != self.crc.value() {
+            return Err(invalid_data("CRC-32 mismatch in gzip trailer"));
+        }
+        if size != self.total_out as u32 {
+            return Err(invalid_data("size mismatch in gzip trailer"));
+        }
+        Ok(())
+    }
+
+    /// Decode the next symbol in a Huffman block.
+    /// Returns the length and distance of a back-reference, or None at the end of the block.
+    fn decode_symbol(&mut self, lit: &Huffman, dist: &Huffman) -> io::Result<Option<Symbol>> {
+        let symbol = lit.decode(&mut self.input)? as usize;
+        if symbol < 256 {
+            return Ok(Some(Symbol::Literal(symbol as u8)));
+        }
+        if symbol == 256 {
+            return Ok(None);
+        }
+        let i = symbol - 257;
+        if i >= LENGTH_BASE.len() {
+            return Err(invalid_data("invalid length code"));
+        }
+        let length = LENGTH_BASE[i] as usize + self.input.bits(LENGTH_EXTRA[i] as u32)? as usize;
+        let i = dist.decode(&mut self.input)? as usize;
+        if i >= DISTANCE_BASE.len() {
+            return Err(invalid_data("invalid distance code"));
+        }
+        let distance =
+            DISTANCE_BASE[i] as usize + self.input.bits(DISTANCE_EXTRA[i] as u32)? as usize;
+        if distance as u64 > self.total_out.min(WINDOW_SIZE as u64) {
+            return Err(invalid_data("distance too far back"));
+        }
+        Ok(Some(Symbol::Reference(length, distance)))
+    }
+
+    fn output(&mut self, byte: u8) -> u8 {
+        self.window[self.window_pos] = byte;
+        self.window_pos = (self.window_pos + 1) % WINDOW_SIZE;
+        self.total_out += 1;
+        self.crc.update(&[byte]);
+        byte
+    }
+}
+
+enum Symbol {
+    Literal(u8),
+    Reference(usize, usize),
+}
+
+impl<R: Read> Read for GzipDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+        while n < buf.len() {
+            if let Some((length, distance)) = self.pending {
+                let byte = self.window[(self.window_pos + WINDOW_SIZE - distance) % WINDOW_SIZE];
+                buf[n] = self.output(byte);
+                n += 1;
+                self.pending = (length > 1).then_some((length - 1, distance));
+                continue;
+            }
+            match std::mem::replace(&mut self.state, State::Finished) {
+                State::Header => {
+                    self.read_header()?;
+                    self.state = State::BlockStart;
+                }
+                State::BlockStart if self.is_final_block => {
+                    self.read_trailer()?;
+                }
+                State::BlockStart => {
+                    self.state = self.read_block_header()?;
+                }
+                State::Stored { remaining: 0 } => {
+                    self.state = State::BlockStart;
+                }
+                State::Stored { remaining } => {
+                    let byte = self.input.bits(8)? as u8;
+                    buf[n] = self.output(byte);
+                    n += 1;
+                    self.state = State::Stored {
+                        remaining: remaining - 1,
+                    };
+                }
+                State::Huffman { lit, dist } => {
+                    match self.decode_symbol(&lit, &dist)? {
+                        Some(Symbol::Literal(byte)) => {
+                            buf[n] = self.output(byte);
+                            n += 1;
+                        }
+                        Some(Symbol::Reference(length, distance)) => {
+                            self.pending = Some((length, distance));
+                        }
+                        None => {
+                            self.state = State::BlockStart;
+                            continue;
+                        }
+                    }
+                    self.state = State::Huffman { lit, dist };
+                }
+                State::Finished => break,
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// Read the size of the decompressed content from the trailer of a gzip file.
+/// The size is modulo 2^32, so it is wrong for content larger than 4 GiB.
+pub fn uncompressed_size<F: Read + Seek>(file: &mut F) -> io::Result<u64> {
+    file.seek(SeekFrom::End(-4))?;
+    let mut size = [0; 4];
+    file.read_exact(&mut size)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(u32::from_le_bytes(size) as u64)
+}
+
+fn fixed_huffman() -> State {
+    let mut lengths = [0_u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    State::Huffman {
+        lit: Huffman::new(&lengths).unwrap(),
+        dist: Huffman::new(&[5; 30]).unwrap(),
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Canonical Huffman code, decoded bit by bit (as in zlib's puff).
+struct Huffman {
+    /// The number of codes of each length.
+    counts: [u16; 16],
+    /// Symbols ordered by their codes.
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> io::Result<Huffman> {
+        let mut counts = [0_u16; 16];
+        for &len in lengths.iter() {
+            counts[len as usize] += 1;
+        }
+        // an incomplete code is allowed (e.g. a single distance code), but not an over-subscribed one
+        let mut left: i32 = 1;
+        for &count in counts[1..].iter() {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(invalid_data("over-subscribed Huffman code"));
+            }
+        }
+
+        let mut offsets = [0_u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode<R: Read>(&self, input: &mut BitReader<R>) -> io::Result<u16> {
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for &count in self.counts[1..].iter() {
+            code |= input.bits(1)? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid_data("invalid Huffman code"))
+    }
+}
+
+/// Read bits from the least significant one in each byte as DEFLATE requires.
+struct BitReader<R> {
+    inner: BufReader<R>,
+    bits: u32,
+    count: u32,
+}
+
+impl<R: Read> BitReader<R> {
+    fn new(inner: R) -> BitReader<R> {
+        BitReader {
+            inner: BufReader::new(inner),
+            bits: 0,
+            count: 0,
+        }
+    }
+
+    /// Read `n` (<= 16) bits.
+    fn bits(&mut self, n: u32) -> io::Result<u32> {
+        while self.count < n {
+            let mut byte = [0; 1];
+            if self.inner.read(&mut byte)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "gzip file is truncated",
+                ));
+            }
+            self.bits |= (byte[0] as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.bits & ((1 << n) - 1);
+        self.bits >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    /// Discard bits up to the next byte boundary.
+    fn align(&mut self) {
+        // less than 8 bits are buffered after each read
+        self.bits = 0;
+        self.count = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::process::{Command, Stdio};
+
+    fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut content = vec![];
+        GzipDecoder::new(data).read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    /// Build gzip with stored (not compressed) blocks.
+    fn compress_stored(content: &[u8]) -> Vec<u8> {
+        let mut data = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 3];
+        let mut chunks = content.chunks(1000).peekable();
+        if chunks.peek().is_none() {
+            data.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+        }
+        while let Some(chunk) = chunks.next() {
+            data.push(chunks.peek().is_none() as u8);
+            let len = chunk.len() as u16;
+            data.extend_from_slice(&len.to_le_bytes());
+            data.extend_from_slice(&(!len).to_le_bytes());
+            data.extend_from_slice(chunk);
+        }
+        let mut crc = Crc32::new();
+        crc.update(content);
+        data.extend_from_slice(&crc.value().to_le_bytes());
+        data.extend_from_slice(&(content.len() as u32).to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_decompress_stored() {
+        let content: Vec<u8> = (0..2500).map(|i| (i % 251) as u8).collect();
+        assert_eq!(decompress(&compress_stored(&content)).unwrap(), content);
+        assert_eq!(decompress(&compress_stored(b"")).unwrap(), b"");
+    }
+
+    #[test]
+    fn test_decompress_fixed_huffman() {
+        // `gzip` of "hello hello hello hello\n"
+        let data = [
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xcb, 0x48, 0xcd, 0xc9,
+            0xc9, 0x57, 0xc8, 0x40, 0x27, 0xb9, 0x00, 0x00, 0x88, 0x59, 0x0b, 0x18, 0x00, 0x00,
+            0x00,
+        ];
+        assert_eq!(decompress(&data).unwrap(), b"hello hello hello hello\n");
+        assert_eq!(uncompressed_size(&mut Cursor::new(&data)).unwrap(), 24);
+    }
+
+    #[test]
+    fn test_decompress_dynamic_huffman() {
+        let content: Vec<u8> = (0..20000)
+            .flat_map(|i| format!("line {}: {}\n", i, i * i % 97).into_bytes())
+            .collect();
+        let mut child = match Command::new("gzip")
+            .arg("-c")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(_) => {
+                eprintln!("skip test_decompress_dynamic_huffman: gzip is not found");
+                return;
+            }
+        };
+        let mut stdin = child.stdin.take().unwrap();
+        let input = content.clone();
+        let writer = std::thread::spawn(move || {
+            io::Write::write_all(&mut stdin, &input).unwrap();
+        });
+        let output = child.wait_with_output().unwrap();
+        writer.join().unwrap();
+
+        assert_eq!(decompress(&output.stdout).unwrap(), content);
+    }
+
+    #[test]
+    fn test_decompress_broken() {
+        let mut data = compress_stored(b"hello");
+        // not gzip
+        assert!(decompress(b"hello").is_err());
+        // truncated
+        assert!(decompress(&data[..data.len() - 1]).is_err());
+        // corrupted content
+        data[15] = b'j';
+        assert!(decompress(&data).is_err());
+    }
+}
@@ -1,7 +1,22 @@
-mod error;
+pub mod checksum;
+pub mod config;
+pub mod error;
 mod file;
+mod gzip;
+mod limit;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod options;
 pub mod packet;
+pub mod prelude;
 pub mod privilege;
 pub mod server;
+pub mod sink;
 mod socket;
+pub mod stats;
 pub mod temp;
+#[cfg(test)]
+mod testing;
+mod window;
+
+pub use error::{Error, Result};
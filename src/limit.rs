@@ -0,0 +1,130 @@
+use std::time::{Duration, Instant};
+
+/// Token bucket to limit the rate of events (e.g. new transfers).
+/// It allows a burst up to `capacity` events, and refills `rate` tokens per second.
+pub struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u32, rate: u32) -> TokenBucket {
+        TokenBucket::new_at(capacity, rate, Instant::now())
+    }
+
+    fn new_at(capacity: u32, rate: u32, now: Instant) -> TokenBucket {
+        TokenBucket {
+            capacity: capacity as f64,
+            rate: rate as f64,
+            tokens: capacity as f64,
+            last: now,
+        }
+    }
+
+    /// Consume a token if available.
+    pub fn try_acquire(&mut self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Budget of bytes counted by a monotonic counter (e.g. bytes sent by the server).
+/// The budget is renewed every `period` if specified.
+pub struct ByteBudget {
+    budget: u64,
+    period: Option<Duration>,
+    base: u64,
+    started: Instant,
+}
+
+impl ByteBudget {
+    /// `counter` is the current value of the counter, which is counted from.
+    pub fn new(budget: u64, period: Option<Duration>, counter: u64) -> ByteBudget {
+        ByteBudget::new_at(budget, period, counter, Instant::now())
+    }
+
+    fn new_at(budget: u64, period: Option<Duration>, counter: u64, now: Instant) -> ByteBudget {
+        ByteBudget {
+            budget,
+            period,
+            base: counter,
+            started: now,
+        }
+    }
+
+    /// Whether bytes are left in the current period.
+    pub fn is_available(&mut self, counter: u64) -> bool {
+        self.is_available_at(counter, Instant::now())
+    }
+
+    fn is_available_at(&mut self, counter: u64, now: Instant) -> bool {
+        if let Some(period) = self.period {
+            if now.saturating_duration_since(self.started) >= period {
+                self.base = counter;
+                self.started = now;
+            }
+        }
+        counter.saturating_sub(self.base) < self.budget
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new_at(3, 2, start);
+
+        // burst is capped by capacity
+        let accepted = (0..10).filter(|_| bucket.try_acquire_at(start)).count();
+        assert_eq!(accepted, 3);
+
+        // refilled 2 tokens per second
+        let now = start + Duration::from_millis(500);
+        assert!(bucket.try_acquire_at(now));
+        assert!(!bucket.try_acquire_at(now));
+
+        let now = start + Duration::from_millis(1500);
+        let accepted = (0..10).filter(|_| bucket.try_acquire_at(now)).count();
+        assert_eq!(accepted, 2);
+
+        // tokens are not accumulated over capacity
+        let now = start + Duration::from_secs(100);
+        let accepted = (0..10).filter(|_| bucket.try_acquire_at(now)).count();
+        assert_eq!(accepted, 3);
+    }
+
+    #[test]
+    fn test_byte_budget() {
+        let start = Instant::now();
+        let mut budget = ByteBudget::new_at(1000, Some(Duration::from_secs(10)), 500, start);
+        assert!(budget.is_available_at(500, start));
+        assert!(budget.is_available_at(1499, start));
+        // the last transfer may exceed the budget, but no more is allowed
+        assert!(!budget.is_available_at(1500, start));
+        assert!(!budget.is_available_at(2000, start + Duration::from_secs(9)));
+
+        // renewed in the next period
+        assert!(budget.is_available_at(2000, start + Duration::from_secs(10)));
+        assert!(!budget.is_available_at(3000, start + Duration::from_secs(11)));
+
+        // never renewed without period
+        let mut budget = ByteBudget::new_at(1000, None, 0, start);
+        assert!(!budget.is_available_at(1000, start + Duration::from_secs(100_000)));
+    }
+}
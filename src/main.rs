@@ -1,8 +1,13 @@
 use anyhow::{Context, Result};
 use clap::Parser;
+use log::info;
+use nix::sys::signal::{signal, SigHandler, Signal};
 use std::net::Ipv4Addr;
+use std::num::{NonZeroU16, NonZeroU32, NonZeroU64, NonZeroUsize};
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
+use tftpff::config::{Config, ErrorPolicy, FilenameMap, FinalAckPolicy, NetasciiStore};
 use tftpff::privilege;
 use tftpff::server;
 use tftpff::temp;
@@ -19,39 +24,340 @@ struct Args {
     #[clap(short, long, default_value_t = 69)]
     port: u16,
 
+    /// User name or numeric uid to run as
     #[clap(short, long, default_value = "root")]
     user: String,
 
+    /// Group name or numeric gid to run as
     #[clap(short, long, default_value = "root")]
     group: String,
+
+    /// Maximum size of datagrams in transfers, which limits blksize option
+    #[clap(long, default_value_t = 65468)]
+    max_datagram_size: usize,
+
+    /// Don't reply with error packets to malformed packets (e.g. from port scanners)
+    #[clap(long)]
+    quiet_errors: bool,
+
+    /// Sync uploaded data to disk every N blocks (no intermediate sync by default)
+    #[clap(long)]
+    sync_interval: Option<NonZeroU32>,
+
+    /// Log CRC-32 of data sent for each RRQ
+    #[clap(long)]
+    rrq_checksum: bool,
+
+    /// Exit on unexpected errors instead of continuing to serve (e.g. under a supervisor)
+    #[clap(long)]
+    abort_on_error: bool,
+
+    /// Maximum number of new transfers started per second (no limit by default)
+    #[clap(long)]
+    max_new_transfers_per_sec: Option<NonZeroU32>,
+
+    /// Maximum number of ERROR packets sent from the server port per second, to limit reflection to spoofed addresses (no limit by default)
+    #[clap(long)]
+    max_errors_per_sec: Option<NonZeroU32>,
+
+    /// Reject new RRQs after sending this number of bytes in total (no limit by default)
+    #[clap(long)]
+    egress_budget: Option<NonZeroU64>,
+
+    /// Renew --egress-budget every N seconds (e.g. 86400 for daily, never renewed by default)
+    #[clap(long)]
+    egress_budget_period: Option<NonZeroU64>,
+
+    /// Reject requests while N transfers are in progress (0 for no limit)
+    #[clap(long, default_value_t = 64)]
+    max_active_transfers: usize,
+
+    /// Retransmit a packet if no response comes in N seconds
+    #[clap(long, default_value_t = NonZeroU64::new(5).unwrap())]
+    retry_interval: NonZeroU64,
+
+    /// Give up a transfer after N retransmissions of a packet
+    #[clap(long, default_value_t = NonZeroU16::new(4).unwrap())]
+    max_retries: NonZeroU16,
+
+    /// Don't serve or overwrite symbolic links
+    #[clap(long)]
+    no_follow_symlinks: bool,
+
+    /// Maximum windowsize accepted for RRQ (up to 32767)
+    #[clap(long, default_value_t = 64, parse(try_from_str = parse_max_window_size))]
+    max_window_size: u16,
+
+    /// Adjust the number of blocks sent at once in RRQ by congestion (up to windowsize). Clients must acknowledge once DATA stop arriving, not only after windowsize blocks
+    #[clap(long)]
+    adaptive_window: bool,
+
+    /// Allow binding to an address not assigned to any interface yet (IP_FREEBIND, Linux only)
+    #[clap(long)]
+    freebind: bool,
+
+    /// Log a summary of transfers every N seconds (disabled by default)
+    #[clap(long)]
+    log_interval: Option<NonZeroU64>,
+
+    /// Refuse all uploads
+    #[clap(long)]
+    read_only: bool,
+
+    /// Refuse all downloads
+    #[clap(long, conflicts_with = "read-only")]
+    write_only: bool,
+
+    /// Refuse uploads to existing files with FileExists instead of overwriting them
+    #[clap(long)]
+    no_clobber: bool,
+
+    /// Stage uploads as dotfiles in the base directory and rename them when completed
+    #[clap(long)]
+    stage_in_place: bool,
+
+    /// Create missing directories for uploads mapped to a subdirectory by --filename-map
+    #[clap(long)]
+    create_dirs: bool,
+
+    /// Permission (in octal) of directories created by --create-dirs, masked by umask
+    #[clap(long, default_value = "755", parse(try_from_str = parse_dir_mode))]
+    dir_mode: u32,
+
+    /// Reject RRQ and WRQ in netascii mode
+    #[clap(long)]
+    octet_only: bool,
+
+    /// How uploads in netascii are stored: unix (LF), verbatim (as on the wire) or crlf
+    #[clap(long, default_value = "unix", possible_values = ["unix", "verbatim", "crlf"])]
+    netascii_store: NetasciiStore,
+
+    /// Whether RRQ fails (required) or completes (optional) when the last DATA is never acknowledged
+    #[clap(long, default_value = "required", possible_values = ["required", "optional"])]
+    final_ack: FinalAckPolicy,
+
+    /// Accept the nonstandard blksize2 option (power-of-two blksize) used by some PXE stacks
+    #[clap(long)]
+    blksize2: bool,
+
+    /// Accept the nonstandard startblock option to resume RRQ from the block
+    #[clap(long)]
+    partial_get: bool,
+
+    /// Receive buffer size of the server socket in bytes, to queue a burst of requests (OS default by default)
+    #[clap(long)]
+    recv_buffer_size: Option<usize>,
+
+    /// Discard an upload whose size differs from tsize sent by the client (octet mode only)
+    #[clap(long)]
+    verify_tsize: bool,
+
+    /// Copy completed uploads to this directory as well (best-effort), can be repeated
+    #[clap(long)]
+    mirror_dir: Vec<PathBuf>,
+
+    /// Change the root directory to --dir before dropping privilege to --user other than root (uploads are staged in place)
+    #[clap(long, conflicts_with = "mirror-dir")]
+    chroot: bool,
+
+    /// Serve <name>.gz decompressed if a requested file <name> doesn't exist
+    #[clap(long)]
+    serve_gzip: bool,
+
+    /// Exit after serving N transfers (e.g. to be restarted by a supervisor)
+    #[clap(long)]
+    max_transfers: Option<NonZeroU64>,
+
+    /// Don't reply FileNotFound to a missing file matching the glob pattern (e.g. "pxelinux.cfg/*"), can be repeated
+    #[clap(long)]
+    silent_miss: Vec<String>,
+
+    /// Serve (or upload to) the file TO in the base directory when FROM is requested, can be repeated
+    #[clap(long, value_name = "FROM=TO", parse(try_from_str = parse_mapping))]
+    filename_map: Vec<(String, String)>,
+
+    /// Read served files with O_DIRECT to bypass the page cache (Linux only)
+    #[clap(long)]
+    direct_io: bool,
+
+    /// Serve stats in the Prometheus text format at http://<ADDR>/metrics (e.g. 127.0.0.1:9169)
+    #[cfg(feature = "metrics")]
+    #[clap(long)]
+    metrics_addr: Option<std::net::SocketAddr>,
 }
 
 fn main() -> Result<()> {
     env_logger::init();
+    ignore_sigpipe()?;
 
     let args = Args::parse();
 
     let server_addr = Ipv4Addr::from_str(&args.addr)?;
     let server_port: u16 = args.port;
+    // resolve a relative path now, because it may be resolved differently
+    // if the working directory changes later (e.g. daemonization)
     let base_dir = PathBuf::from_str(&args.dir)?;
+    let base_dir = base_dir
+        .canonicalize()
+        .with_context(|| format!("Failed to resolve base directory {:?}", base_dir))?;
+    info!("base directory: {:?}", base_dir);
+    let mirror_dirs = args
+        .mirror_dir
+        .iter()
+        .map(|dir| {
+            dir.canonicalize()
+                .with_context(|| format!("Failed to resolve mirror directory {:?}", dir))
+        })
+        .collect::<Result<Vec<_>>>()?;
 
     let user: &str = &args.user;
     let group: &str = &args.group;
+    // look up the user and group before chroot, after which /etc/passwd and /etc/group are not visible
+    let identity = privilege::Identity::lookup(user, group)?;
+    if args.chroot {
+        privilege::check_chroot_identity(&identity)?;
+    }
+
+    // the base directory is the root after chroot,
+    // and no temporary directory is necessary because it would be outside of the root
+    let served_dir = if args.chroot {
+        PathBuf::from("/")
+    } else {
+        base_dir.clone()
+    };
+    let temp_dir = if args.chroot {
+        None
+    } else {
+        let temp_dir = temp::create_temp_dir()?;
+        privilege::chmod(temp_dir.path(), 0o777)?;
+        privilege::chown(temp_dir.path(), user, group)?;
+        Some(temp_dir)
+    };
 
-    let temp_dir = temp::create_temp_dir()?;
-    privilege::chmod(temp_dir.path(), 0o777)?;
-    privilege::chown(temp_dir.path(), user, group)?;
+    let config = Config {
+        max_datagram_size: args.max_datagram_size,
+        quiet_errors: args.quiet_errors,
+        sync_interval: args.sync_interval,
+        rrq_checksum: args.rrq_checksum,
+        error_policy: if args.abort_on_error {
+            ErrorPolicy::Abort
+        } else {
+            ErrorPolicy::Continue
+        },
+        max_new_transfers_per_sec: args.max_new_transfers_per_sec,
+        max_errors_per_sec: args.max_errors_per_sec,
+        egress_budget: args.egress_budget,
+        egress_budget_period: args
+            .egress_budget_period
+            .map(|secs| Duration::from_secs(secs.get())),
+        max_active_transfers: NonZeroUsize::new(args.max_active_transfers),
+        retry_interval: Duration::from_secs(args.retry_interval.get()),
+        max_retries: args.max_retries,
+        follow_symlinks: !args.no_follow_symlinks,
+        max_window_size: args.max_window_size,
+        adaptive_window: args.adaptive_window,
+        freebind: args.freebind,
+        announce_interval: args
+            .log_interval
+            .map(|secs| Duration::from_secs(secs.get())),
+        read_only: args.read_only,
+        write_only: args.write_only,
+        no_clobber: args.no_clobber,
+        stage_in_place: args.stage_in_place || args.chroot,
+        create_dirs: args.create_dirs.then_some(args.dir_mode),
+        octet_only: args.octet_only,
+        netascii_store: args.netascii_store,
+        final_ack_policy: args.final_ack,
+        blksize2: args.blksize2,
+        partial_get: args.partial_get,
+        recv_buffer_size: args.recv_buffer_size,
+        verify_tsize: args.verify_tsize,
+        mirror_dirs: mirror_dirs.clone(),
+        serve_gzip: args.serve_gzip,
+        max_transfers: args.max_transfers,
+        silent_miss_patterns: args.silent_miss,
+        filename_map: args
+            .filename_map
+            .iter()
+            .fold(FilenameMap::default(), |map, (from, to)| map.with(from, to)),
+        direct_io: args.direct_io,
+        #[cfg(feature = "metrics")]
+        metrics_addr: args.metrics_addr,
+        ..Config::default()
+    };
 
     let mut server = server::TftpServer::create(
         server_addr,
         server_port,
-        base_dir,
-        temp_dir.path().to_owned(),
+        served_dir.clone(),
+        temp_dir
+            .as_ref()
+            .map_or_else(|| served_dir.clone(), |dir| dir.path().to_owned()),
+        config,
     )
     .context("Failed to create TftpServer")?;
     server.bind().context("Failed to bind")?;
-    privilege::drop_privilege(user, group)?;
+    // chroot while running as root
+    if args.chroot {
+        privilege::chroot(&base_dir)?;
+    }
+    privilege::drop_privilege_to(&identity)?;
+    privilege::check_dir_access(&served_dir, true)
+        .context("Base directory is not accessible after dropping privilege")?;
+    if let Some(temp_dir) = temp_dir.as_ref() {
+        privilege::check_dir_access(temp_dir.path(), true)
+            .context("Temporary directory is not accessible after dropping privilege")?;
+    }
+    for dir in mirror_dirs.iter() {
+        privilege::check_dir_access(dir, true).with_context(|| {
+            format!(
+                "Mirror directory {:?} is not accessible after dropping privilege",
+                dir
+            )
+        })?;
+    }
+    server
+        .reject_pending_requests()
+        .context("Failed to reject pending requests")?;
     server.run().context("Failed in TftpServer running")?;
 
     Ok(())
 }
+
+/// Parse a mapping of --filename-map.
+fn parse_mapping(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((from, to)) if !from.is_empty() && !to.is_empty() => {
+            Ok((from.to_string(), to.to_string()))
+        }
+        _ => Err(format!("expected FROM=TO: {}", s)),
+    }
+}
+
+/// Parse --dir-mode as octal permission bits.
+fn parse_dir_mode(s: &str) -> Result<u32, String> {
+    match u32::from_str_radix(s, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("expected permission bits in octal: {}", s)),
+    }
+}
+
+/// Parse --max-window-size, which must fit in half of the block number space.
+fn parse_max_window_size(s: &str) -> Result<u16, String> {
+    match s.parse::<u16>() {
+        Ok(size) if size <= Config::MAX_WINDOW_SIZE => Ok(size),
+        _ => Err(format!(
+            "expected a number up to {}: {}",
+            Config::MAX_WINDOW_SIZE,
+            s
+        )),
+    }
+}
+
+/// Make a write to a closed pipe or socket (e.g. stderr read by a dead logger) fail with EPIPE
+/// instead of killing the process. The Rust runtime does this by default, but it is not
+/// guaranteed to be inherited when the binary is built differently, so set it explicitly.
+fn ignore_sigpipe() -> Result<()> {
+    unsafe { signal(Signal::SIGPIPE, SigHandler::SigIgn) }.context("Failed to ignore SIGPIPE")?;
+    Ok(())
+}
@@ -0,0 +1,148 @@
+//! Export stats in the Prometheus text format over HTTP.
+
+use crate::stats::{ServerStats, StatsSnapshot};
+use log::{debug, warn};
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Serve `/metrics` at `addr` until `term` is set.
+/// Scrapes are handled one by one in the thread, which is enough for a few scrapers.
+pub(crate) fn spawn_exporter(
+    addr: SocketAddr,
+    stats: Arc<ServerStats>,
+    term: Arc<AtomicBool>,
+) -> io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(addr)?;
+    // poll so that term is checked periodically
+    listener.set_nonblocking(true)?;
+    Ok(thread::spawn(move || {
+        while !term.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    if let Err(err) = respond(stream, &stats) {
+                        debug!("Failed to respond to scrape from {}: {:?}", peer, err);
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(100));
+                }
+                Err(err) => warn!("Failed to accept a connection for metrics: {:?}", err),
+            }
+        }
+    }))
+}
+
+fn respond(stream: TcpStream, stats: &ServerStats) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // the rest of the request (headers) is not necessary
+    let mut stream = reader.into_inner();
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(&stats.snapshot())),
+        _ => ("404 Not Found", "Not Found\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+fn render(snapshot: &StatsSnapshot) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, u64)]| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (labels, value) in samples.iter() {
+            let _ = writeln!(out, "{}{} {}", name, labels, value);
+        }
+    };
+
+    metric(
+        "tftpff_active_transfers",
+        "gauge",
+        "The number of transfers in progress.",
+        &[(String::new(), snapshot.active_transfers)],
+    );
+    metric(
+        "tftpff_transfers_total",
+        "counter",
+        "The number of finished transfers.",
+        &[
+            (labels("read", "completed"), snapshot.rrq_completed),
+            (labels("read", "failed"), snapshot.rrq_failed),
+            (labels("write", "completed"), snapshot.wrq_completed),
+            (labels("write", "failed"), snapshot.wrq_failed),
+        ],
+    );
+    metric(
+        "tftpff_sent_bytes_total",
+        "counter",
+        "Bytes of data sent by RRQ.",
+        &[(String::new(), snapshot.bytes_sent)],
+    );
+    metric(
+        "tftpff_received_bytes_total",
+        "counter",
+        "Bytes of data received by WRQ.",
+        &[(String::new(), snapshot.bytes_received)],
+    );
+    let errors: Vec<(String, u64)> = snapshot
+        .errors_sent
+        .iter()
+        .enumerate()
+        .map(|(code, count)| (format!("{{code=\"{}\"}}", code), *count))
+        .collect();
+    metric(
+        "tftpff_errors_sent_total",
+        "counter",
+        "The number of ERROR packets sent by error code.",
+        &errors,
+    );
+    out
+}
+
+fn labels(operation: &str, result: &str) -> String {
+    format!("{{operation=\"{}\",result=\"{}\"}}", operation, result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let snapshot = StatsSnapshot {
+            active_transfers: 1,
+            rrq_completed: 2,
+            bytes_sent: 1024,
+            errors_sent: [0, 3, 0, 0, 0, 0, 0, 0],
+            ..StatsSnapshot::default()
+        };
+        let text = render(&snapshot);
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines.contains(&"# TYPE tftpff_active_transfers gauge"));
+        assert!(lines.contains(&"tftpff_active_transfers 1"));
+        assert!(
+            lines.contains(&"tftpff_transfers_total{operation=\"read\",result=\"completed\"} 2")
+        );
+        assert!(lines.contains(&"tftpff_transfers_total{operation=\"write\",result=\"failed\"} 0"));
+        assert!(lines.contains(&"tftpff_sent_bytes_total 1024"));
+        assert!(lines.contains(&"tftpff_errors_sent_total{code=\"1\"} 3"));
+        assert!(lines.contains(&"tftpff_errors_sent_total{code=\"7\"} 0"));
+    }
+}
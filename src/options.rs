@@ -0,0 +1,394 @@
+use crate::config::Config;
+use crate::packet;
+use log::debug;
+use std::time::Duration;
+
+/// Block size option defined in RFC 2348.
+pub const BLKSIZE: &str = "blksize";
+/// Transfer size option defined in RFC 2349.
+pub const TSIZE: &str = "tsize";
+/// Timeout interval option (in seconds) defined in RFC 2349.
+pub const TIMEOUT: &str = "timeout";
+/// Window size option defined in RFC 7440.
+pub const WINDOWSIZE: &str = "windowsize";
+/// Nonstandard block size option which requires a power of two (used by some PXE stacks).
+pub const BLKSIZE2: &str = "blksize2";
+/// Nonstandard option to start RRQ from the block (1-origin) to resume a download.
+pub const STARTBLOCK: &str = "startblock";
+
+/// The block size used when blksize is not negotiated.
+pub const DEFAULT_BLOCK_SIZE: u16 = 512;
+/// The minimum of blksize defined in RFC 2348.
+const MIN_BLOCK_SIZE: u16 = 8;
+
+/// Options accepted by the server for a transfer.
+/// Only options which appear here are acknowledged in OACK (RFC 2347).
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct TransferOptions {
+    pub blksize: Option<u16>,
+    pub tsize: Option<u64>,
+    pub timeout: Option<u8>,
+    pub windowsize: Option<u16>,
+    /// Acknowledged instead of blksize if both are requested.
+    pub blksize2: Option<u16>,
+    pub startblock: Option<u16>,
+}
+
+impl TransferOptions {
+    /// Negotiate options requested in RRQ.
+    /// `file_size` is the size of the file to be sent.
+    pub fn negotiate_rrq(
+        requested: &[(String, String)],
+        mode: &packet::Mode,
+        file_size: u64,
+        config: &Config,
+    ) -> TransferOptions {
+        let mut options = TransferOptions::default();
+        for (name, value) in requested.iter() {
+            match name.as_str() {
+                BLKSIZE => options.blksize = negotiate_blksize(value, config),
+                // tsize is meaningful only for octet because netascii changes the size on the wire
+                TSIZE if *mode == packet::Mode::OCTET => options.tsize = Some(file_size),
+                TIMEOUT => options.timeout = negotiate_timeout(value),
+                WINDOWSIZE => options.windowsize = negotiate_windowsize(value, config),
+                BLKSIZE2 if config.blksize2 => options.blksize2 = negotiate_blksize2(value, config),
+                STARTBLOCK if config.partial_get => {
+                    options.startblock = value.parse().ok().filter(|&block| block > 0)
+                }
+                // RFC 2347: unrecognized options are just omitted from OACK
+                _ => debug!("ignore unsupported option: {}={}", name, value),
+            }
+        }
+        options.prefer_blksize2();
+        // the block must start within the file (or just at its end)
+        let block_size = options.block_size() as u64;
+        options.startblock = options
+            .startblock
+            .filter(|&block| (block as u64 - 1) * block_size <= file_size);
+        options
+    }
+
+    /// Negotiate options requested in WRQ.
+    pub fn negotiate_wrq(requested: &[(String, String)], config: &Config) -> TransferOptions {
+        let mut options = TransferOptions::default();
+        for (name, value) in requested.iter() {
+            match name.as_str() {
+                BLKSIZE => options.blksize = negotiate_blksize(value, config),
+                // ignore the option if it is malformed
+                TSIZE => options.tsize = value.parse().ok(),
+                TIMEOUT => options.timeout = negotiate_timeout(value),
+                BLKSIZE2 if config.blksize2 => options.blksize2 = negotiate_blksize2(value, config),
+                // RFC 2347: unrecognized options are just omitted from OACK
+                _ => debug!("ignore unsupported option: {}={}", name, value),
+            }
+        }
+        options.prefer_blksize2();
+        options
+    }
+
+    /// Don't acknowledge both blksize and blksize2 so that the block size is not ambiguous.
+    fn prefer_blksize2(&mut self) {
+        if self.blksize2.is_some() {
+            self.blksize = None;
+        }
+    }
+
+    /// The size of data in a DATA packet (except for the last one).
+    pub fn block_size(&self) -> u16 {
+        self.blksize2.or(self.blksize).unwrap_or(DEFAULT_BLOCK_SIZE)
+    }
+
+    /// The block number of the first DATA.
+    pub fn start_block(&self) -> u16 {
+        self.startblock.unwrap_or(1)
+    }
+
+    /// The interval to wait for the response before retransmission, or None for the default.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout.map(|secs| Duration::from_secs(secs as u64))
+    }
+
+    /// The number of DATA packets sent before waiting for ACK.
+    pub fn window_size(&self) -> u16 {
+        self.windowsize.unwrap_or(1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == TransferOptions::default()
+    }
+
+    /// Build OACK for the accepted options, or None if no option is accepted.
+    pub fn oack(&self) -> Option<packet::OACK> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut pairs = vec![];
+        if let Some(blksize) = self.blksize {
+            pairs.push((BLKSIZE.to_string(), blksize.to_string()));
+        }
+        if let Some(tsize) = self.tsize {
+            pairs.push((TSIZE.to_string(), tsize.to_string()));
+        }
+        if let Some(timeout) = self.timeout {
+            pairs.push((TIMEOUT.to_string(), timeout.to_string()));
+        }
+        if let Some(windowsize) = self.windowsize {
+            pairs.push((WINDOWSIZE.to_string(), windowsize.to_string()));
+        }
+        if let Some(blksize2) = self.blksize2 {
+            pairs.push((BLKSIZE2.to_string(), blksize2.to_string()));
+        }
+        if let Some(startblock) = self.startblock {
+            pairs.push((STARTBLOCK.to_string(), startblock.to_string()));
+        }
+        Some(packet::OACK::new(pairs))
+    }
+}
+
+/// Accept blksize requested by client.
+/// A value larger than the server can handle is clamped, and an illegal value is ignored.
+fn negotiate_blksize(value: &str, config: &Config) -> Option<u16> {
+    let requested: u64 = value.parse().ok()?;
+    if requested < MIN_BLOCK_SIZE as u64 {
+        return None;
+    }
+    let max = config.max_block_size();
+    if max < MIN_BLOCK_SIZE {
+        return None;
+    }
+    Some(requested.min(max as u64) as u16)
+}
+
+/// Accept blksize2 requested by client.
+/// The value is negotiated as blksize and then rounded down to a power of two.
+fn negotiate_blksize2(value: &str, config: &Config) -> Option<u16> {
+    let blksize = negotiate_blksize(value, config)?;
+    Some(1 << (u16::BITS - 1 - blksize.leading_zeros()))
+}
+
+/// Accept timeout requested by client.
+/// RFC 2349 allows 1 to 255 seconds, and other values are ignored (not acknowledged).
+fn negotiate_timeout(value: &str) -> Option<u8> {
+    value.parse().ok().filter(|&secs| secs > 0)
+}
+
+/// Accept windowsize requested by client.
+/// A value larger than the configured maximum (or Config::MAX_WINDOW_SIZE) is clamped,
+/// and an illegal value is ignored.
+fn negotiate_windowsize(value: &str, config: &Config) -> Option<u16> {
+    let requested: u16 = value.parse().ok()?;
+    if requested == 0 {
+        return None;
+    }
+    Some(requested.min(config.max_window_size.clamp(1, Config::MAX_WINDOW_SIZE)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_rrq_tsize() {
+        let requested = vec![(TSIZE.to_string(), "0".to_string())];
+        let options = TransferOptions::negotiate_rrq(
+            &requested,
+            &packet::Mode::OCTET,
+            513,
+            &Config::default(),
+        );
+        assert_eq!(options.tsize, Some(513));
+        assert_eq!(options.oack().unwrap().get(TSIZE), Some("513"));
+    }
+
+    #[test]
+    fn test_negotiate_wrq_tsize() {
+        let requested = vec![(TSIZE.to_string(), "1024".to_string())];
+        let options = TransferOptions::negotiate_wrq(&requested, &Config::default());
+        assert_eq!(options.tsize, Some(1024));
+        assert_eq!(options.oack().unwrap().get(TSIZE), Some("1024"));
+
+        let requested = vec![(TSIZE.to_string(), "abc".to_string())];
+        let options = TransferOptions::negotiate_wrq(&requested, &Config::default());
+        assert!(options.oack().is_none());
+    }
+
+    #[test]
+    fn test_negotiate_timeout() {
+        let do_negotiate = |value: &str| {
+            let requested = vec![(TIMEOUT.to_string(), value.to_string())];
+            TransferOptions::negotiate_wrq(&requested, &Config::default())
+        };
+        let options = do_negotiate("2");
+        assert_eq!(options.timeout(), Some(Duration::from_secs(2)));
+        assert_eq!(options.oack().unwrap().get(TIMEOUT), Some("2"));
+        assert_eq!(do_negotiate("255").timeout, Some(255));
+        for value in ["0", "256", "-1", "1.5", "abc"] {
+            assert!(do_negotiate(value).oack().is_none(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_negotiate_blksize() {
+        let config = Config::default();
+        let do_negotiate = |value: &str| {
+            let requested = vec![(BLKSIZE.to_string(), value.to_string())];
+            TransferOptions::negotiate_wrq(&requested, &config).blksize
+        };
+        assert_eq!(do_negotiate("1024"), Some(1024));
+        assert_eq!(do_negotiate("8"), Some(8));
+        assert_eq!(do_negotiate("65464"), Some(65464));
+        assert_eq!(do_negotiate("65465"), Some(65464));
+        assert_eq!(do_negotiate("7"), None);
+        assert_eq!(do_negotiate("abc"), None);
+    }
+
+    #[test]
+    fn test_negotiate_blksize_clamped_by_max_datagram_size() {
+        let config = Config {
+            max_datagram_size: 1472,
+            ..Config::default()
+        };
+        let requested = vec![(BLKSIZE.to_string(), "8192".to_string())];
+        let options = TransferOptions::negotiate_rrq(&requested, &packet::Mode::OCTET, 0, &config);
+        assert_eq!(options.blksize, Some(1468));
+        assert_eq!(options.oack().unwrap().get(BLKSIZE), Some("1468"));
+    }
+
+    #[test]
+    fn test_negotiate_windowsize() {
+        let config = Config {
+            max_window_size: 16,
+            ..Config::default()
+        };
+        let do_negotiate = |value: &str| {
+            let requested = vec![(WINDOWSIZE.to_string(), value.to_string())];
+            TransferOptions::negotiate_rrq(&requested, &packet::Mode::OCTET, 0, &config).windowsize
+        };
+        assert_eq!(do_negotiate("1"), Some(1));
+        assert_eq!(do_negotiate("8"), Some(8));
+        assert_eq!(do_negotiate("17"), Some(16));
+        assert_eq!(do_negotiate("0"), None);
+        assert_eq!(do_negotiate("65536"), None);
+        assert_eq!(do_negotiate("abc"), None);
+
+        let config = Config {
+            max_window_size: u16::MAX,
+            ..Config::default()
+        };
+        let requested = vec![(WINDOWSIZE.to_string(), "65535".to_string())];
+        let options = TransferOptions::negotiate_rrq(&requested, &packet::Mode::OCTET, 0, &config);
+        assert_eq!(options.windowsize, Some(Config::MAX_WINDOW_SIZE));
+
+        // not supported in WRQ
+        let requested = vec![(WINDOWSIZE.to_string(), "8".to_string())];
+        let options = TransferOptions::negotiate_wrq(&requested, &config);
+        assert!(options.oack().is_none());
+    }
+
+    #[test]
+    fn test_negotiate_blksize2() {
+        let config = Config {
+            blksize2: true,
+            ..Config::default()
+        };
+        let requested = vec![(BLKSIZE2.to_string(), "1500".to_string())];
+        let options = TransferOptions::negotiate_rrq(&requested, &packet::Mode::OCTET, 0, &config);
+        assert_eq!(options.block_size(), 1024);
+        assert_eq!(
+            options.oack().unwrap().options(),
+            &[(BLKSIZE2.to_string(), "1024".to_string())]
+        );
+
+        // blksize2 is preferred to blksize
+        let requested = vec![
+            (BLKSIZE.to_string(), "1500".to_string()),
+            (BLKSIZE2.to_string(), "1500".to_string()),
+        ];
+        let options = TransferOptions::negotiate_wrq(&requested, &config);
+        assert_eq!(options.block_size(), 1024);
+        assert_eq!(options.oack().unwrap().get(BLKSIZE), None);
+
+        // the result is still limited by max_datagram_size and the minimum
+        let config = Config {
+            max_datagram_size: 1000,
+            ..config
+        };
+        let requested = vec![(BLKSIZE2.to_string(), "1500".to_string())];
+        assert_eq!(
+            TransferOptions::negotiate_wrq(&requested, &config).blksize2,
+            Some(512)
+        );
+        let requested = vec![(BLKSIZE2.to_string(), "7".to_string())];
+        assert_eq!(
+            TransferOptions::negotiate_wrq(&requested, &config).blksize2,
+            None
+        );
+
+        // ignored unless enabled
+        let requested = vec![(BLKSIZE2.to_string(), "1500".to_string())];
+        let options =
+            TransferOptions::negotiate_rrq(&requested, &packet::Mode::OCTET, 0, &Config::default());
+        assert!(options.oack().is_none());
+    }
+
+    #[test]
+    fn test_negotiate_startblock() {
+        let config = Config {
+            partial_get: true,
+            ..Config::default()
+        };
+        let do_negotiate = |blksize: &str, startblock: &str| {
+            let requested = vec![
+                (BLKSIZE.to_string(), blksize.to_string()),
+                (STARTBLOCK.to_string(), startblock.to_string()),
+            ];
+            TransferOptions::negotiate_rrq(&requested, &packet::Mode::OCTET, 2048, &config)
+                .startblock
+        };
+        assert_eq!(do_negotiate("512", "3"), Some(3));
+        // just at the end of file, where the empty last block is sent
+        assert_eq!(do_negotiate("512", "5"), Some(5));
+        // validated by the negotiated blksize
+        assert_eq!(do_negotiate("1024", "3"), Some(3));
+        assert_eq!(do_negotiate("1024", "4"), None);
+        assert_eq!(do_negotiate("512", "0"), None);
+        assert_eq!(do_negotiate("512", "abc"), None);
+
+        // ignored unless enabled
+        let requested = vec![(STARTBLOCK.to_string(), "2".to_string())];
+        let options = TransferOptions::negotiate_rrq(
+            &requested,
+            &packet::Mode::OCTET,
+            2048,
+            &Config::default(),
+        );
+        assert!(options.oack().is_none());
+        assert_eq!(options.start_block(), 1);
+    }
+
+    #[test]
+    fn test_oack_omits_unknown_option() {
+        let requested = vec![
+            ("foo".to_string(), "bar".to_string()),
+            (BLKSIZE.to_string(), "1024".to_string()),
+        ];
+        let options = TransferOptions::negotiate_wrq(&requested, &Config::default());
+        assert_eq!(
+            options.oack().unwrap().options(),
+            &[(BLKSIZE.to_string(), "1024".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_negotiate_rrq_ignore_unknown_option() {
+        let requested = vec![("foo".to_string(), "bar".to_string())];
+        let options = TransferOptions::negotiate_rrq(
+            &requested,
+            &packet::Mode::OCTET,
+            513,
+            &Config::default(),
+        );
+        assert!(options.is_empty());
+        assert!(options.oack().is_none());
+    }
+}
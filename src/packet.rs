@@ -1,10 +1,21 @@
-use crate::error::TftpError;
-use anyhow::{anyhow, bail, Result};
+use crate::error::{Result, TftpError};
 use std::fmt;
 use std::fmt::Formatter;
 use std::path::Path;
 
-#[derive(Debug, PartialEq, Eq)]
+/// Build Error::Protocol for a malformed packet.
+macro_rules! malformed {
+    ($($arg:tt)*) => {
+        crate::Error::Protocol(format!($($arg)*))
+    };
+}
+
+/// Read a big-endian u16 at the head of the slice.
+fn read_u16(s: &[u8]) -> u16 {
+    u16::from_be_bytes([s[0], s[1]])
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Mode {
     NETASCII,
     OCTET,
@@ -37,35 +48,67 @@ impl fmt::Display for Mode {
     }
 }
 
+/// A packet which starts a transfer: a request from a client, or OACK from a server
+/// in reply to a request with options.
 #[derive(Debug)]
 pub enum InitialPacket {
     WRQ(WritePacket),
     RRQ(ReadPacket),
+    OACK(OACK),
 }
 
 impl InitialPacket {
     pub fn parse(s: &[u8]) -> Result<InitialPacket> {
-        let opcode = u16::from_be_bytes(s[..2].try_into()?);
+        if s.len() < 2 {
+            return Err(malformed!("Too short packet as InitialPacket"));
+        }
+        let opcode = read_u16(s);
         match opcode {
             ReadPacket::OPCODE => Ok(InitialPacket::RRQ(ReadPacket::parse(s)?)),
             WritePacket::OPCODE => Ok(InitialPacket::WRQ(WritePacket::parse(s)?)),
-            _ => bail!("Unknown InitialPacket"),
+            OACK::OPCODE => Ok(InitialPacket::OACK(OACK::parse(s)?)),
+            _ => Err(malformed!("Unknown InitialPacket")),
         }
     }
+
+    /// Whether the packet is a request, which a server accepts to start a transfer.
+    pub fn is_request(&self) -> bool {
+        !matches!(self, InitialPacket::OACK(_))
+    }
 }
 
 #[derive(Debug)]
 pub struct WritePacket {
+    /// The last component of the requested filename, which is safe to join to a directory.
     pub filename: String,
+    /// The filename as requested by the client (it may contain directories).
+    pub raw_filename: String,
     pub mode: Mode,
+    pub options: Vec<(String, String)>,
 }
 
 impl WritePacket {
     const OPCODE: u16 = 0x02;
 
-    #[allow(dead_code)]
-    pub(crate) fn new(filename: String, mode: Mode) -> WritePacket {
-        WritePacket { filename, mode }
+    /// Build a request without options. `filename` is sent as is, and its last component is
+    /// used as `filename` in the same way as a received request.
+    pub fn new(filename: String, mode: Mode) -> WritePacket {
+        WritePacket::new_with_options(filename, mode, vec![])
+    }
+
+    pub fn new_with_options(
+        filename: String,
+        mode: Mode,
+        options: Vec<(String, String)>,
+    ) -> WritePacket {
+        WritePacket {
+            // a filename without the last component (e.g. "..") is rejected when received,
+            // and an empty one never points to a file in the base directory
+            filename: last_component(&filename).unwrap_or_default(),
+            raw_filename: filename,
+            mode,
+            options,
+        }
     }
 
     fn parse(s: &[u8]) -> Result<WritePacket> {
@@ -73,44 +116,61 @@ impl WritePacket {
         //  ------------------------------------------------
         // | Opcode |  Filename  |   0  |    Mode    |   0  |
         //  ------------------------------------------------
-        let opcode = u16::from_be_bytes(s[..2].try_into()?);
+        let opcode = read_u16(s);
         if opcode != WritePacket::OPCODE {
-            bail!("Illegal opcode as WRQ");
+            return Err(malformed!("Illegal opcode as WRQ"));
         }
-        let s = &s[2..];
-        let bs: Vec<&[u8]> = s.split(|x| *x == 0).collect();
-        if bs.len() != 3 {
-            bail!("Illegal packet as WRQ");
-        }
-        let raw_filename = String::from_utf8_lossy(bs[0]).into_owned();
-        let filename = Path::new(&raw_filename)
-            .file_name()
-            .map(|name| name.to_string_lossy().into_owned())
-            .ok_or_else(|| anyhow!("Illegal format of filename: {}", raw_filename))?;
-        let mode = Mode::parse(bs[1]).ok_or(anyhow!("Failed to parse mode"))?;
-        Ok(WritePacket { filename, mode })
+        let (filename, raw_filename, mode, options) =
+            parse_request(&s[2..]).map_err(|err| malformed!("Illegal packet as WRQ: {}", err))?;
+        Ok(WritePacket {
+            filename,
+            raw_filename,
+            mode,
+            options,
+        })
     }
 
     pub fn encode(&self) -> Vec<u8> {
         let opcode: Vec<u8> = WritePacket::OPCODE.to_be_bytes().to_vec();
-        let filename: Vec<u8> = self.filename.as_bytes().to_vec();
+        let filename: Vec<u8> = self.raw_filename.as_bytes().to_vec();
         let mode: Vec<u8> = self.mode.encode();
-        [opcode, filename, vec![0], mode, vec![0]].concat()
+        let options: Vec<u8> = encode_options(&self.options);
+        [opcode, filename, vec![0], mode, vec![0], options].concat()
     }
 }
 
 #[derive(Debug)]
 pub struct ReadPacket {
+    /// The last component of the requested filename, which is safe to join to a directory.
     pub filename: String,
+    /// The filename as requested by the client (it may contain directories).
+    pub raw_filename: String,
     pub mode: Mode,
+    pub options: Vec<(String, String)>,
 }
 
 impl ReadPacket {
     const OPCODE: u16 = 0x01;
 
-    #[allow(dead_code)]
-    pub(crate) fn new(filename: String, mode: Mode) -> ReadPacket {
-        ReadPacket { filename, mode }
+    /// Build a request without options. `filename` is sent as is, and its last component is
+    /// used as `filename` in the same way as a received request.
+    pub fn new(filename: String, mode: Mode) -> ReadPacket {
+        ReadPacket::new_with_options(filename, mode, vec![])
+    }
+
+    pub fn new_with_options(
+        filename: String,
+        mode: Mode,
+        options: Vec<(String, String)>,
+    ) -> ReadPacket {
+        ReadPacket {
+            // a filename without the last component (e.g. "..") is rejected when received,
+            // and an empty one never points to a file in the base directory
+            filename: last_component(&filename).unwrap_or_default(),
+            raw_filename: filename,
+            mode,
+            options,
+        }
     }
 
     fn parse(s: &[u8]) -> Result<ReadPacket> {
@@ -118,29 +178,141 @@ impl ReadPacket {
         //  ------------------------------------------------
         // | Opcode |  Filename  |   0  |    Mode    |   0  |
         //  ------------------------------------------------
-        let opcode = u16::from_be_bytes(s[..2].try_into()?);
+        let opcode = read_u16(s);
         if opcode != ReadPacket::OPCODE {
-            bail!("Illegal opcode as RRQ");
-        }
-        let s = &s[2..];
-        let bs: Vec<&[u8]> = s.split(|x| *x == 0).collect();
-        if bs.len() != 3 {
-            bail!("Illegal packet as RRQ");
+            return Err(malformed!("Illegal opcode as RRQ"));
         }
-        let raw_filename = String::from_utf8_lossy(bs[0]).into_owned();
-        let filename = Path::new(&raw_filename)
-            .file_name()
-            .map(|name| name.to_string_lossy().into_owned())
-            .ok_or_else(|| anyhow!("Illegal format of filename: {}", raw_filename))?;
-        let mode = Mode::parse(bs[1]).ok_or(anyhow!("Failed to parse mode"))?;
-        Ok(ReadPacket { filename, mode })
+        let (filename, raw_filename, mode, options) =
+            parse_request(&s[2..]).map_err(|err| malformed!("Illegal packet as RRQ: {}", err))?;
+        Ok(ReadPacket {
+            filename,
+            raw_filename,
+            mode,
+            options,
+        })
     }
 
     pub fn encode(&self) -> Vec<u8> {
         let opcode: Vec<u8> = ReadPacket::OPCODE.to_be_bytes().to_vec();
-        let filename: Vec<u8> = self.filename.as_bytes().to_vec();
+        let filename: Vec<u8> = self.raw_filename.as_bytes().to_vec();
         let mode: Vec<u8> = self.mode.encode();
-        [opcode, filename, vec![0], mode, vec![0]].concat()
+        let options: Vec<u8> = encode_options(&self.options);
+        [opcode, filename, vec![0], mode, vec![0], options].concat()
+    }
+}
+
+/// Filename (stripped and raw), mode, and options of RRQ or WRQ.
+type RequestFields = (String, String, Mode, Vec<(String, String)>);
+
+/// Parse the body of RRQ or WRQ (after opcode).
+///
+///  string    1 byte     string   1 byte   string   1 byte   string   1 byte
+///  ----------------------------------------------------------------------
+/// |  Filename  |   0  |    Mode    |   0  |  opt1  |   0  | value1 |   0  | ...
+///  ----------------------------------------------------------------------
+fn parse_request(s: &[u8]) -> Result<RequestFields, String> {
+    let bs: Vec<&[u8]> = s.split(|x| *x == 0).collect();
+    // the last element should be empty because the packet ends with 0
+    if bs.len() < 3 || bs.len().is_multiple_of(2) || !bs[bs.len() - 1].is_empty() {
+        return Err("unexpected number of fields".to_string());
+    }
+    let raw_filename = String::from_utf8_lossy(bs[0]).into_owned();
+    let filename = last_component(&raw_filename)
+        .ok_or_else(|| format!("Illegal format of filename: {}", raw_filename))?;
+    let mode = Mode::parse(bs[1]).ok_or("Failed to parse mode")?;
+    let options = parse_options(&bs[2..(bs.len() - 1)]);
+    Ok((filename, raw_filename, mode, options))
+}
+
+/// The last component of a requested filename, which is safe to join to a directory.
+fn last_component(raw_filename: &str) -> Option<String> {
+    Path::new(raw_filename)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+}
+
+/// Make pairs of option name and value. Option names are case-insensitive, so lowercased here.
+fn parse_options(bs: &[&[u8]]) -> Vec<(String, String)> {
+    bs.chunks(2)
+        .map(|pair| {
+            (
+                String::from_utf8_lossy(pair[0]).to_ascii_lowercase(),
+                String::from_utf8_lossy(pair[1]).into_owned(),
+            )
+        })
+        .collect()
+}
+
+fn encode_options(options: &[(String, String)]) -> Vec<u8> {
+    options
+        .iter()
+        .flat_map(|(name, value)| [name.as_bytes(), &[0], value.as_bytes(), &[0]].concat())
+        .collect()
+}
+
+/// Option Acknowledgment defined in RFC 2347.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OACK {
+    options: Vec<(String, String)>,
+}
+
+impl OACK {
+    const OPCODE: u16 = 0x06;
+
+    pub fn new(options: Vec<(String, String)>) -> OACK {
+        OACK { options }
+    }
+
+    pub fn options(&self) -> &[(String, String)] {
+        &self.options
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn parse(s: &[u8]) -> Result<OACK> {
+        //  2 bytes    string   1 byte   string   1 byte
+        //  ---------------------------------------------
+        // | Opcode |  opt1  |   0  | value1 |   0  | ...
+        //  ---------------------------------------------
+        if s.len() < 2 {
+            return Err(malformed!("Too short packet as OACK"));
+        }
+        let opcode = read_u16(s);
+        if opcode != OACK::OPCODE {
+            return Err(malformed!("Illegal opcode as OACK: {}", opcode));
+        }
+
+        // OACK is sent only if some options are acknowledged
+        let bs: Vec<&[u8]> = s[2..].split(|x| *x == 0).collect();
+        if bs.len() < 3 || bs.len().is_multiple_of(2) || !bs[bs.len() - 1].is_empty() {
+            return Err(malformed!("Illegal packet as OACK"));
+        }
+        let options = parse_options(&bs[..(bs.len() - 1)]);
+        Ok(OACK { options })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let opcode = OACK::OPCODE.to_be_bytes().to_vec();
+        let options = encode_options(&self.options);
+        [opcode, options].concat()
+    }
+}
+
+impl fmt::Display for OACK {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("OACK {")?;
+        for (i, (name, value)) in self.options.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            f.write_fmt(format_args!(" {}: {}", name, value))?;
+        }
+        f.write_str(" }")
     }
 }
 
@@ -150,7 +322,9 @@ pub struct ACK {
 }
 
 impl ACK {
-    const OPCODE: u16 = 0x04;
+    pub(crate) const OPCODE: u16 = 0x04;
+    /// ACK has no payload.
+    pub(crate) const SIZE: usize = 4;
 
     pub fn new(block: u16) -> ACK {
         ACK { block }
@@ -165,12 +339,15 @@ impl ACK {
         //  ---------------------
         // | Opcode |   Block #  |
         //  ---------------------
-        let opcode = u16::from_be_bytes(s[..2].try_into()?);
+        if s.len() < 4 {
+            return Err(malformed!("Too short packet as ACK"));
+        }
+        let opcode = read_u16(s);
         if opcode != ACK::OPCODE {
-            bail!("Illegal opcode as Data: {}", opcode);
+            return Err(malformed!("Illegal opcode as ACK: {}", opcode));
         }
 
-        let block = u16::from_be_bytes(s[2..4].try_into()?);
+        let block = read_u16(&s[2..]);
         Ok(ACK { block })
     }
 
@@ -187,7 +364,7 @@ pub struct Data {
 }
 
 impl Data {
-    const OPCODE: u16 = 0x03;
+    pub(crate) const OPCODE: u16 = 0x03;
 
     pub fn new(block: u16, data: &[u8]) -> Data {
         Data {
@@ -205,18 +382,30 @@ impl Data {
     }
 
     pub fn parse(s: &[u8]) -> Result<Data> {
+        let (block, data) = Data::parse_borrowed(s)?;
+        Ok(Data {
+            block,
+            data: data.to_owned(),
+        })
+    }
+
+    /// Parse DATA without copying the payload.
+    /// Returns the block number and the payload, which is a slice of `s`.
+    pub fn parse_borrowed(s: &[u8]) -> Result<(u16, &[u8])> {
         //  2 bytes     2 bytes      n bytes
         //  ----------------------------------
         // | Opcode |   Block #  |   Data     |
         //  ----------------------------------
-        let opcode = u16::from_be_bytes(s[..2].try_into()?);
+        if s.len() < 4 {
+            return Err(malformed!("Too short packet as Data"));
+        }
+        let opcode = read_u16(s);
         if opcode != Data::OPCODE {
-            bail!("Illegal opcode as Data: {}", opcode);
+            return Err(malformed!("Illegal opcode as Data: {}", opcode));
         }
 
-        let block = u16::from_be_bytes(s[2..4].try_into()?);
-        let data = s[4..].to_owned();
-        Ok(Data { block, data })
+        let block = read_u16(&s[2..]);
+        Ok((block, &s[4..]))
     }
 
     pub fn encode(&self) -> Vec<u8> {
@@ -263,16 +452,20 @@ impl Error {
         //  -----------------------------------------
         // | Opcode |  ErrorCode |   ErrMsg   |   0  |
         //  -----------------------------------------
-        let opcode = u16::from_be_bytes(data[..2].try_into()?);
+        if data.len() < 4 {
+            return Err(malformed!("Too short packet as Error"));
+        }
+        let opcode = read_u16(data);
         if opcode != Error::OPCODE {
-            bail!("Illegal opcode as Error");
+            return Err(malformed!("Illegal opcode as Error"));
         }
 
-        let error_code = u16::from_be_bytes(data[2..4].try_into()?);
-        let tftp_error = TftpError::from_u16(error_code).ok_or(anyhow!("Illegal error code"))?;
+        let error_code = read_u16(&data[2..]);
+        let tftp_error =
+            TftpError::from_u16(error_code).ok_or_else(|| malformed!("Illegal error code"))?;
 
         if data.last() != Some(&b'\0') {
-            bail!("Illegal packet as Error");
+            return Err(malformed!("Illegal packet as Error"));
         }
 
         let msg = String::from_utf8_lossy(&data[4..(data.len() - 1)]).to_string();
@@ -315,7 +508,7 @@ mod tests {
             0x00,
         ];
         let res = WritePacket::parse(&s);
-        assert!(res.is_err());
+        assert!(matches!(res, Err(crate::Error::Protocol(_))));
     }
 
     #[test]
@@ -330,6 +523,34 @@ mod tests {
         assert_eq!(res.mode, Mode::NETASCII);
     }
 
+    #[test]
+    fn test_parse_rrq_with_raw_filename() {
+        let s = [&[0x00, 0x01][..], b"a/b/c.txt\x00octet\x00"].concat();
+        let res = ReadPacket::parse(&s).unwrap();
+        assert_eq!(res.filename, "c.txt");
+        assert_eq!(res.raw_filename, "a/b/c.txt");
+        // the request is encoded as it is
+        assert_eq!(res.encode(), s);
+    }
+
+    #[test]
+    fn test_new_request_with_directories() {
+        let rrq = ReadPacket::new("../etc/passwd".to_string(), Mode::OCTET);
+        assert_eq!(rrq.filename, "passwd");
+        assert_eq!(rrq.raw_filename, "../etc/passwd");
+        let parsed = ReadPacket::parse(&rrq.encode()).unwrap();
+        assert_eq!(parsed.filename, rrq.filename);
+        assert_eq!(parsed.raw_filename, rrq.raw_filename);
+
+        let wrq = WritePacket::new("a/b/c.txt".to_string(), Mode::OCTET);
+        assert_eq!(wrq.filename, "c.txt");
+        assert_eq!(wrq.raw_filename, "a/b/c.txt");
+
+        let wrq = WritePacket::new("..".to_string(), Mode::OCTET);
+        assert_eq!(wrq.filename, "");
+        assert!(WritePacket::parse(&wrq.encode()).is_err());
+    }
+
     #[test]
     fn test_parse_rrq_ok() {
         // opcode=1, filename=Cargo.toml, mode=netascii
@@ -365,6 +586,103 @@ mod tests {
         assert_eq!(res.mode, Mode::NETASCII);
     }
 
+    #[test]
+    fn test_parse_rrq_with_options() {
+        // opcode=1, filename=foo, mode=octet, tsize=0, BLKSIZE=1024
+        let s = [
+            b"\x00\x01foo\0octet\0".to_vec(),
+            b"tsize\x000\0BLKSIZE\x001024\0".to_vec(),
+        ]
+        .concat();
+        let res = ReadPacket::parse(&s).unwrap();
+        assert_eq!(res.filename, "foo");
+        assert_eq!(res.mode, Mode::OCTET);
+        assert_eq!(
+            res.options,
+            vec![
+                ("tsize".to_string(), "0".to_string()),
+                ("blksize".to_string(), "1024".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_rrq_with_incomplete_option() {
+        // opcode=1, filename=foo, mode=octet, tsize (without value)
+        let s = b"\x00\x01foo\0octet\0tsize\0";
+        let res = ReadPacket::parse(s);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_encode_rrq_with_options() {
+        let rrq = ReadPacket::new_with_options(
+            "foo".to_string(),
+            Mode::OCTET,
+            vec![("tsize".to_string(), "0".to_string())],
+        );
+        assert_eq!(rrq.encode(), b"\x00\x01foo\0octet\0tsize\x000\0".to_vec());
+    }
+
+    #[test]
+    fn test_parse_oack() {
+        let s = b"\x00\x06tsize\x00513\0";
+        let oack = OACK::parse(s).unwrap();
+        assert_eq!(oack.get("tsize"), Some("513"));
+
+        let s = b"\x00\x06blksize\x001024\0tsize\x00513\0";
+        let oack = OACK::parse(s).unwrap();
+        assert_eq!(
+            oack.options(),
+            [
+                ("blksize".to_string(), "1024".to_string()),
+                ("tsize".to_string(), "513".to_string())
+            ]
+        );
+
+        // no option, an option without value, or trailing garbage
+        for s in [
+            &b"\x00"[..],
+            b"\x00\x06",
+            b"\x00\x06tsize\0",
+            b"\x00\x06tsize\x00513",
+            b"\x00\x06tsize\x00513\0abc",
+            b"\x00\x04tsize\x00513\0",
+        ] {
+            assert!(OACK::parse(s).is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn test_encode_oack() {
+        let oack = OACK::new(vec![("tsize".to_string(), "513".to_string())]);
+        assert_eq!(oack.encode(), b"\x00\x06tsize\x00513\0".to_vec());
+    }
+
+    #[test]
+    fn test_parse_oack_as_initial_packet() {
+        let oack = OACK::new(vec![
+            ("blksize".to_string(), "1024".to_string()),
+            ("tsize".to_string(), "513".to_string()),
+        ]);
+        match InitialPacket::parse(&oack.encode()).unwrap() {
+            InitialPacket::OACK(parsed) => assert_eq!(parsed, oack),
+            other => panic!("expected OACK: {:?}", other),
+        }
+        let rrq = ReadPacket::new("foo".to_string(), Mode::OCTET);
+        assert!(InitialPacket::parse(&rrq.encode()).unwrap().is_request());
+        assert!(!InitialPacket::parse(&oack.encode()).unwrap().is_request());
+
+        for s in [
+            &b"\x00\x06"[..],
+            b"\x00\x06tsize\0",
+            b"\x00\x06tsize\x00513",
+            b"\x00\x06tsize\x00513\0abc",
+        ] {
+            assert!(InitialPacket::parse(s).is_err(), "{:?}", s);
+        }
+    }
+
     #[test]
     fn test_parse_ack() {
         let s = [0x00, 0x04, 0x00, 0x01];
@@ -372,6 +690,31 @@ mod tests {
         assert_eq!(ack.block(), 1);
     }
 
+    #[test]
+    fn test_parse_truncated_packets() {
+        let s = [0x00, 0x04, 0x00, 0x01];
+        for n in 0..4 {
+            assert!(ACK::parse(&s[..n]).is_err(), "{:?}", &s[..n]);
+        }
+        let s = [0x00, 0x03, 0x00, 0x01];
+        for n in 0..4 {
+            assert!(Data::parse(&s[..n]).is_err(), "{:?}", &s[..n]);
+            assert!(Data::parse_borrowed(&s[..n]).is_err(), "{:?}", &s[..n]);
+        }
+        let s = [0x00, 0x05, 0x00, 0x01];
+        for n in 0..4 {
+            assert!(Error::parse(&s[..n]).is_err(), "{:?}", &s[..n]);
+        }
+        let s = [0x00, 0x06, b'a', 0x00];
+        for n in 0..4 {
+            assert!(OACK::parse(&s[..n]).is_err(), "{:?}", &s[..n]);
+        }
+        let s = [0x00, 0x01, b'a', 0x00];
+        for n in 0..4 {
+            assert!(InitialPacket::parse(&s[..n]).is_err(), "{:?}", &s[..n]);
+        }
+    }
+
     #[test]
     fn test_encode_ack() {
         let ack = ACK::new(1);
@@ -386,6 +729,19 @@ mod tests {
         assert_eq!(data.data(), &s[4..]);
     }
 
+    #[test]
+    fn test_parse_data_borrowed() {
+        let s = [0x00, 0x03, 0x00, 0x01, 0x68, 0x65, 0x6c, 0x6c, 0x6f];
+        let (block, data) = Data::parse_borrowed(&s).unwrap();
+        assert_eq!(block, 1);
+        assert_eq!(data, b"hello");
+        // the payload points to the original buffer
+        assert_eq!(data.as_ptr(), s[4..].as_ptr());
+
+        let s = [0x00, 0x04, 0x00, 0x01];
+        assert!(Data::parse_borrowed(&s).is_err());
+    }
+
     #[test]
     fn test_encode_data() {
         let data = Data::new(1, &b"hello"[..]);
@@ -407,6 +763,10 @@ mod tests {
         let pkt = Error::parse(&data).unwrap();
         assert_eq!(pkt.error_code(), 1);
         assert_eq!(pkt.message(), "File not found");
+
+        // too short to have an error code
+        assert!(Error::parse(&[0x00, 0x05]).is_err());
+        assert!(Error::parse(&[0x00]).is_err());
     }
 
     #[test]
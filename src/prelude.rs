@@ -0,0 +1,42 @@
+//! Types commonly needed to embed the server or to write custom handlers.
+//!
+//! ```
+//! use std::net::{SocketAddr, UdpSocket};
+//! use tftpff::prelude::*;
+//!
+//! // serve the requested filename as the content of a file
+//! let rrq_handler = |sock: UdpSocket, client_addr: SocketAddr, rrq: ReadPacket| -> Result<()> {
+//!     let data = Data::new(1, rrq.filename.as_bytes());
+//!     sock.send_to(&data.encode(), client_addr)?;
+//!     Ok(())
+//! };
+//! // reject all uploads
+//! let wrq_handler = |sock: UdpSocket, client_addr: SocketAddr, _: WritePacket| -> Result<()> {
+//!     let err = ErrorPacket::new(TftpError::AccessViolation, "Read only".to_string());
+//!     sock.send_to(&err.encode(), client_addr)?;
+//!     Ok(())
+//! };
+//!
+//! let sock_client = UdpSocket::bind("127.0.0.1:0").unwrap();
+//! let sock_handler = UdpSocket::bind("127.0.0.1:0").unwrap();
+//! let rrq = ReadPacket::new("hello".to_string(), Mode::OCTET);
+//! rrq_handler(sock_handler, sock_client.local_addr().unwrap(), rrq).unwrap();
+//! let mut buf = [0; 1024];
+//! let (n, _) = sock_client.recv_from(&mut buf).unwrap();
+//! assert_eq!(Data::parse(&buf[..n]).unwrap().data(), b"hello");
+//!
+//! let server = TftpServer::create_with_handlers(
+//!     "127.0.0.1".parse().unwrap(),
+//!     0,
+//!     Box::new(rrq_handler),
+//!     Box::new(wrq_handler),
+//! )
+//! .with_config(Config::default());
+//! ```
+
+pub use crate::config::Config;
+pub use crate::error::{Error, Result, TftpError};
+pub use crate::packet::{
+    Data, Error as ErrorPacket, InitialPacket, Mode, ReadPacket, WritePacket, ACK, OACK,
+};
+pub use crate::server::{AuthHook, RRQHandler, TftpServer, WRQHandler};
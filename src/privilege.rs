@@ -1,36 +1,327 @@
-use anyhow::{Context, Result};
+use crate::error::{Error, Result};
+use log::warn;
 use nix;
+use nix::unistd::{AccessFlags, Gid, Group, Uid, User};
 use std::ffi::CString;
 use std::path::Path;
 
 pub fn chmod(path: impl AsRef<Path>, mode: u32) -> Result<()> {
-    let path = CString::new(path.as_ref().to_string_lossy().to_string())?;
-    let res = unsafe { nix::libc::chmod(path.as_ptr(), mode) };
-    nix::errno::Errno::result(res).map(drop)?;
+    let path = path.as_ref();
+    let c_path = CString::new(path.to_string_lossy().to_string())
+        .map_err(|err| Error::Privilege(format!("Illegal path {:?}: {}", path, err)))?;
+    let res = unsafe { nix::libc::chmod(c_path.as_ptr(), mode) };
+    nix::errno::Errno::result(res)
+        .map_err(|err| Error::Privilege(format!("Failed to chmod {:?}: {}", path, err)))?;
 
     Ok(())
 }
 
+/// Change the owner of the path.
+/// This is skipped if the process is not root, because only root can give a file away.
 pub fn chown(path: impl AsRef<Path>, user: &str, group: &str) -> Result<()> {
-    let group = nix::unistd::Group::from_name(group)?
-        .with_context(|| format!("group is not found: {}", group))?;
-    let user = nix::unistd::User::from_name(user)?
-        .with_context(|| format!("user is not found: {}", user))?;
-    nix::unistd::chown(path.as_ref(), Some(user.uid), Some(group.gid))?;
+    let path = path.as_ref();
+    let group = find_group(group)?;
+    let user = find_user(user)?;
+    if !Uid::effective().is_root() {
+        warn!("Not running as root, skip chown {:?}", path);
+        return Ok(());
+    }
+    nix::unistd::chown(path, Some(user), Some(group))
+        .map_err(|err| Error::Privilege(format!("Failed to chown {:?}: {}", path, err)))?;
 
     Ok(())
 }
 
+/// A user and group to switch to.
+/// They are looked up in advance when the process is chrooted,
+/// because /etc/passwd and /etc/group are not visible after `chroot`.
+pub struct Identity {
+    uid: Uid,
+    gid: Gid,
+}
+
+impl Identity {
+    pub fn lookup(user: &str, group: &str) -> Result<Identity> {
+        let gid = find_group(group)?;
+        let uid = find_user(user)?;
+        Ok(Identity { uid, gid })
+    }
+}
+
+/// Switch to the user and group.
+/// If the process is not root (e.g. started by a normal user for local testing),
+/// there is no privilege to drop and setuid/setgid would fail with EPERM,
+/// so the process keeps running as the current user.
 pub fn drop_privilege(user: &str, group: &str) -> Result<()> {
+    drop_privilege_to(&Identity::lookup(user, group)?)
+}
+
+/// Same as `drop_privilege` with the user and group looked up already.
+pub fn drop_privilege_to(identity: &Identity) -> Result<()> {
+    let Identity { uid, gid } = *identity;
+    if !Uid::effective().is_root() {
+        if uid != Uid::effective() || gid != Gid::effective() {
+            warn!(
+                "Not running as root, keep running as uid {} and gid {} instead of {} and {}",
+                Uid::effective(),
+                Gid::effective(),
+                uid,
+                gid
+            );
+        }
+        return Ok(());
+    }
+
     // should drop group privilege first
     // ref. https://wiki.sei.cmu.edu/confluence/display/c/POS36-C.+Observe+correct+revocation+order+while+relinquishing+privileges
-    let group = nix::unistd::Group::from_name(group)?
-        .with_context(|| format!("group is not found: {}", group))?;
-    nix::unistd::setgid(group.gid)?;
+    nix::unistd::setgid(gid)
+        .map_err(|err| Error::Privilege(format!("Failed to setgid: {}", err)))?;
+    nix::unistd::setuid(uid)
+        .map_err(|err| Error::Privilege(format!("Failed to setuid: {}", err)))?;
+
+    Ok(())
+}
 
-    let user = nix::unistd::User::from_name(user)?
-        .with_context(|| format!("user is not found: {}", user))?;
-    nix::unistd::setuid(user.uid)?;
+/// Change the root directory of the process to the path and move into it.
+/// This requires root, so it should be called after binding the socket (which may need
+/// root for a privileged port) and before `drop_privilege_to`, for which the user and group
+/// have to be looked up before this.
+pub fn chroot(path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    nix::unistd::chroot(path)
+        .map_err(|err| Error::Privilege(format!("Failed to chroot to {:?}: {}", path, err)))?;
+    // otherwise the working directory is still outside of the new root
+    nix::unistd::chdir("/")
+        .map_err(|err| Error::Privilege(format!("Failed to chdir after chroot: {}", err)))?;
 
     Ok(())
 }
+
+/// Refuse to keep running as root in a chroot, from which root can escape easily
+/// (e.g. by chroot again). It is fine if the process is not root, which cannot chroot anyway.
+pub fn check_chroot_identity(identity: &Identity) -> Result<()> {
+    if Uid::effective().is_root() && identity.uid.is_root() {
+        return Err(Error::Privilege(
+            "chroot requires a user other than root to drop privilege to".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Check the current user can access the directory.
+/// This is useful to detect misconfiguration just after dropping privilege
+/// instead of failing in each transfer.
+pub fn check_dir_access(path: impl AsRef<Path>, writable: bool) -> Result<()> {
+    let path = path.as_ref();
+    let mut flags = AccessFlags::R_OK | AccessFlags::X_OK;
+    if writable {
+        flags |= AccessFlags::W_OK;
+    }
+    nix::unistd::access(path, flags).map_err(|err| {
+        Error::Privilege(format!(
+            "uid {} doesn't have {} permission for {:?}: {}",
+            nix::unistd::Uid::effective(),
+            if writable { "read and write" } else { "read" },
+            path,
+            err
+        ))
+    })?;
+
+    Ok(())
+}
+
+/// A numeric id is used as is, which works without an entry in /etc/group
+/// (e.g. in a minimal container).
+fn find_group(name: &str) -> Result<Gid> {
+    if let Ok(gid) = name.parse::<u32>() {
+        return Ok(Gid::from_raw(gid));
+    }
+    Group::from_name(name)
+        .map_err(|err| Error::Privilege(format!("Failed to find group {}: {}", name, err)))?
+        .map(|group| group.gid)
+        .ok_or_else(|| Error::Privilege(format!("group is not found: {}", name)))
+}
+
+/// A numeric id is used as is, which works without an entry in /etc/passwd.
+fn find_user(name: &str) -> Result<Uid> {
+    if let Ok(uid) = name.parse::<u32>() {
+        return Ok(Uid::from_raw(uid));
+    }
+    User::from_name(name)
+        .map_err(|err| Error::Privilege(format!("Failed to find user {}: {}", name, err)))?
+        .map(|user| user.uid)
+        .ok_or_else(|| Error::Privilege(format!("user is not found: {}", name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp;
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::{fork, ForkResult};
+
+    #[test]
+    fn test_check_dir_access_after_drop_privilege() {
+        if !nix::unistd::Uid::effective().is_root() {
+            // this test requires root to drop privilege
+            return;
+        }
+
+        //
+        // setup
+        //
+        let dir = temp::create_temp_dir().unwrap();
+        chmod(dir.path(), 0o700).unwrap();
+        let user = nix::unistd::User::from_name("nobody").unwrap().unwrap();
+
+        //
+        // exercise
+        //
+        // drop privilege in a child process not to affect other tests
+        let status = match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let code = (|| {
+                    nix::unistd::setgid(user.gid).ok()?;
+                    nix::unistd::setuid(user.uid).ok()?;
+                    let err = check_dir_access(dir.path(), false).err()?;
+                    err.to_string().contains("permission").then_some(0)
+                })()
+                .unwrap_or(1);
+                unsafe { nix::libc::_exit(code) }
+            }
+            ForkResult::Parent { child } => waitpid(child, None).unwrap(),
+        };
+
+        //
+        // verify
+        //
+        assert!(matches!(status, WaitStatus::Exited(_, 0)));
+        assert!(check_dir_access(dir.path(), true).is_ok());
+    }
+
+    #[test]
+    fn test_drop_privilege_without_root() {
+        if !nix::unistd::Uid::effective().is_root() {
+            // already without root, just check nothing fails
+            let user = User::from_uid(Uid::effective()).unwrap().unwrap();
+            let group = Group::from_gid(Gid::effective()).unwrap().unwrap();
+            assert!(drop_privilege(&user.name, &group.name).is_ok());
+            return;
+        }
+
+        //
+        // setup
+        //
+        let dir = temp::create_temp_dir().unwrap();
+        let user = User::from_name("nobody").unwrap().unwrap();
+        let group = Group::from_gid(user.gid).unwrap().unwrap();
+
+        //
+        // exercise
+        //
+        // become a non-root user in a child process not to affect other tests
+        let status = match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let code = (|| {
+                    nix::unistd::setgid(user.gid).ok()?;
+                    nix::unistd::setuid(user.uid).ok()?;
+                    // the same user as the current one
+                    drop_privilege("nobody", &group.name).ok()?;
+                    // another user cannot be switched to, but startup should go on
+                    drop_privilege("root", "root").ok()?;
+                    chown(dir.path(), "root", "root").ok()?;
+                    (Uid::effective() == user.uid).then_some(0)
+                })()
+                .unwrap_or(1);
+                unsafe { nix::libc::_exit(code) }
+            }
+            ForkResult::Parent { child } => waitpid(child, None).unwrap(),
+        };
+
+        //
+        // verify
+        //
+        assert!(matches!(status, WaitStatus::Exited(_, 0)));
+    }
+
+    #[test]
+    fn test_check_chroot_identity() {
+        let root = Identity::lookup("0", "0").unwrap();
+        let nobody = Identity::lookup("nobody", "0").unwrap();
+        assert!(check_chroot_identity(&nobody).is_ok());
+        if Uid::effective().is_root() {
+            let err = check_chroot_identity(&root).unwrap_err();
+            assert!(err.to_string().contains("other than root"), "{}", err);
+        } else {
+            assert!(check_chroot_identity(&root).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_chroot() {
+        if !nix::unistd::Uid::effective().is_root() {
+            // this test requires root to chroot
+            return;
+        }
+
+        //
+        // setup
+        //
+        let dir = temp::create_temp_dir().unwrap();
+        chmod(dir.path(), 0o755).unwrap();
+        std::fs::write(dir.path().join("foo.txt"), b"foo").unwrap();
+        let user = User::from_name("nobody").unwrap().unwrap();
+        let group = Group::from_gid(user.gid).unwrap().unwrap();
+
+        //
+        // exercise
+        //
+        // chroot in a child process not to affect other tests
+        let status = match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let code = (|| {
+                    let identity = Identity::lookup("nobody", &group.name).ok()?;
+                    chroot(dir.path()).ok()?;
+                    drop_privilege_to(&identity).ok()?;
+                    (Uid::effective() == user.uid
+                        && std::env::current_dir().ok()? == Path::new("/")
+                        && std::fs::read("/foo.txt").ok()? == b"foo"
+                        && !dir.path().exists())
+                    .then_some(0)
+                })()
+                .unwrap_or(1);
+                unsafe { nix::libc::_exit(code) }
+            }
+            ForkResult::Parent { child } => waitpid(child, None).unwrap(),
+        };
+
+        //
+        // verify
+        //
+        assert!(matches!(status, WaitStatus::Exited(_, 0)));
+    }
+
+    #[test]
+    fn test_find_user_and_group() {
+        assert_eq!(find_user("0").unwrap(), Uid::from_raw(0));
+        assert_eq!(find_user("root").unwrap(), Uid::from_raw(0));
+        assert_eq!(find_group("0").unwrap(), Gid::from_raw(0));
+        assert_eq!(find_group("root").unwrap(), Gid::from_raw(0));
+        // no entry is necessary for a numeric id
+        assert_eq!(find_user("54321").unwrap(), Uid::from_raw(54321));
+        assert_eq!(find_group("54321").unwrap(), Gid::from_raw(54321));
+    }
+
+    #[test]
+    fn test_unknown_user_and_group() {
+        let dir = temp::create_temp_dir().unwrap();
+        assert!(matches!(
+            chown(dir.path(), "tftpff-no-such-user", "root"),
+            Err(Error::Privilege(_))
+        ));
+        assert!(matches!(
+            drop_privilege("root", "tftpff-no-such-group"),
+            Err(Error::Privilege(_))
+        ));
+    }
+}
@@ -1,27 +1,58 @@
-use crate::error::TftpErrorNotifier;
+use crate::checksum::Crc32;
+use crate::config::{Config, ErrorMessages, ErrorPolicy, FinalAckPolicy};
+use crate::error::{send_error_packet, Error, Result, TftpError, TftpErrorNotifier};
+use crate::limit::{ByteBudget, TokenBucket};
+use crate::options::TransferOptions;
 use crate::packet::{ReadPacket, WritePacket};
-use crate::{file, packet, socket, temp};
-use anyhow::{bail, Context, Result};
-use log::{debug, error, warn};
-use std::io::{ErrorKind, Read, Write};
+use crate::sink::{TempFileSink, WrqSink};
+use crate::stats::{self, Operation, ServerStats, StatsSnapshot, TransferInfo};
+use crate::window::Window;
+use crate::{file, packet, socket};
+use anyhow::{bail, Context};
+use log::{debug, error, info, log, warn, Level};
+use std::collections::{HashSet, VecDeque};
+use std::fmt::Formatter;
+use std::io::{self, ErrorKind, Read};
 use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
-use std::time::Duration;
-use std::{fs, thread};
+use std::time::{Duration, Instant};
+use std::{fmt, thread};
 
-type RRQHandler = dyn Fn(UdpSocket, SocketAddr, ReadPacket) -> Result<()> + Send + Sync;
-type WRQHandler = dyn Fn(UdpSocket, SocketAddr, WritePacket) -> Result<()> + Send + Sync;
+/// The maximum size of RRQ and WRQ accepted by the server.
+/// This is large enough for a request with a long filename and some options.
+const MAX_REQUEST_SIZE: usize = 4096;
+/// The maximum size of packets other than DATA and ACK received in transfers
+/// (e.g. ERROR or a retransmitted request).
+const MAX_CONTROL_PACKET_SIZE: usize = MAX_REQUEST_SIZE;
+/// The minimum interval of retransmission in handlers.
+/// A shorter read timeout of the handler socket is raised to this,
+/// otherwise a near-zero timeout makes the handler retransmit in a busy loop.
+const MIN_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A handler of RRQ called with a socket connected to the client.
+pub type RRQHandler = dyn Fn(UdpSocket, SocketAddr, ReadPacket) -> Result<()> + Send + Sync;
+/// A handler of WRQ called with a socket connected to the client.
+pub type WRQHandler = dyn Fn(UdpSocket, SocketAddr, WritePacket) -> Result<()> + Send + Sync;
+/// A hook to authorize a request before a handler is spawned.
+/// A denied request is answered with the returned error code and message.
+pub type AuthHook = dyn Fn(&SocketAddr, &packet::InitialPacket) -> std::result::Result<(), (TftpError, String)>
+    + Send
+    + Sync;
 
 pub struct TftpServer {
     server_addr: Ipv4Addr,
     server_port: u16,
-    retry_interval: Duration,
     rrq_handler: Arc<RRQHandler>,
     wrq_handler: Arc<WRQHandler>,
     server_sock: Option<UdpSocket>,
+    config: Config,
+    active_transfers: Arc<Mutex<HashSet<TransferKey>>>,
+    stats: Arc<ServerStats>,
+    auth: Option<Box<AuthHook>>,
 }
 
 impl TftpServer {
@@ -30,19 +61,64 @@ impl TftpServer {
         server_port: u16,
         base_dir: impl AsRef<Path> + Send + Sync + 'static,
         temp_dir: impl AsRef<Path> + Send + Sync + 'static,
+        mut config: Config,
     ) -> Result<TftpServer> {
-        let rrq_handler = create_rrq_handler(base_dir.as_ref().to_owned());
-        let wrq_handler = create_wrq_handler(base_dir, temp_dir);
+        if config.retry_interval.is_zero() {
+            return Err(Error::Config("Retry interval must be positive".to_string()));
+        }
+        if !config.stage_in_place && is_same_dir(base_dir.as_ref(), temp_dir.as_ref()) {
+            // otherwise partial uploads are visible (and served) with their temporary names
+            warn!(
+                "temporary directory is the same as base directory {:?}, stage uploads in place",
+                base_dir.as_ref()
+            );
+            config.stage_in_place = true;
+        }
+        let stats = Arc::new(ServerStats::default());
+        let rrq_handler = create_rrq_handler_with_stats(
+            base_dir.as_ref().to_owned(),
+            config.clone(),
+            Arc::clone(&stats),
+        );
+        let wrq_handler =
+            create_wrq_handler_with_stats(base_dir, temp_dir, config.clone(), Arc::clone(&stats));
         Ok(TftpServer {
             server_addr,
             server_port,
-            retry_interval: Duration::from_secs(5),
             rrq_handler: Arc::new(rrq_handler),
             wrq_handler: Arc::new(wrq_handler),
             server_sock: None,
+            config,
+            active_transfers: Arc::new(Mutex::new(HashSet::new())),
+            stats,
+            auth: None,
         })
     }
 
+    /// Create a server with a socket bound in advance (e.g. passed by socket activation).
+    /// `bind` is not necessary for the server.
+    /// SO_REUSEPORT and SO_REUSEADDR are set to the socket
+    /// because sockets for transfers are bound to the same address.
+    pub fn from_socket(
+        sock: UdpSocket,
+        base_dir: impl AsRef<Path> + Send + Sync + 'static,
+        temp_dir: impl AsRef<Path> + Send + Sync + 'static,
+        config: Config,
+    ) -> Result<TftpServer> {
+        let addr = match sock.local_addr()? {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(addr) => {
+                return Err(Error::Config(format!("IPv6 is not supported: {}", addr)))
+            }
+        };
+        socket::reuse_port(sock.as_raw_fd())?;
+        set_recv_buffer_size(&sock, &config)?;
+        sock.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let mut server = TftpServer::create(*addr.ip(), addr.port(), base_dir, temp_dir, config)?;
+        server.server_sock = Some(sock);
+        Ok(server)
+    }
+
     pub fn create_with_handlers(
         server_addr: Ipv4Addr,
         server_port: u16,
@@ -52,13 +128,50 @@ impl TftpServer {
         TftpServer {
             server_addr,
             server_port,
-            retry_interval: Duration::from_secs(5),
             rrq_handler: Arc::from(rrq_handler),
             wrq_handler: Arc::from(wrq_handler),
             server_sock: None,
+            config: Config::default(),
+            active_transfers: Arc::new(Mutex::new(HashSet::new())),
+            stats: Arc::new(ServerStats::default()),
+            auth: None,
         }
     }
 
+    /// Set the configuration used by the server itself (handlers are not affected).
+    pub fn with_config(mut self, config: Config) -> TftpServer {
+        self.config = config;
+        self
+    }
+
+    /// Authorize requests by `auth` before transfers start.
+    pub fn with_auth(mut self, auth: Box<AuthHook>) -> TftpServer {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Current stats of transfers.
+    /// Bytes are counted only by the default handlers.
+    pub fn stats(&self) -> StatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    /// Details of transfers in progress.
+    /// Bytes are counted only by the default handlers.
+    pub fn active_transfers(&self) -> Vec<TransferInfo> {
+        self.stats.active_transfers()
+    }
+
+    /// Stop transfers with the client. Their handlers send an ERROR packet to the client
+    /// when they receive the next packet or time out.
+    /// Returns the number of transfers cancelled.
+    /// Only the default handlers can be cancelled.
+    pub fn cancel(&self, client_addr: SocketAddr) -> usize {
+        let count = self.stats.cancel(client_addr);
+        info!("cancel {} transfer(s) with {}", count, client_addr);
+        count
+    }
+
     pub fn server_addr(&self) -> Option<SocketAddr> {
         self.server_sock
             .as_ref()
@@ -67,17 +180,61 @@ impl TftpServer {
 
     pub fn bind(&mut self) -> Result<()> {
         let server_sock_addr = SocketAddr::from((self.server_addr, self.server_port));
-        let server_sock = socket::create_udp_socket(server_sock_addr)
+        let server_sock = socket::create_udp_socket(server_sock_addr, self.config.freebind)
             .context("Failed to create server socket")?;
+        set_recv_buffer_size(&server_sock, &self.config)?;
         server_sock.set_read_timeout(Some(Duration::from_secs(1)))?;
-        debug!("listening at {}:{}", self.server_addr, self.server_port);
+        // log the actual address because the port may be assigned by OS (e.g. port 0)
+        info!("listening on {}", server_sock.local_addr()?);
         self.server_sock = Some(server_sock);
         Ok(())
     }
 
+    /// Reply an error to requests which have been queued in the socket since `bind`.
+    /// This is expected to be called just before `run` if startup takes long (e.g. dropping privilege),
+    /// so that clients can retry soon instead of waiting for requests processed late.
+    /// Returns the number of rejected requests.
+    pub fn reject_pending_requests(&self) -> Result<usize> {
+        let server_sock = self
+            .server_sock
+            .as_ref()
+            .ok_or_else(|| Error::Config("Server socket is not bound".to_string()))?;
+        server_sock.set_nonblocking(true)?;
+
+        let mut buf = vec![0; MAX_REQUEST_SIZE + 1];
+        let mut count = 0;
+        let result = loop {
+            let (n, client_addr) = match server_sock.recv_from(&mut buf) {
+                Ok(res) => res,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break Ok(count),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => break Err(err),
+            };
+            // reply only to requests not to confirm the service to others
+            if n <= MAX_REQUEST_SIZE
+                && matches!(packet::InitialPacket::parse(&buf[..n]), Ok(request) if request.is_request())
+            {
+                info!("[{}] reject a request received during startup", client_addr);
+                send_error_packet(
+                    server_sock,
+                    &client_addr,
+                    TftpError::Others,
+                    "Server is starting, try again".to_string(),
+                    &self.config.error_messages,
+                    &self.stats,
+                );
+                count += 1;
+            }
+        };
+
+        server_sock.set_nonblocking(false)?;
+        Ok(result?)
+    }
+
     pub fn run(&self) -> Result<()> {
         let server_sock = self.server_sock.as_ref().unwrap();
         let server_addr = server_sock.local_addr()?;
+        info!("start serving on {}", server_addr);
 
         // for graceful shutdown
         let term = Arc::new(AtomicBool::new(false));
@@ -85,8 +242,51 @@ impl TftpServer {
             signal_hook::flag::register(sig, Arc::clone(&term))?;
         }
 
+        if let Some(interval) = self.config.announce_interval {
+            stats::spawn_announcer(Arc::clone(&self.stats), interval, Arc::clone(&term));
+        }
+
+        #[cfg(feature = "metrics")]
+        if let Some(addr) = self.config.metrics_addr {
+            crate::metrics::spawn_exporter(addr, Arc::clone(&self.stats), Arc::clone(&term))
+                .map_err(|err| {
+                    Error::Config(format!("Failed to serve metrics at {}: {}", addr, err))
+                })?;
+            info!("serve metrics at http://{}/metrics", addr);
+        }
+
+        let mut limiter = self
+            .config
+            .max_new_transfers_per_sec
+            .map(|n| TokenBucket::new(n.get(), n.get()));
+        let mut error_limiter = self
+            .config
+            .max_errors_per_sec
+            .map(|n| TokenBucket::new(n.get(), n.get()));
+        let mut egress_budget = self.config.egress_budget.map(|n| {
+            ByteBudget::new(
+                n.get(),
+                self.config.egress_budget_period,
+                self.stats.bytes_sent(),
+            )
+        });
+
+        // one more byte than MAX_REQUEST_SIZE to detect truncation of a larger datagram
+        let mut client_buf = vec![0; MAX_REQUEST_SIZE + 1];
         while !term.load(Ordering::Relaxed) {
-            let mut client_buf = [0; 1024];
+            if let Some(max) = self.config.max_transfers {
+                if self.stats.snapshot().total_transfers >= max.get() {
+                    info!(
+                        "reached the maximum number of transfers ({}), wait for active transfers and exit",
+                        max
+                    );
+                    self.wait_for_transfers();
+                    // stop the announcer
+                    term.store(true, Ordering::Relaxed);
+                    break;
+                }
+            }
+
             let (client_n, client_addr) = match server_sock.recv_from(&mut client_buf) {
                 Ok(res) => res,
                 Err(err)
@@ -95,37 +295,171 @@ impl TftpServer {
                     continue;
                 }
                 Err(err) => {
-                    bail!("Failed to receive request packet: {:?}", err);
+                    self.handle_error(
+                        anyhow::Error::new(err).context("Failed to receive request packet"),
+                    )?;
+                    continue;
                 }
             };
 
-            match packet::InitialPacket::parse(&client_buf[..client_n]) {
-                Ok(packet::InitialPacket::WRQ(wrq)) => match socket::create_udp_socket(server_addr)
-                {
-                    Ok(child_sock) => {
-                        child_sock.set_read_timeout(Some(self.retry_interval))?;
-                        child_sock.set_write_timeout(Some(self.retry_interval))?;
-                        child_sock.connect(&client_addr)?;
-                        self.spawn_wrq(child_sock, client_addr, wrq);
+            if client_n > MAX_REQUEST_SIZE {
+                // the datagram may be truncated, so don't try to parse it
+                warn!("Ignore too large request from {}", client_addr);
+                if !self.config.quiet_errors && error_allowed(&mut error_limiter, &client_addr) {
+                    send_error_packet(
+                        server_sock,
+                        &client_addr,
+                        TftpError::IllegalTftpOp,
+                        "Request too large".to_string(),
+                        &self.config.error_messages,
+                        &self.stats,
+                    );
+                }
+                continue;
+            }
+
+            let request = packet::InitialPacket::parse(&client_buf[..client_n]);
+            // OACK is never sent to a server, so it is left to reply_to_illegal_packet below
+            let is_request = matches!(&request, Ok(request) if request.is_request());
+            if let (true, Some(limiter)) = (is_request, limiter.as_mut()) {
+                if !limiter.try_acquire() {
+                    // drop it silently not to spend more resources for a flood of requests
+                    debug!("[{}] drop request by rate limit", client_addr);
+                    continue;
+                }
+            }
+
+            if let (Ok(request), true, Some(auth)) = (&request, is_request, self.auth.as_ref()) {
+                if let Err((tftp_err, msg)) = auth(&client_addr, request) {
+                    info!("[{}] request denied: {}", client_addr, msg);
+                    if error_allowed(&mut error_limiter, &client_addr) {
+                        send_error_packet(
+                            server_sock,
+                            &client_addr,
+                            tftp_err,
+                            msg,
+                            &self.config.error_messages,
+                            &self.stats,
+                        );
+                    }
+                    continue;
+                }
+            }
+
+            match request {
+                Ok(packet::InitialPacket::WRQ(wrq)) => {
+                    if self.config.read_only {
+                        info!("[{}] reject WRQ in read-only mode", client_addr);
+                        if error_allowed(&mut error_limiter, &client_addr) {
+                            send_error_packet(
+                                server_sock,
+                                &client_addr,
+                                TftpError::AccessViolation,
+                                "Server is read-only".to_string(),
+                                &self.config.error_messages,
+                                &self.stats,
+                            );
+                        }
+                        continue;
                     }
-                    Err(err) => {
-                        error!("Failed to create child_sock for {:?}. {:?}", wrq, err);
+                    let key = TransferKey::new(client_addr, true, &wrq.filename);
+                    match self.start_transfer(&key) {
+                        StartResult::Started => (),
+                        StartResult::AlreadyActive => {
+                            debug!("[{}] ignore retransmitted WRQ: {:?}", client_addr, wrq);
+                            continue;
+                        }
+                        StartResult::Busy => {
+                            self.reject_busy(server_sock, &client_addr, &mut error_limiter);
+                            continue;
+                        }
                     }
-                },
-                Ok(packet::InitialPacket::RRQ(rrq)) => match socket::create_udp_socket(server_addr)
-                {
-                    Ok(child_sock) => {
-                        child_sock.set_read_timeout(Some(self.retry_interval))?;
-                        child_sock.set_write_timeout(Some(self.retry_interval))?;
-                        child_sock.connect(&client_addr)?;
-                        self.spawn_rrq(child_sock, client_addr, rrq);
+                    match create_child_socket(server_addr, client_addr, self.config.retry_interval)
+                    {
+                        Ok(child_sock) => {
+                            self.spawn_wrq(child_sock, client_addr, wrq, key);
+                        }
+                        Err(err) => {
+                            self.active_transfers.lock().unwrap().remove(&key);
+                            self.handle_error(
+                                err.context(format!("Failed to create child_sock for {:?}", wrq)),
+                            )?;
+                        }
+                    }
+                }
+                Ok(packet::InitialPacket::RRQ(rrq)) => {
+                    if self.config.write_only {
+                        info!("[{}] reject RRQ in write-only mode", client_addr);
+                        if error_allowed(&mut error_limiter, &client_addr) {
+                            send_error_packet(
+                                server_sock,
+                                &client_addr,
+                                TftpError::AccessViolation,
+                                "Server is write-only".to_string(),
+                                &self.config.error_messages,
+                                &self.stats,
+                            );
+                        }
+                        continue;
+                    }
+                    if let Some(budget) = egress_budget.as_mut() {
+                        if !budget.is_available(self.stats.bytes_sent()) {
+                            info!("[{}] reject RRQ by egress budget", client_addr);
+                            if error_allowed(&mut error_limiter, &client_addr) {
+                                send_error_packet(
+                                    server_sock,
+                                    &client_addr,
+                                    TftpError::Others,
+                                    "Transfer budget exceeded".to_string(),
+                                    &self.config.error_messages,
+                                    &self.stats,
+                                );
+                            }
+                            continue;
+                        }
+                    }
+                    let key = TransferKey::new(client_addr, false, &rrq.filename);
+                    match self.start_transfer(&key) {
+                        StartResult::Started => (),
+                        StartResult::AlreadyActive => {
+                            debug!("[{}] ignore retransmitted RRQ: {:?}", client_addr, rrq);
+                            continue;
+                        }
+                        StartResult::Busy => {
+                            self.reject_busy(server_sock, &client_addr, &mut error_limiter);
+                            continue;
+                        }
                     }
-                    Err(err) => {
-                        error!("Failed to create child_sock for {:?}. {:?}", rrq, err);
+                    match create_child_socket(server_addr, client_addr, self.config.retry_interval)
+                    {
+                        Ok(child_sock) => {
+                            self.spawn_rrq(child_sock, client_addr, rrq, key);
+                        }
+                        Err(err) => {
+                            self.active_transfers.lock().unwrap().remove(&key);
+                            self.handle_error(
+                                err.context(format!("Failed to create child_sock for {:?}", rrq)),
+                            )?;
+                        }
                     }
-                },
+                }
+                Ok(packet::InitialPacket::OACK(oack)) => {
+                    warn!("Ignore unexpected packet (expected WRQ or RRQ): {}", oack);
+                    self.reply_to_illegal_packet(
+                        server_sock,
+                        &client_addr,
+                        &client_buf[..client_n],
+                        &mut error_limiter,
+                    );
+                }
                 Err(err) => {
                     warn!("Ignore unknown packet (expected WRQ or RRQ): {:?}", err);
+                    self.reply_to_illegal_packet(
+                        server_sock,
+                        &client_addr,
+                        &client_buf[..client_n],
+                        &mut error_limiter,
+                    );
                 }
             }
         }
@@ -133,17 +467,109 @@ impl TftpServer {
         Ok(())
     }
 
+    /// Block until all transfers in progress finish.
+    fn wait_for_transfers(&self) {
+        while !self.active_transfers.lock().unwrap().is_empty() {
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// Handle an unexpected error in the main loop according to the configured policy.
+    /// Returns the error if the server should stop.
+    fn handle_error(&self, err: anyhow::Error) -> anyhow::Result<()> {
+        match self.config.error_policy {
+            ErrorPolicy::Continue => {
+                error!("{:?}", err);
+                Ok(())
+            }
+            ErrorPolicy::Abort => Err(err),
+        }
+    }
+
+    /// Reply to a packet which is received by the server socket but is not a request.
+    fn reply_to_illegal_packet(
+        &self,
+        sock: &UdpSocket,
+        client_addr: &SocketAddr,
+        data: &[u8],
+        error_limiter: &mut Option<TokenBucket>,
+    ) {
+        match classify_packet(data) {
+            // never reply to ERROR (RFC 1350)
+            Some(PacketKind::Error) => (),
+            None if self.config.quiet_errors => {
+                debug!("[{}] stay silent to malformed packet", client_addr);
+            }
+            _ if !error_allowed(error_limiter, client_addr) => (),
+            _ => send_error_packet(
+                sock,
+                client_addr,
+                TftpError::IllegalTftpOp,
+                "Illegal TFTP operation".to_string(),
+                &self.config.error_messages,
+                &self.stats,
+            ),
+        }
+    }
+
+    /// Register a transfer as active unless the same transfer is already active
+    /// or too many transfers are in progress.
+    fn start_transfer(&self, key: &TransferKey) -> StartResult {
+        let mut active_transfers = self.active_transfers.lock().unwrap();
+        if active_transfers.contains(key) {
+            StartResult::AlreadyActive
+        } else if self
+            .config
+            .max_active_transfers
+            .is_some_and(|max| active_transfers.len() >= max.get())
+        {
+            StartResult::Busy
+        } else {
+            active_transfers.insert(key.clone());
+            StartResult::Started
+        }
+    }
+
+    fn reject_busy(
+        &self,
+        sock: &UdpSocket,
+        client_addr: &SocketAddr,
+        error_limiter: &mut Option<TokenBucket>,
+    ) {
+        info!(
+            "[{}] reject request: too many active transfers",
+            client_addr
+        );
+        if error_allowed(error_limiter, client_addr) {
+            send_error_packet(
+                sock,
+                client_addr,
+                TftpError::Others,
+                "Server busy".to_string(),
+                &self.config.error_messages,
+                &self.stats,
+            );
+        }
+    }
+
     fn spawn_rrq(
         &self,
         socket: UdpSocket,
         client_addr: SocketAddr,
         rrq: ReadPacket,
+        key: TransferKey,
     ) -> JoinHandle<()> {
         let handler = Arc::clone(&self.rrq_handler);
+        let active_transfers = Arc::clone(&self.active_transfers);
+        let stats = Arc::clone(&self.stats);
+        let transfer = stats.transfer_started(client_addr, Operation::Read, &rrq.filename);
         thread::spawn(move || {
-            (handler)(socket, client_addr, rrq).unwrap_or_else(|err| {
-                error!("Failed in handling RRQ from {}: {:?}", client_addr, err)
-            })
+            let result = (handler)(socket, client_addr, rrq);
+            if log_transfer_result("RRQ", &client_addr, &result).is_none() {
+                log_transfer_completed("RRQ", &client_addr, &transfer.info());
+            }
+            stats.transfer_finished(&transfer, result.is_ok());
+            active_transfers.lock().unwrap().remove(&key);
         })
     }
 
@@ -152,47 +578,436 @@ impl TftpServer {
         socket: UdpSocket,
         client_addr: SocketAddr,
         wrq: WritePacket,
+        key: TransferKey,
     ) -> JoinHandle<()> {
         let handler = Arc::clone(&self.wrq_handler);
+        let active_transfers = Arc::clone(&self.active_transfers);
+        let stats = Arc::clone(&self.stats);
+        let transfer = stats.transfer_started(client_addr, Operation::Write, &wrq.filename);
         thread::spawn(move || {
-            (handler)(socket, client_addr, wrq).unwrap_or_else(|err| {
-                error!("Failed in handling WRQ from {}: {:?}", client_addr, err)
-            })
+            let result = (handler)(socket, client_addr, wrq);
+            if log_transfer_result("WRQ", &client_addr, &result).is_none() {
+                log_transfer_completed("WRQ", &client_addr, &transfer.info());
+            }
+            stats.transfer_finished(&transfer, result.is_ok());
+            active_transfers.lock().unwrap().remove(&key);
+        })
+    }
+}
+
+enum PacketKind {
+    Error,
+    Others,
+}
+
+/// Apply `Config::recv_buffer_size` to the server socket.
+fn set_recv_buffer_size(sock: &UdpSocket, config: &Config) -> anyhow::Result<()> {
+    if let Some(size) = config.recv_buffer_size {
+        let actual = socket::set_recv_buffer_size(sock.as_raw_fd(), size)
+            .context("Failed to set receive buffer size")?;
+        info!("receive buffer size: {} bytes", actual);
+    }
+    Ok(())
+}
+
+/// Whether an ERROR packet can be sent from the server socket under `Config::max_errors_per_sec`.
+fn error_allowed(limiter: &mut Option<TokenBucket>, client_addr: &SocketAddr) -> bool {
+    let allowed = limiter.as_mut().is_none_or(TokenBucket::try_acquire);
+    if !allowed {
+        debug!("[{}] drop error reply by rate limit", client_addr);
+    }
+    allowed
+}
+
+/// Classify a packet which is not RRQ nor WRQ.
+/// Returns None if it is not well-formed TFTP (e.g. garbage sent by scanners).
+fn classify_packet(data: &[u8]) -> Option<PacketKind> {
+    if data.len() < 4 {
+        return None;
+    }
+    if packet::Error::parse(data).is_ok() {
+        Some(PacketKind::Error)
+    } else if packet::Data::parse(data).is_ok()
+        || packet::ACK::parse(data).is_ok()
+        || packet::OACK::parse(data).is_ok()
+    {
+        Some(PacketKind::Others)
+    } else {
+        None
+    }
+}
+
+/// Log the failure of a transfer and return the level used (None if succeeded).
+/// Timeout is logged at warn because it usually means the client has gone away
+/// rather than something wrong in the server.
+fn log_transfer_result(op: &str, client_addr: &SocketAddr, result: &Result<()>) -> Option<Level> {
+    let level = match result {
+        Ok(_) => return None,
+        Err(Error::Timeout { .. }) => Level::Warn,
+        Err(Error::Cancelled) => Level::Info,
+        Err(_) => Level::Error,
+    };
+    log!(
+        level,
+        "Failed in handling {} from {}: {}",
+        op,
+        client_addr,
+        result.as_ref().unwrap_err()
+    );
+    Some(level)
+}
+
+/// Log the size and throughput of a transfer completed successfully.
+fn log_transfer_completed(op: &str, client_addr: &SocketAddr, info: &TransferInfo) {
+    let elapsed = info.started_at.elapsed().unwrap_or_default();
+    info!(
+        "[{}] {} of {:?} completed: {} bytes in {:.3}s ({})",
+        client_addr,
+        op,
+        info.filename,
+        info.bytes,
+        elapsed.as_secs_f64(),
+        stats::format_throughput(info.bytes, elapsed)
+    );
+}
+
+/// Notify the client and abort the transfer if it was cancelled by `TftpServer::cancel`.
+fn check_cancelled(
+    transfer: &stats::Transfer,
+    sock: &UdpSocket,
+    client_addr: &SocketAddr,
+    messages: &ErrorMessages,
+    stats: &ServerStats,
+) -> Result<()> {
+    if !transfer.is_cancelled() {
+        return Ok(());
+    }
+    send_error_packet(
+        sock,
+        client_addr,
+        TftpError::Others,
+        "cancelled".to_string(),
+        messages,
+        stats,
+    );
+    Err(Error::Cancelled)
+}
+
+/// Use the negotiated timeout instead of the one set to the socket by the server.
+fn apply_timeout(sock: &UdpSocket, options: &TransferOptions) -> io::Result<()> {
+    if let Some(timeout) = options.timeout() {
+        sock.set_read_timeout(Some(timeout))?;
+        sock.set_write_timeout(Some(timeout))?;
+    }
+    Ok(())
+}
+
+/// Reject a request in netascii if only octet is allowed by `config.octet_only`.
+fn reject_mode(
+    mode: packet::Mode,
+    config: &Config,
+    sock: &UdpSocket,
+    client_addr: &SocketAddr,
+    stats: &ServerStats,
+) -> anyhow::Result<()> {
+    if !config.octet_only || mode == packet::Mode::OCTET {
+        return Ok(());
+    }
+    send_error_packet(
+        sock,
+        client_addr,
+        TftpError::IllegalTftpOp,
+        "Only octet mode is supported".to_string(),
+        &config.error_messages,
+        stats,
+    );
+    bail!("Rejected a request in {} mode from {}", mode, client_addr);
+}
+
+/// Reject a datagram larger than expected for its opcode, which is sent by a broken peer.
+/// `max_data_size` is the size of DATA packet allowed by the negotiated blksize.
+fn reject_oversized(
+    datagram: &[u8],
+    max_data_size: usize,
+    sock: &UdpSocket,
+    client_addr: &SocketAddr,
+    messages: &ErrorMessages,
+    stats: &ServerStats,
+) -> anyhow::Result<()> {
+    let max = match datagram
+        .get(..2)
+        .map(|op| u16::from_be_bytes([op[0], op[1]]))
+    {
+        Some(packet::Data::OPCODE) => max_data_size,
+        Some(packet::ACK::OPCODE) => packet::ACK::SIZE,
+        _ => MAX_CONTROL_PACKET_SIZE,
+    };
+    if datagram.len() <= max {
+        return Ok(());
+    }
+    send_error_packet(
+        sock,
+        client_addr,
+        TftpError::IllegalTftpOp,
+        "Packet too large".to_string(),
+        messages,
+        stats,
+    );
+    bail!(
+        "Received too large packet from {}: {} bytes (at most {} bytes)",
+        client_addr,
+        datagram.len(),
+        max
+    );
+}
+
+/// Reject a request received in a transfer unless it is a retransmission of the request served
+/// (which reaches the socket of the handler when the response to it is lost).
+/// Any other request means the peer is confused, because a new request must be sent to the server port.
+fn reject_request(
+    datagram: &[u8],
+    operation: Operation,
+    filename: &str,
+    sock: &UdpSocket,
+    client_addr: &SocketAddr,
+    messages: &ErrorMessages,
+    stats: &ServerStats,
+) -> anyhow::Result<()> {
+    let is_retransmission = match packet::InitialPacket::parse(datagram) {
+        Ok(packet::InitialPacket::RRQ(rrq)) => {
+            operation == Operation::Read && rrq.filename == filename
+        }
+        Ok(packet::InitialPacket::WRQ(wrq)) => {
+            operation == Operation::Write && wrq.filename == filename
+        }
+        Ok(packet::InitialPacket::OACK(_)) | Err(_) => return Ok(()),
+    };
+    if is_retransmission {
+        return Ok(());
+    }
+    send_error_packet(
+        sock,
+        client_addr,
+        TftpError::IllegalTftpOp,
+        "Unexpected request".to_string(),
+        messages,
+        stats,
+    );
+    bail!(
+        "Received an unexpected request from {} in transfer",
+        client_addr
+    );
+}
+
+/// Identify a transfer in progress.
+/// Options are not included so that a retransmitted request is regarded as the same transfer
+/// even if it has different options (the options in the first request are used).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct TransferKey {
+    client_addr: SocketAddr,
+    is_write: bool,
+    filename: String,
+}
+
+impl TransferKey {
+    fn new(client_addr: SocketAddr, is_write: bool, filename: &str) -> TransferKey {
+        TransferKey {
+            client_addr,
+            is_write,
+            filename: filename.to_string(),
+        }
+    }
+}
+
+enum StartResult {
+    Started,
+    /// The request is retransmitted for a transfer in progress.
+    AlreadyActive,
+    /// `max_active_transfers` is reached.
+    Busy,
+}
+
+/// Create a socket for a transfer, which shares the address and port with the server socket.
+///
+/// The socket is connected to the client. This is necessary (not just an optimization)
+/// because the kernel delivers a datagram to the connected socket in preference to
+/// the server socket only if the source matches the connected peer.
+/// As a tradeoff, packets from other addresses never reach the handler,
+/// so the handler cannot reply UnknownTid to them (they are dropped or go to the server socket).
+fn create_child_socket(
+    server_addr: SocketAddr,
+    client_addr: SocketAddr,
+    timeout: Duration,
+) -> anyhow::Result<UdpSocket> {
+    let child_sock = socket::create_udp_socket(server_addr, false)?;
+    child_sock.set_read_timeout(Some(timeout))?;
+    child_sock.set_write_timeout(Some(timeout))?;
+    child_sock.connect(client_addr)?;
+    Ok(child_sock)
+}
+
+/// The time to give up waiting for the response to the packets sent last.
+/// Unlike a plain read timeout, it is not extended by stray packets (e.g. duplicated ACKs),
+/// so a peer sending them periodically cannot delay the retransmission forever.
+struct RetryTimer {
+    /// The read timeout of the socket given to the handler.
+    timeout: Option<Duration>,
+    /// The read timeout of the socket (at least MIN_RETRY_INTERVAL), or None if it blocks forever.
+    interval: Option<Duration>,
+    deadline: Option<Instant>,
+}
+
+impl RetryTimer {
+    fn new(sock: &UdpSocket) -> io::Result<RetryTimer> {
+        let timeout = sock.read_timeout()?;
+        Ok(RetryTimer {
+            timeout,
+            interval: timeout.map(|timeout| timeout.max(MIN_RETRY_INTERVAL)),
+            deadline: None,
         })
     }
+
+    /// Start waiting for the response to the packets just sent.
+    fn reset(&mut self) {
+        self.deadline = self.interval.map(|interval| Instant::now() + interval);
+    }
+
+    /// Receive a datagram, or fail with WouldBlock when the deadline has passed.
+    fn recv_from(&self, sock: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::Error::from(ErrorKind::WouldBlock));
+            }
+            sock.set_read_timeout(Some(remaining))?;
+        }
+        sock.recv_from(buf)
+    }
+}
+
+/// Describe where the interval comes from, which is logged to diagnose slow transfers.
+impl fmt::Display for RetryTimer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match (self.timeout, self.interval) {
+            (Some(timeout), Some(interval)) if timeout < interval => write!(
+                f,
+                "{:?} (raised from the socket read timeout {:?})",
+                interval, timeout
+            ),
+            (_, Some(interval)) => write!(f, "{:?} (the socket read timeout)", interval),
+            (_, None) => write!(f, "none (the socket blocks forever)"),
+        }
+    }
+}
+
+/// A packet sent by handlers.
+/// OACK is sent first (as the response to a request) only if some options are accepted.
+#[allow(clippy::upper_case_acronyms)]
+enum ResponsePacket {
+    OACK(packet::OACK),
+    Data(packet::Data),
+    ACK(packet::ACK),
+}
+
+impl ResponsePacket {
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            ResponsePacket::OACK(pkt) => pkt.encode(),
+            ResponsePacket::Data(pkt) => pkt.encode(),
+            ResponsePacket::ACK(pkt) => pkt.encode(),
+        }
+    }
+}
+
+impl fmt::Display for ResponsePacket {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ResponsePacket::OACK(pkt) => pkt.fmt(f),
+            ResponsePacket::Data(pkt) => pkt.fmt(f),
+            ResponsePacket::ACK(pkt) => f.write_fmt(format_args!("{:?}", pkt)),
+        }
+    }
+}
+
+/// The result of ACK received in RRQ.
+#[derive(Debug, PartialEq, Eq)]
+enum AckResult {
+    /// OACK or some DATA blocks are acknowledged. `blocks` is the number of acknowledged DATA blocks.
+    Progress { blocks: usize },
+    /// The ACK is for a block already acknowledged (the last one or older).
+    /// It may be delayed or duplicated.
+    Previous,
+    /// The ACK is for a block never sent.
+    Illegal,
 }
 
 struct RrqHandlingState {
-    block: u16,
+    /// The last block acknowledged by the client.
+    acked_block: u16,
+    /// DATA sent but not acknowledged yet, which follows `acked_block`.
+    outstanding: VecDeque<Vec<u8>>,
+    /// The last block of the latest burst of DATA sent, which a client acknowledges unless
+    /// some blocks in the burst are lost.
+    sent_block: u16,
     trial_count: u16,
-    data: Vec<u8>,
+    illegal_ack_count: u16,
+    oack: Option<packet::OACK>,
 }
 
 impl RrqHandlingState {
-    const MAX_TRIAL_COUNT: u16 = 5;
+    const MAX_ILLEGAL_ACK_COUNT: u16 = 3;
 
     fn new() -> RrqHandlingState {
         RrqHandlingState {
-            block: 0,
+            acked_block: 0,
+            outstanding: VecDeque::new(),
+            sent_block: 0,
             trial_count: 0,
-            data: vec![],
+            illegal_ack_count: 0,
+            oack: None,
         }
     }
 
-    fn block(&self) -> u16 {
-        self.block
+    /// Start with OACK, which should be acknowledged by ACK of block 0 before sending data.
+    /// The first DATA is `start_block`.
+    fn new_with_oack(oack: packet::OACK, start_block: u16) -> RrqHandlingState {
+        RrqHandlingState {
+            acked_block: start_block.wrapping_sub(1),
+            sent_block: start_block.wrapping_sub(1),
+            oack: Some(oack),
+            ..RrqHandlingState::new()
+        }
+    }
+
+    /// The number of DATA blocks not acknowledged yet.
+    fn outstanding_count(&self) -> usize {
+        self.outstanding.len()
+    }
+
+    /// The latest burst of DATA is acknowledged as a whole, i.e. no block in it seems to be lost.
+    fn is_burst_acknowledged(&self) -> bool {
+        self.acked_block == self.sent_block
     }
 
-    fn data(&self) -> &[u8] {
-        self.data.as_slice()
+    /// All sent packets are acknowledged.
+    fn is_acknowledged(&self) -> bool {
+        self.oack.is_none() && self.outstanding.is_empty()
+    }
+
+    /// Only the last DATA (shorter than `block_size`) is waiting for its ACK.
+    fn is_waiting_for_last_ack(&self, block_size: u16) -> bool {
+        self.oack.is_none()
+            && self.outstanding.len() == 1
+            && self.outstanding[0].len() < block_size as usize
     }
 
     fn trial_count(&self) -> u16 {
         self.trial_count
     }
 
-    fn increment_trial_count(&mut self) -> Option<u16> {
-        if self.trial_count() >= Self::MAX_TRIAL_COUNT {
+    /// The first transmission counts as a trial, so `max_retries + 1` trials are allowed.
+    fn increment_trial_count(&mut self, max_retries: u16) -> Option<u16> {
+        if self.trial_count() > max_retries {
             None
         } else {
             self.trial_count += 1;
@@ -200,122 +1015,391 @@ impl RrqHandlingState {
         }
     }
 
-    fn prepare_packet(&mut self) -> Option<packet::Data> {
-        self.increment_trial_count()
-            .map(|_| packet::Data::new(self.block(), self.data()))
+    /// Count ACKs for blocks never sent through the transfer.
+    fn increment_illegal_ack_count(&mut self) -> Option<u16> {
+        if self.illegal_ack_count >= Self::MAX_ILLEGAL_ACK_COUNT {
+            None
+        } else {
+            self.illegal_ack_count += 1;
+            Some(self.illegal_ack_count)
+        }
+    }
+
+    /// Packets to be sent for the current window, or None if exceeding the maximum retry count.
+    /// At most `window_size` DATA packets are sent even if more blocks are outstanding.
+    fn prepare_packets(
+        &mut self,
+        window_size: u16,
+        max_retries: u16,
+    ) -> Option<Vec<ResponsePacket>> {
+        self.increment_trial_count(max_retries)?;
+        if let Some(oack) = &self.oack {
+            return Some(vec![ResponsePacket::OACK(oack.clone())]);
+        }
+        let count = self.outstanding.len().min(window_size as usize) as u16;
+        self.sent_block = self.acked_block.wrapping_add(count);
+        Some(
+            self.outstanding
+                .iter()
+                .take(count as usize)
+                .zip(1..)
+                .map(|(data, i)| {
+                    ResponsePacket::Data(packet::Data::new(self.acked_block.wrapping_add(i), data))
+                })
+                .collect(),
+        )
+    }
+
+    /// Add DATA to be sent next.
+    fn push(&mut self, data: Vec<u8>) {
+        self.outstanding.push_back(data);
     }
 
-    fn next(&mut self, data: Vec<u8>) {
-        self.block += 1;
-        self.trial_count = 0;
-        self.data = data;
+    fn acknowledge(&mut self, block: u16) -> AckResult {
+        if self.oack.is_some() {
+            return if block == 0 {
+                self.oack = None;
+                self.trial_count = 0;
+                AckResult::Progress { blocks: 0 }
+            } else {
+                AckResult::Illegal
+            };
+        }
+        let blocks = block.wrapping_sub(self.acked_block);
+        // a block in the preceding half of the number space is regarded as behind `acked_block`,
+        // e.g. a stale ACK reordered with the later ones in a window.
+        // windowsize is capped at Config::MAX_WINDOW_SIZE, so an ACK in the window never falls there
+        if blocks == 0 || blocks > Config::MAX_WINDOW_SIZE {
+            AckResult::Previous
+        } else if (blocks as usize) <= self.outstanding.len() {
+            // a late ACK for a block sent before the latest burst may go beyond it
+            if blocks >= self.sent_block.wrapping_sub(self.acked_block) {
+                self.sent_block = block;
+            }
+            let blocks = blocks as usize;
+            self.acked_block = block;
+            self.outstanding.drain(..blocks);
+            self.trial_count = 0;
+            AckResult::Progress { blocks }
+        } else {
+            AckResult::Illegal
+        }
     }
 }
 
 pub fn create_rrq_handler(
     base_dir: PathBuf,
+    config: Config,
+) -> impl Fn(UdpSocket, SocketAddr, ReadPacket) -> Result<()> {
+    create_rrq_handler_with_stats(base_dir, config, Arc::default())
+}
+
+fn create_rrq_handler_with_stats(
+    base_dir: PathBuf,
+    config: Config,
+    stats: Arc<ServerStats>,
 ) -> impl Fn(UdpSocket, SocketAddr, ReadPacket) -> Result<()> {
     move |sock, client_addr, rrq| {
-        debug!("[{}] received RRQ: {:?}", client_addr, rrq);
+        Ok(handle_rrq(
+            &base_dir,
+            &config,
+            &stats,
+            sock,
+            client_addr,
+            rrq,
+        )?)
+    }
+}
 
-        let src_path = base_dir.join(&rrq.filename);
-        let mut file = file::File::open(&src_path, rrq.mode)
-            .notify_error(&sock, &client_addr)
-            .with_context(|| format!("Failed to open {:?}", src_path))?;
-        let mut file_buf = [0_u8; 512];
-        let mut file_n = file.read(&mut file_buf)?;
+/// anyhow is used in handlers to add context to errors, which is converted to Error on return.
+fn handle_rrq(
+    base_dir: &Path,
+    config: &Config,
+    stats: &ServerStats,
+    sock: UdpSocket,
+    client_addr: SocketAddr,
+    rrq: ReadPacket,
+) -> anyhow::Result<()> {
+    debug!("[{}] received RRQ: {:?}", client_addr, rrq);
+    let transfer = stats.transfer(client_addr, Operation::Read, &rrq.filename);
+    reject_mode(rrq.mode, config, &sock, &client_addr, stats)?;
 
-        let mut buf = [0; 1024];
-        let mut state = RrqHandlingState::new();
-        state.next(file_buf[..file_n].to_owned());
+    let src_path = config
+        .filename_map
+        .resolve(base_dir, &rrq.raw_filename, &rrq.filename)
+        .notify_error(&sock, &client_addr, &config.error_messages, stats)
+        .with_context(|| format!("Failed to resolve {:?}", rrq.raw_filename))?;
+    if src_path != base_dir.join(&rrq.filename) {
+        debug!(
+            "[{}] {:?} is mapped to {:?}",
+            client_addr, rrq.raw_filename, src_path
+        );
+    }
+    let opened = file::File::open_to_serve(
+        &src_path,
+        rrq.mode,
+        config.follow_symlinks,
+        config.serve_gzip,
+        config.direct_io,
+    );
+    if let Err(err) = &opened {
+        if err.kind() == ErrorKind::NotFound && config.is_silent_miss(&rrq.raw_filename) {
+            // the client will try the next candidate after timeout
+            debug!(
+                "[{}] {:?} is not found, drop the request silently",
+                client_addr, rrq.raw_filename
+            );
+            return Ok(());
+        }
+    }
+    let (mut file, file_size) = opened
+        .notify_error(&sock, &client_addr, &config.error_messages, stats)
+        .with_context(|| format!("Failed to open {:?}", src_path))?;
+    let options = TransferOptions::negotiate_rrq(&rrq.options, &rrq.mode, file_size, config);
+    stats.set_options(&transfer, &options);
+    apply_timeout(&sock, &options)?;
+    let mut file_buf = vec![0_u8; options.block_size() as usize];
+    // skip blocks before the requested one.
+    // this fails if the file is shorter than it looked in negotiation (e.g. truncated meanwhile)
+    for _ in 1..options.start_block() {
+        file.read_exact(&mut file_buf)
+            .notify_error(&sock, &client_addr, &config.error_messages, stats)
+            .with_context(|| format!("Failed to skip to block {}", options.start_block()))?;
+    }
+    let mut window = Window::new(options.window_size(), config.adaptive_window);
+    // calculated over data on the wire, which is what the client receives
+    let mut checksum = config.rrq_checksum.then(Crc32::new);
+    // read blocks up to the window size
+    let mut fill_window = |state: &mut RrqHandlingState, window: &Window| -> anyhow::Result<()> {
+        while state.outstanding_count() < window.size() as usize && file.has_next() {
+            let file_n = file.read(&mut file_buf)?;
+            if let Some(checksum) = checksum.as_mut() {
+                checksum.update(&file_buf[..file_n]);
+            }
+            stats.add_bytes(&transfer, file_n);
+            state.push(file_buf[..file_n].to_owned());
+        }
+        Ok(())
+    };
+
+    // one more byte to detect a datagram truncated by the buffer
+    let mut buf = vec![0; MAX_CONTROL_PACKET_SIZE + 1];
+    let mut state = match options.oack() {
+        Some(oack) => {
+            debug!("[{}] accepted options: {:?}", client_addr, options);
+            RrqHandlingState::new_with_oack(oack, options.start_block())
+        }
+        None => {
+            let mut state = RrqHandlingState::new();
+            fill_window(&mut state, &window)?;
+            state
+        }
+    };
 
-        let data = state.prepare_packet().unwrap();
-        sock.send_to(&data.encode(), client_addr)?;
-        debug!("[{}] sent data: {}", client_addr, data);
+    let mut timer = RetryTimer::new(&sock)?;
+    debug!("[{}] retry interval: {}", client_addr, timer);
+    for pkt in state
+        .prepare_packets(window.size(), config.max_retries.get())
+        .unwrap()
+    {
+        sock.send_to(&pkt.encode(), client_addr)?;
+        debug!("[{}] sent packet: {}", client_addr, pkt);
+    }
+    timer.reset();
 
-        loop {
-            let (ack_n, ack_addr) = match sock.recv_from(&mut buf) {
-                Ok(res) => res,
-                Err(err) if err.kind() == ErrorKind::WouldBlock => {
-                    // timeout
-                    match state.prepare_packet() {
-                        Some(pkt) => {
-                            // retransmit
+    loop {
+        let received = timer.recv_from(&sock, &mut buf);
+        check_cancelled(
+            &transfer,
+            &sock,
+            &client_addr,
+            &config.error_messages,
+            stats,
+        )?;
+        let (ack_n, ack_addr) = match received {
+            Ok(res) => res,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                // timeout
+                window.on_loss();
+                match state.prepare_packets(window.size(), config.max_retries.get()) {
+                    Some(pkts) => {
+                        // retransmit
+                        for pkt in pkts {
                             sock.send_to(&pkt.encode(), client_addr)?;
                             debug!(
-                                "[{}] sent data again (trial_count={}): {}",
+                                "[{}] sent packet again (trial_count={}): {}",
                                 client_addr,
                                 state.trial_count(),
                                 pkt
                             );
-                            continue;
                         }
-                        None => {
-                            // exceed maximum retry count
-                            bail!("Failed to receive ack from {}: timeout", client_addr);
+                        timer.reset();
+                        continue;
+                    }
+                    None if config.final_ack_policy == FinalAckPolicy::Optional
+                        && state.is_waiting_for_last_ack(options.block_size()) =>
+                    {
+                        warn!(
+                            "[{}] ack for the last block is not received, assume it is lost",
+                            client_addr
+                        );
+                        break;
+                    }
+                    None => {
+                        // exceed maximum retry count
+                        return Err(Error::Timeout {
+                            retries: state.trial_count() - 1,
                         }
+                        .into());
                     }
                 }
-                Err(err) => {
-                    bail!("Failed to receive ack from {}: {:?}", client_addr, err);
-                }
-            };
+            }
+            Err(err) => {
+                bail!("Failed to receive ack from {}: {:?}", client_addr, err);
+            }
+        };
+
+        // the socket given by the server is connected, so this happens only with other sockets
+        if ack_addr != client_addr {
+            warn!(
+                "[{}] received packet from unknown client: {}. ignore it.",
+                client_addr, ack_addr
+            );
+            continue;
+        }
 
-            if ack_addr != client_addr {
+        reject_oversized(
+            &buf[..ack_n],
+            MAX_CONTROL_PACKET_SIZE,
+            &sock,
+            &client_addr,
+            &config.error_messages,
+            stats,
+        )?;
+        reject_request(
+            &buf[..ack_n],
+            Operation::Read,
+            &rrq.filename,
+            &sock,
+            &client_addr,
+            &config.error_messages,
+            stats,
+        )?;
+
+        if let Ok(err) = packet::Error::parse(&buf[..ack_n]) {
+            // never reply to ERROR (RFC 1350)
+            bail!(
+                "Download is aborted by {}: code={}, message={:?}",
+                client_addr,
+                err.error_code(),
+                err.message()
+            );
+        }
+
+        let pkt = match packet::ACK::parse(&buf[..ack_n]) {
+            Ok(pkt) => pkt,
+            Err(err) => {
                 warn!(
-                    "[{}] received packet from unknown client: {}. ignore it.",
-                    client_addr, ack_addr
+                    "[{}] received unknown packet. ignore it: {:?}",
+                    client_addr, err
                 );
                 continue;
             }
-
-            match packet::ACK::parse(&buf[..ack_n]) {
-                Ok(pkt) if pkt.block() == state.block() => {
-                    debug!("[{}] received ack: {:?}", client_addr, pkt);
-                    if file.has_next() {
-                        file_n = file.read(&mut file_buf)?;
-                        state.next(file_buf[..file_n].to_owned());
-                        match state.prepare_packet() {
-                            Some(data) => {
-                                sock.send_to(&data.encode(), client_addr)?;
-                                debug!("[{}] sent data: {}", client_addr, data);
-                            }
-                            None => {
-                                // shouldn't come here
-                                continue;
-                            }
-                        }
+        };
+        match state.acknowledge(pkt.block()) {
+            AckResult::Progress { blocks } => {
+                debug!("[{}] received ack: {:?}", client_addr, pkt);
+                if blocks > 0 {
+                    if state.is_burst_acknowledged() {
+                        window.on_ack();
                     } else {
-                        break;
+                        // the client lost blocks following the acknowledged one.
+                        // blocks left over from a larger window before are not counted as lost
+                        window.on_loss();
                     }
                 }
-                Ok(_pkt) => {
-                    warn!("[{}] received ack with wrong block.", client_addr);
+                fill_window(&mut state, &window)?;
+                if state.is_acknowledged() {
+                    break;
                 }
-                Err(err) => {
-                    warn!(
-                        "[{}] received unknown packet. ignore it: {:?}",
-                        client_addr, err
+                if let Some(pkts) = state.prepare_packets(window.size(), config.max_retries.get()) {
+                    for pkt in pkts {
+                        sock.send_to(&pkt.encode(), client_addr)?;
+                        debug!("[{}] sent packet: {}", client_addr, pkt);
+                    }
+                    timer.reset();
+                }
+            }
+            AckResult::Previous => {
+                // it may be delayed or duplicated
+                debug!(
+                    "[{}] received ack for an acknowledged block: {}",
+                    client_addr,
+                    pkt.block()
+                );
+            }
+            AckResult::Illegal => {
+                warn!(
+                    "[{}] received ack with wrong block: {}",
+                    client_addr,
+                    pkt.block()
+                );
+                if state.increment_illegal_ack_count().is_none() {
+                    // the client seems to be broken, so it is useless to wait for it
+                    send_error_packet(
+                        &sock,
+                        &client_addr,
+                        TftpError::IllegalTftpOp,
+                        "Unexpected block number".to_string(),
+                        &config.error_messages,
+                        stats,
+                    );
+                    bail!(
+                        "Failed to receive ack from {}: too many acks with wrong block",
+                        client_addr
                     );
                 }
             }
         }
+    }
 
-        debug!("[{}] finish RRQ for {:?}", client_addr, rrq.filename);
-        Ok(())
+    debug!("[{}] finish RRQ for {:?}", client_addr, rrq.filename);
+    if let Some(checksum) = checksum {
+        info!(
+            "[{}] sent {:?} with crc32={:08x}",
+            client_addr,
+            rrq.filename,
+            checksum.value()
+        );
     }
+    Ok(())
 }
 
 enum WrqHandlingState {
-    RequestAccepted { trial_count: u16 },
-    DataAccepted { block: u16, trial_count: u16 },
+    RequestAccepted {
+        trial_count: u16,
+        oack: Option<packet::OACK>,
+    },
+    DataAccepted {
+        block: u16,
+        trial_count: u16,
+    },
 }
 
 impl WrqHandlingState {
-    const MAX_TRIAL_COUNT: u16 = 5;
-
     fn new() -> WrqHandlingState {
-        WrqHandlingState::RequestAccepted { trial_count: 0 }
+        WrqHandlingState::RequestAccepted {
+            trial_count: 0,
+            oack: None,
+        }
+    }
+
+    /// Respond to the request with OACK instead of ACK of block 0.
+    fn new_with_oack(oack: packet::OACK) -> WrqHandlingState {
+        WrqHandlingState::RequestAccepted {
+            trial_count: 0,
+            oack: Some(oack),
+        }
     }
 
     fn block(&self) -> u16 {
@@ -327,17 +1411,18 @@ impl WrqHandlingState {
 
     fn trial_count(&self) -> u16 {
         *(match self {
-            WrqHandlingState::RequestAccepted { trial_count } => trial_count,
+            WrqHandlingState::RequestAccepted { trial_count, .. } => trial_count,
             WrqHandlingState::DataAccepted { trial_count, .. } => trial_count,
         })
     }
 
-    fn increment_trial_count(&mut self) -> Option<u16> {
+    /// The first transmission counts as a trial, so `max_retries + 1` trials are allowed.
+    fn increment_trial_count(&mut self, max_retries: u16) -> Option<u16> {
         let cur = match self {
-            WrqHandlingState::RequestAccepted { trial_count } => trial_count,
+            WrqHandlingState::RequestAccepted { trial_count, .. } => trial_count,
             WrqHandlingState::DataAccepted { trial_count, .. } => trial_count,
         };
-        if *cur >= Self::MAX_TRIAL_COUNT {
+        if *cur > max_retries {
             None
         } else {
             *cur += 1;
@@ -345,9 +1430,19 @@ impl WrqHandlingState {
         }
     }
 
-    fn prepare_packet(&mut self) -> Option<packet::ACK> {
-        self.increment_trial_count()
-            .map(|_| packet::ACK::new(self.block()))
+    fn prepare_packet(&mut self, max_retries: u16) -> Option<ResponsePacket> {
+        self.increment_trial_count(max_retries)
+            .map(|_| self.last_packet())
+    }
+
+    /// The response to the last accepted packet, which doesn't count as a retry.
+    fn last_packet(&self) -> ResponsePacket {
+        match self {
+            WrqHandlingState::RequestAccepted {
+                oack: Some(oack), ..
+            } => ResponsePacket::OACK(oack.clone()),
+            _ => ResponsePacket::ACK(packet::ACK::new(self.block())),
+        }
     }
 
     fn next(self) -> Self {
@@ -356,55 +1451,252 @@ impl WrqHandlingState {
                 block: 1,
                 trial_count: 0,
             },
+            // block 65535 is followed by 0 as most implementations do
             WrqHandlingState::DataAccepted { block, .. } => WrqHandlingState::DataAccepted {
-                block: block + 1,
+                block: block.wrapping_add(1),
                 trial_count: 0,
             },
         }
     }
 }
 
+/// Mark a file as being uploaded while this is alive.
+struct UploadGuard {
+    uploading: Arc<Mutex<HashSet<String>>>,
+    filename: String,
+}
+
+impl UploadGuard {
+    /// Returns None if the file is already being uploaded.
+    fn acquire(uploading: &Arc<Mutex<HashSet<String>>>, filename: &str) -> Option<UploadGuard> {
+        if !uploading.lock().unwrap().insert(filename.to_string()) {
+            return None;
+        }
+        Some(UploadGuard {
+            uploading: Arc::clone(uploading),
+            filename: filename.to_string(),
+        })
+    }
+}
+
+impl Drop for UploadGuard {
+    fn drop(&mut self) {
+        self.uploading.lock().unwrap().remove(&self.filename);
+    }
+}
+
 pub fn create_wrq_handler(
     base_dir: impl AsRef<Path>,
     temp_dir: impl AsRef<Path>,
+    config: Config,
+) -> impl Fn(UdpSocket, SocketAddr, WritePacket) -> Result<()> {
+    create_wrq_handler_with_stats(base_dir, temp_dir, config, Arc::default())
+}
+
+fn create_wrq_handler_with_stats(
+    base_dir: impl AsRef<Path>,
+    temp_dir: impl AsRef<Path>,
+    config: Config,
+    stats: Arc<ServerStats>,
+) -> impl Fn(UdpSocket, SocketAddr, WritePacket) -> Result<()> {
+    let follow_symlinks = config.follow_symlinks;
+    let no_clobber = config.no_clobber;
+    let stage_in_place = config.stage_in_place;
+    let netascii_store = config.netascii_store;
+    let filename_map = config.filename_map.clone();
+    let create_dirs = config.create_dirs;
+    let mirror_dirs = config.mirror_dirs.clone();
+    create_wrq_handler_with_sink_and_stats(config, stats, move |wrq| {
+        if let Some(mode) = create_dirs {
+            let target = filename_map.target(&wrq.raw_filename, &wrq.filename);
+            let base_dir = base_dir.as_ref();
+            file::create_parent_dirs(base_dir, &base_dir.join(target), mode)
+                .context("Failed to create destination directories")?;
+        }
+        let dest_path =
+            filename_map.resolve(base_dir.as_ref(), &wrq.raw_filename, &wrq.filename)?;
+        file::check_writable_destination(&dest_path, follow_symlinks)
+            .with_context(|| format!("Cannot write to {:?}", dest_path))?;
+        // checked before the transfer starts, so the last of concurrent uploads may still win
+        if no_clobber && dest_path.symlink_metadata().is_ok() {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{:?} already exists", dest_path),
+            )
+            .into());
+        }
+        let sink = if stage_in_place {
+            TempFileSink::create_in_place(dest_path, wrq.mode)?
+        } else {
+            ensure_temp_dir(temp_dir.as_ref())?;
+            TempFileSink::create(&temp_dir, dest_path, wrq.mode)?
+        };
+        Ok(sink
+            .with_netascii_store(netascii_store)
+            .with_mirror_dirs(mirror_dirs.clone()))
+    })
+}
+
+/// Whether two paths point to the same directory, e.g. via a symlink.
+fn is_same_dir(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Recreate the temporary directory if it was removed while the server runs (e.g. by a tmp cleaner),
+/// otherwise every upload fails.
+fn ensure_temp_dir(temp_dir: &Path) -> anyhow::Result<()> {
+    if temp_dir.is_dir() {
+        return Ok(());
+    }
+    warn!(
+        "temporary directory {:?} was removed, recreate it",
+        temp_dir
+    );
+    std::fs::create_dir_all(temp_dir)
+        .with_context(|| format!("Failed to recreate temporary directory {:?}", temp_dir))
+}
+
+/// Create a WRQ handler which writes uploaded data into a WrqSink created by `create_sink`.
+/// Note that the data is passed to the sink as is on the wire, so it is netascii-encoded
+/// if requested in the mode (TempFileSink decodes it when writing to file).
+pub fn create_wrq_handler_with_sink<S: WrqSink>(
+    config: Config,
+    create_sink: impl Fn(&WritePacket) -> Result<S>,
 ) -> impl Fn(UdpSocket, SocketAddr, WritePacket) -> Result<()> {
+    create_wrq_handler_with_sink_and_stats(config, Arc::default(), create_sink)
+}
+
+fn create_wrq_handler_with_sink_and_stats<S: WrqSink>(
+    config: Config,
+    stats: Arc<ServerStats>,
+    create_sink: impl Fn(&WritePacket) -> Result<S>,
+) -> impl Fn(UdpSocket, SocketAddr, WritePacket) -> Result<()> {
+    let uploading = Arc::new(Mutex::new(HashSet::new()));
     move |sock, client_addr, wrq| {
-        debug!("[{}] received WRQ: {:?}", client_addr, wrq);
-        let mut buf = [0; 1024];
-        let mut state = WrqHandlingState::new();
+        Ok(handle_wrq(
+            &config,
+            &stats,
+            &uploading,
+            &create_sink,
+            sock,
+            client_addr,
+            wrq,
+        )?)
+    }
+}
 
-        let ack = state.prepare_packet().unwrap();
-        sock.send_to(&ack.encode(), client_addr)?;
-        debug!("[{}] sent ack: {:?}", client_addr, ack);
+fn handle_wrq<S: WrqSink>(
+    config: &Config,
+    stats: &ServerStats,
+    uploading: &Arc<Mutex<HashSet<String>>>,
+    create_sink: &impl Fn(&WritePacket) -> Result<S>,
+    sock: UdpSocket,
+    client_addr: SocketAddr,
+    wrq: WritePacket,
+) -> anyhow::Result<()> {
+    debug!("[{}] received WRQ: {:?}", client_addr, wrq);
+    let transfer = stats.transfer(client_addr, Operation::Write, &wrq.filename);
+    reject_mode(wrq.mode, config, &sock, &client_addr, stats)?;
 
-        let temp_file_path = temp_dir.as_ref().join(format!(
-            "{}.{}",
-            &wrq.filename,
-            temp::generate_random_name()?
-        ));
-        let mut temp_file = file::File::create(&temp_file_path, wrq.mode)?;
-        debug!("[{}] created {:?}", client_addr, temp_file_path);
+    // reject concurrent uploads to the same file, otherwise the last writer wins unpredictably.
+    // compared by the target so that a mapped name and the file itself are the same
+    let target = config.filename_map.target(&wrq.raw_filename, &wrq.filename);
+    let _guard = match UploadGuard::acquire(uploading, &target.to_string_lossy()) {
+        Some(guard) => guard,
+        None => {
+            send_error_packet(
+                &sock,
+                &client_addr,
+                TftpError::Others,
+                "File is being uploaded by another client".to_string(),
+                &config.error_messages,
+                stats,
+            );
+            bail!("{:?} is being uploaded by another client", wrq.filename);
+        }
+    };
+
+    let options = TransferOptions::negotiate_wrq(&wrq.options, config);
+    stats.set_options(&transfer, &options);
+    apply_timeout(&sock, &options)?;
+    let block_size = options.block_size() as usize;
+    let max_data_size = block_size + Config::DATA_HEADER_SIZE;
+    // one more byte to detect a datagram truncated by the buffer
+    let mut buf = vec![0; max_data_size.max(MAX_CONTROL_PACKET_SIZE) + 1];
+
+    let mut sink =
+        create_sink(&wrq).notify_error(&sock, &client_addr, &config.error_messages, stats)?;
+
+    if let Some(tsize) = options.tsize {
+        // fail fast before the transfer starts if the space cannot be reserved
+        if let Err(err) =
+            sink.reserve(tsize)
+                .notify_error(&sock, &client_addr, &config.error_messages, stats)
+        {
+            sink.abort().context("Failed to abort WRQ")?;
+            return Err(err).with_context(|| format!("Failed to allocate {} bytes for WRQ", tsize));
+        }
+    }
+
+    let mut state = match options.oack() {
+        Some(oack) => {
+            debug!("[{}] accepted options: {:?}", client_addr, options);
+            WrqHandlingState::new_with_oack(oack)
+        }
+        None => WrqHandlingState::new(),
+    };
+
+    let mut timer = RetryTimer::new(&sock)?;
+    debug!("[{}] retry interval: {}", client_addr, timer);
+    let pkt = state.prepare_packet(config.max_retries.get()).unwrap();
+    sock.send_to(&pkt.encode(), client_addr)?;
+    debug!("[{}] sent packet: {}", client_addr, pkt);
+    timer.reset();
 
+    // verified only for octet because netascii changes the size on the wire
+    let expected_size = options
+        .tsize
+        .filter(|_| config.verify_tsize && wrq.mode == packet::Mode::OCTET);
+
+    // the upload is discarded if the transfer fails on the way
+    let receive_data = || -> anyhow::Result<()> {
+        let mut blocks_written: u64 = 0;
+        let mut bytes_written: u64 = 0;
         loop {
-            let (data_n, data_addr) = match sock.recv_from(&mut buf) {
+            let received = timer.recv_from(&sock, &mut buf);
+            check_cancelled(
+                &transfer,
+                &sock,
+                &client_addr,
+                &config.error_messages,
+                stats,
+            )?;
+            let (data_n, data_addr) = match received {
                 Ok(res) => res,
                 Err(err) if err.kind() == ErrorKind::WouldBlock => {
                     // timeout
-                    match state.prepare_packet() {
+                    match state.prepare_packet(config.max_retries.get()) {
                         Some(pkt) => {
                             // retransmit
                             sock.send_to(&pkt.encode(), client_addr)?;
                             debug!(
-                                "[{}] sent ack again (trial_count={}): {:?}",
+                                "[{}] sent packet again (trial_count={}): {}",
                                 client_addr,
                                 state.trial_count(),
                                 pkt
                             );
+                            timer.reset();
                             continue;
                         }
                         None => {
                             // exceed maximum retry count
-                            bail!("Failed to receive data from {}: timeout", client_addr);
+                            return Err(Error::Timeout {
+                                retries: state.trial_count() - 1,
+                            }
+                            .into());
                         }
                     }
                 }
@@ -421,17 +1713,122 @@ pub fn create_wrq_handler(
                 continue;
             }
 
-            match packet::Data::parse(&buf[..data_n]) {
-                Ok(pkt) => {
-                    debug!("[{}] received data: size={}", client_addr, pkt.data().len());
-                    temp_file.write_all(pkt.data())?;
+            reject_oversized(
+                &buf[..data_n],
+                max_data_size,
+                &sock,
+                &client_addr,
+                &config.error_messages,
+                stats,
+            )?;
+            reject_request(
+                &buf[..data_n],
+                Operation::Write,
+                &wrq.filename,
+                &sock,
+                &client_addr,
+                &config.error_messages,
+                stats,
+            )?;
+
+            if let (WrqHandlingState::RequestAccepted { .. }, Ok(packet::InitialPacket::WRQ(_))) =
+                (&state, packet::InitialPacket::parse(&buf[..data_n]))
+            {
+                // the response to the request seems to be lost.
+                // the options in the retransmitted request are ignored to keep the state coherent.
+                if let Some(pkt) = state.prepare_packet(config.max_retries.get()) {
+                    sock.send_to(&pkt.encode(), client_addr)?;
+                    debug!(
+                        "[{}] sent packet again for retransmitted WRQ: {}",
+                        client_addr, pkt
+                    );
+                }
+                continue;
+            }
+
+            if let Ok(err) = packet::Error::parse(&buf[..data_n]) {
+                // never reply to ERROR (RFC 1350)
+                bail!(
+                    "Upload is aborted by {}: code={}, message={:?}",
+                    client_addr,
+                    err.error_code(),
+                    err.message()
+                );
+            }
+
+            // write the payload directly from the receive buffer
+            match packet::Data::parse_borrowed(&buf[..data_n]) {
+                Ok((block, _)) if block != state.block().wrapping_add(1) => {
+                    // e.g. the client retransmitted DATA because our ACK was lost.
+                    // reply the last ACK again, but don't write the data nor advance the state.
+                    let ack = state.last_packet();
+                    sock.send_to(&ack.encode(), client_addr)?;
+                    debug!(
+                        "[{}] received data of unexpected block {}. sent packet again: {}",
+                        client_addr, block, ack
+                    );
+                }
+                Ok((_, data)) => {
+                    debug!("[{}] received data: size={}", client_addr, data.len());
+                    match sink.write_block(data) {
+                        Ok(()) => (),
+                        // e.g. '\r' followed by other than '\n' or '\0' in netascii
+                        Err(err) if err.kind() == ErrorKind::InvalidData => {
+                            send_error_packet(
+                                &sock,
+                                &client_addr,
+                                TftpError::IllegalTftpOp,
+                                "Malformed data".to_string(),
+                                &config.error_messages,
+                                stats,
+                            );
+                            return Err(err).context("Received malformed data");
+                        }
+                        Err(err) => {
+                            Err::<(), _>(err).notify_error(
+                                &sock,
+                                &client_addr,
+                                &config.error_messages,
+                                stats,
+                            )?;
+                        }
+                    }
+                    stats.add_bytes(&transfer, data.len());
+                    blocks_written += 1;
+                    bytes_written += data.len() as u64;
+                    if let Some(tsize) = expected_size {
+                        // reply ERROR instead of the last ACK so that the client knows the failure
+                        let is_last = data.len() < block_size;
+                        if bytes_written > tsize || (is_last && bytes_written != tsize) {
+                            send_error_packet(
+                                &sock,
+                                &client_addr,
+                                TftpError::Others,
+                                "Size mismatch with tsize".to_string(),
+                                &config.error_messages,
+                                stats,
+                            );
+                            bail!(
+                                "Received {} bytes{}, but tsize is {}",
+                                bytes_written,
+                                if is_last { "" } else { " or more" },
+                                tsize
+                            );
+                        }
+                    }
+                    if let Some(interval) = config.sync_interval {
+                        if blocks_written.is_multiple_of(interval.get() as u64) {
+                            sink.sync()?;
+                        }
+                    }
 
                     state = state.next();
-                    let ack = state.prepare_packet().unwrap();
+                    let ack = state.prepare_packet(config.max_retries.get()).unwrap();
                     sock.send_to(&ack.encode(), client_addr)?;
-                    debug!("[{}] sent ack: {:?}", client_addr, ack);
+                    debug!("[{}] sent ack: {}", client_addr, ack);
+                    timer.reset();
 
-                    if pkt.data().len() < 512 {
+                    if data.len() < block_size {
                         break;
                     }
                 }
@@ -444,27 +1841,36 @@ pub fn create_wrq_handler(
             }
         }
 
-        let dest_path = base_dir.as_ref().join(&wrq.filename);
-        // avoid using fs::rename (it cannot move if src and dest mount point are different)
-        fs::copy(&temp_file_path, &dest_path)
-            .notify_error(&sock, &client_addr)
-            .with_context(|| format!("Failed to copy {:?} to {:?}", temp_file_path, dest_path))?;
-        fs::remove_file(&temp_file_path)
-            .with_context(|| format!("Failed to delete {:?}", temp_file_path))?;
-        debug!("[{}] finish WRQ for {:?}", client_addr, wrq.filename);
         Ok(())
+    };
+    if let Err(err) = receive_data() {
+        if let Err(abort_err) = sink.abort() {
+            warn!("[{}] failed to abort WRQ: {:?}", client_addr, abort_err);
+        }
+        return Err(err);
     }
+
+    sink.finish()
+        .notify_error(&sock, &client_addr, &config.error_messages, stats)
+        .with_context(|| format!("Failed to save {:?}", wrq.filename))?;
+    debug!("[{}] finish WRQ for {:?}", client_addr, wrq.filename);
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::error::TftpError;
+    use crate::config::FilenameMap;
     use crate::packet::Mode;
     use crate::temp;
+    use crate::testing::FaultyTransport;
+    use std::fs;
+    use std::io::Write;
+    use std::num::{NonZeroU16, NonZeroU64, NonZeroUsize};
+    use std::os::unix::fs::{FileExt, PermissionsExt};
     use std::str::FromStr;
     use std::sync;
-    use std::sync::Mutex;
+    use std::sync::atomic::AtomicUsize;
 
     #[test]
     fn test_server_run() {
@@ -515,148 +1921,4490 @@ mod tests {
     }
 
     #[test]
-    fn test_rrq_handler() {
-        //
-        // setup
-        //
-        let base_dir = temp::create_temp_dir().unwrap();
-        let handler = create_rrq_handler(base_dir.path().to_owned());
+    fn test_server_run_with_large_request() {
+        let server_addr = Arc::new(Mutex::new(None));
+        let rrq_queue = Arc::new(Mutex::new(vec![]));
 
-        let test_file_name = "test_wrq_handler.txt";
-        let test_file_content = [b'a'; 513];
         {
-            // prepare test file
-            let mut test_file = fs::File::create(base_dir.path().join(test_file_name)).unwrap();
-            test_file.write_all(&test_file_content).unwrap();
-        }
+            let sa = Arc::clone(&server_addr);
+            let rq = Arc::clone(&rrq_queue);
 
-        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
-        let addr_client = sock_client.local_addr().unwrap();
-        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
-        let addr_handler = sock_handler.local_addr().unwrap();
-        sock_handler
-            .set_read_timeout(Some(Duration::from_secs(1)))
-            .unwrap();
-        sock_handler
-            .set_write_timeout(Some(Duration::from_secs(1)))
-            .unwrap();
-        let rrq = packet::ReadPacket::new(test_file_name.to_string(), packet::Mode::OCTET);
+            let rrq_handler = move |_sock, _addr, pkt| {
+                rq.lock().unwrap().push(pkt);
+                Ok(())
+            };
 
-        let _h = thread::spawn(move || {
-            handler(sock_handler, addr_client, rrq).unwrap();
-        });
+            let mut server = TftpServer::create_with_handlers(
+                Ipv4Addr::from_str("127.0.0.1").unwrap(),
+                0,
+                Box::new(rrq_handler),
+                Box::new(|_sock, _addr, _pkt| Ok(())),
+            );
 
-        //
-        // exercise and verify
-        //
-        let mut buf_client = [0; 1024];
-        let mut actual_content: Vec<u8> = vec![];
+            let _h = thread::spawn(move || {
+                server.bind().unwrap();
+                *sa.lock().unwrap() = Some(server.server_addr().unwrap());
+                server.run().unwrap()
+            });
+        }
 
-        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
-        let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
-        assert_eq!(data.data().len(), 512);
-        actual_content.append(&mut data.data().to_owned());
-        sock_client
-            .send_to(&packet::ACK::new(data.block()).encode(), addr_handler)
-            .unwrap();
+        thread::sleep(std::time::Duration::from_secs(1));
 
-        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
-        let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
-        assert_eq!(data.data().len(), 1);
-        actual_content.append(&mut data.data().to_owned());
+        let server_addr = server_addr.lock().unwrap().unwrap();
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
         sock_client
-            .send_to(&packet::ACK::new(data.block()).encode(), addr_handler)
+            .set_read_timeout(Some(Duration::from_secs(1)))
             .unwrap();
+        let make_rrq = |n: usize| {
+            let options = (0..n)
+                .map(|i| (format!("opt{:03}", i), "x".repeat(10)))
+                .collect();
+            ReadPacket::new_with_options("foo.txt".to_string(), Mode::OCTET, options)
+        };
 
-        assert_eq!(&actual_content, &test_file_content);
+        // a request longer than 1024 bytes is parsed without truncation
+        let rrq = make_rrq(100);
+        assert!(rrq.encode().len() > 1024);
+        sock_client.send_to(&rrq.encode(), server_addr).unwrap();
+        thread::sleep(std::time::Duration::from_secs(1));
+        {
+            let queue = rrq_queue.lock().unwrap();
+            assert_eq!(queue.len(), 1);
+            assert_eq!(queue[0].options.len(), 100);
+        }
+
+        // a request exceeding the maximum is rejected
+        let rrq = make_rrq(300);
+        assert!(rrq.encode().len() > MAX_REQUEST_SIZE);
+        sock_client.send_to(&rrq.encode(), server_addr).unwrap();
+        let mut buf = [0; 1024];
+        let n = sock_client.recv(&mut buf).unwrap();
+        let err = packet::Error::parse(&buf[..n]).unwrap();
+        assert_eq!(err.error_code(), TftpError::IllegalTftpOp.error_code());
+        assert_eq!(rrq_queue.lock().unwrap().len(), 1);
     }
 
     #[test]
-    fn test_rrq_handler_with_512_multiple_bytes() {
-        env_logger::init();
+    fn test_server_run_with_retransmitted_wrq() {
         //
         // setup
         //
         let base_dir = temp::create_temp_dir().unwrap();
-        let handler = create_rrq_handler(base_dir.path().to_owned());
-
+        let temp_dir = temp::create_temp_dir().unwrap();
         let test_file_name = "test_wrq_handler.txt";
-        let test_file_content = [b'a'; 1024];
+        let server_addr = Arc::new(Mutex::new(None));
+        let handler_count = Arc::new(AtomicUsize::new(0));
+
         {
-            // prepare test file
-            let mut test_file = fs::File::create(base_dir.path().join(test_file_name)).unwrap();
-            test_file.write_all(&test_file_content).unwrap();
+            let sa = Arc::clone(&server_addr);
+            let hc = Arc::clone(&handler_count);
+            let handler = create_wrq_handler(
+                base_dir.path().to_owned(),
+                temp_dir.path().to_owned(),
+                Config::default(),
+            );
+            let wrq_handler = move |sock, addr, pkt| {
+                hc.fetch_add(1, Ordering::SeqCst);
+                handler(sock, addr, pkt)
+            };
+
+            let mut server = TftpServer::create_with_handlers(
+                Ipv4Addr::from_str("127.0.0.1").unwrap(),
+                0,
+                Box::new(|_sock, _addr, _pkt| Ok(())),
+                Box::new(wrq_handler),
+            );
+
+            let _h = thread::spawn(move || {
+                server.bind().unwrap();
+                *sa.lock().unwrap() = Some(server.server_addr().unwrap());
+                server.run().unwrap()
+            });
         }
 
+        thread::sleep(std::time::Duration::from_secs(1));
+
+        let server_addr = server_addr.lock().unwrap().unwrap();
         let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
-        let addr_client = sock_client.local_addr().unwrap();
-        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
-        let addr_handler = sock_handler.local_addr().unwrap();
-        sock_handler
+        sock_client
             .set_read_timeout(Some(Duration::from_secs(1)))
             .unwrap();
-        sock_handler
-            .set_write_timeout(Some(Duration::from_secs(1)))
-            .unwrap();
-        let rrq = packet::ReadPacket::new(test_file_name.to_string(), packet::Mode::OCTET);
+        let content = [b'a'; 100];
 
-        let _h = thread::spawn(move || {
-            handler(sock_handler, addr_client, rrq).unwrap();
-        });
+        //
+        // exercise
+        //
+        // the second WRQ is a retransmission with a different option
+        for blksize in ["1024", "8"] {
+            let wrq = WritePacket::new_with_options(
+                test_file_name.to_string(),
+                Mode::OCTET,
+                vec![("blksize".to_string(), blksize.to_string())],
+            );
+            sock_client.send_to(&wrq.encode(), server_addr).unwrap();
+        }
 
         //
-        // exercise and verify
+        // verify
         //
         let mut buf_client = [0; 1024];
-        let mut actual_content: Vec<u8> = vec![];
-
-        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
-        let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
-        assert_eq!(data.data().len(), 512);
-        actual_content.append(&mut data.data().to_owned());
-        sock_client
-            .send_to(&packet::ACK::new(data.block()).encode(), addr_handler)
-            .unwrap();
-
-        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
-        let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
-        assert_eq!(data.data().len(), 512);
-        actual_content.append(&mut data.data().to_owned());
-        sock_client
-            .send_to(&packet::ACK::new(data.block()).encode(), addr_handler)
-            .unwrap();
+        let (n_client, addr_handler) = sock_client.recv_from(&mut buf_client).unwrap();
+        let oack = packet::OACK::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(oack.get("blksize"), Some("1024"));
 
-        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
-        let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
-        assert_eq!(data.data().len(), 0);
-        actual_content.append(&mut data.data().to_owned());
-        sock_client
-            .send_to(&packet::ACK::new(data.block()).encode(), addr_handler)
-            .unwrap();
+        let data = packet::Data::new(1, &content);
+        sock_client.send_to(&data.encode(), addr_handler).unwrap();
+        loop {
+            let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+            // OACK may be sent again for the retransmitted WRQ, which has the same options
+            if let Ok(oack) = packet::OACK::parse(&buf_client[..n_client]) {
+                assert_eq!(oack.get("blksize"), Some("1024"));
+                continue;
+            }
+            let ack = packet::ACK::parse(&buf_client[..n_client]).unwrap();
+            assert_eq!(ack.block(), 1);
+            break;
+        }
 
-        assert_eq!(&actual_content, &test_file_content);
+        thread::sleep(std::time::Duration::from_millis(500));
+        assert_eq!(handler_count.load(Ordering::SeqCst), 1);
+        let actual_content = fs::read(base_dir.path().join(test_file_name)).unwrap();
+        assert_eq!(&actual_content, &content);
     }
 
     #[test]
-    fn test_rrq_handler_with_error() {
-        //
-        // setup
-        //
-        let base_dir = temp::create_temp_dir().unwrap();
-        let handler = create_rrq_handler(base_dir.path().to_owned());
+    fn test_server_run_with_quiet_errors() {
+        let server_addr = Arc::new(Mutex::new(None));
 
-        // this file doesn't exist, which should cause TftpError::FileNotFound
+        {
+            let sa = Arc::clone(&server_addr);
+
+            // reject all requests
+            let rrq_handler = |sock: UdpSocket, addr, _pkt| {
+                send_error_packet(
+                    &sock,
+                    &addr,
+                    TftpError::AccessViolation,
+                    "Access denied".to_string(),
+                    &ErrorMessages::default(),
+                    &ServerStats::default(),
+                );
+                Ok(())
+            };
+
+            let config = Config {
+                quiet_errors: true,
+                ..Config::default()
+            };
+            let mut server = TftpServer::create_with_handlers(
+                Ipv4Addr::from_str("127.0.0.1").unwrap(),
+                0,
+                Box::new(rrq_handler),
+                Box::new(|_sock, _addr, _pkt| Ok(())),
+            )
+            .with_config(config);
+
+            let _h = thread::spawn(move || {
+                server.bind().unwrap();
+                *sa.lock().unwrap() = Some(server.server_addr().unwrap());
+                server.run().unwrap()
+            });
+        }
+
+        thread::sleep(std::time::Duration::from_secs(1));
+
+        let server_addr = server_addr.lock().unwrap().unwrap();
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut buf = [0; 1024];
+
+        // no reply to garbage
+        for garbage in [&b"x"[..], b"GET / HTTP/1.0\r\n\r\n", b"\x00\x09\x00\x00"] {
+            sock_client.send_to(garbage, server_addr).unwrap();
+            let err = sock_client.recv(&mut buf).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        }
+
+        // well-formed but unexpected packet gets an error
+        sock_client
+            .send_to(&packet::ACK::new(1).encode(), server_addr)
+            .unwrap();
+        let n = sock_client.recv(&mut buf).unwrap();
+        let err = packet::Error::parse(&buf[..n]).unwrap();
+        assert_eq!(err.error_code(), TftpError::IllegalTftpOp.error_code());
+
+        // valid request gets an error from handler
+        let rrq = ReadPacket::new("foo.txt".to_string(), Mode::OCTET);
+        sock_client.send_to(&rrq.encode(), server_addr).unwrap();
+        let n = sock_client.recv(&mut buf).unwrap();
+        let err = packet::Error::parse(&buf[..n]).unwrap();
+        assert_eq!(err.error_code(), TftpError::AccessViolation.error_code());
+    }
+
+    #[test]
+    fn test_classify_packet() {
+        assert!(classify_packet(b"x").is_none());
+        assert!(classify_packet(b"GET / HTTP/1.0").is_none());
+        assert!(matches!(
+            classify_packet(&packet::ACK::new(1).encode()),
+            Some(PacketKind::Others)
+        ));
+        assert!(matches!(
+            classify_packet(&packet::Data::new(1, b"abc").encode()),
+            Some(PacketKind::Others)
+        ));
+        assert!(matches!(
+            classify_packet(&packet::Error::new(TftpError::Others, "error".to_string()).encode()),
+            Some(PacketKind::Error)
+        ));
+    }
+
+    #[test]
+    fn test_handle_error_with_policy() {
+        let create_server = |error_policy| {
+            TftpServer::create_with_handlers(
+                Ipv4Addr::from_str("127.0.0.1").unwrap(),
+                0,
+                Box::new(|_sock, _addr, _pkt| Ok(())),
+                Box::new(|_sock, _addr, _pkt| Ok(())),
+            )
+            .with_config(Config {
+                error_policy,
+                ..Config::default()
+            })
+        };
+        // e.g. caused by ICMP port unreachable for a packet sent before
+        let inject_error =
+            || anyhow::Error::new(std::io::Error::from(ErrorKind::ConnectionRefused));
+
+        let server = create_server(ErrorPolicy::Continue);
+        assert!(server.handle_error(inject_error()).is_ok());
+
+        let server = create_server(ErrorPolicy::Abort);
+        let err = server.handle_error(inject_error()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<std::io::Error>().unwrap().kind(),
+            ErrorKind::ConnectionRefused
+        );
+    }
+
+    #[test]
+    fn test_server_run_with_rate_limit() {
+        let server_addr = Arc::new(Mutex::new(None));
+        let rrq_count = Arc::new(AtomicUsize::new(0));
+
+        {
+            let sa = Arc::clone(&server_addr);
+            let rc = Arc::clone(&rrq_count);
+
+            let rrq_handler = move |_sock, _addr, _pkt| {
+                rc.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            };
+
+            let config = Config {
+                max_new_transfers_per_sec: std::num::NonZeroU32::new(5),
+                ..Config::default()
+            };
+            let mut server = TftpServer::create_with_handlers(
+                Ipv4Addr::from_str("127.0.0.1").unwrap(),
+                0,
+                Box::new(rrq_handler),
+                Box::new(|_sock, _addr, _pkt| Ok(())),
+            )
+            .with_config(config);
+
+            let _h = thread::spawn(move || {
+                server.bind().unwrap();
+                *sa.lock().unwrap() = Some(server.server_addr().unwrap());
+                server.run().unwrap()
+            });
+        }
+
+        thread::sleep(std::time::Duration::from_secs(1));
+
+        let server_addr = server_addr.lock().unwrap().unwrap();
+        let send_burst = || {
+            for _ in 0..20 {
+                // each request comes from a different client
+                let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+                let rrq = ReadPacket::new("foo.txt".to_string(), Mode::OCTET);
+                sock_client.send_to(&rrq.encode(), server_addr).unwrap();
+            }
+            thread::sleep(std::time::Duration::from_millis(500));
+        };
+
+        // the burst is capped
+        send_burst();
+        let count = rrq_count.load(Ordering::SeqCst);
+        assert!((5..=7).contains(&count), "count: {}", count);
+
+        // the limiter is refilled over time
+        thread::sleep(std::time::Duration::from_secs(1));
+        send_burst();
+        let count = rrq_count.load(Ordering::SeqCst) - count;
+        assert!((5..=7).contains(&count), "count: {}", count);
+    }
+
+    #[test]
+    fn test_server_run_with_error_rate_limit() {
+        //
+        // setup
+        //
+        let config = Config {
+            max_errors_per_sec: std::num::NonZeroU32::new(3),
+            ..Config::default()
+        };
+        let mut server = TftpServer::create_with_handlers(
+            Ipv4Addr::from_str("127.0.0.1").unwrap(),
+            0,
+            Box::new(|_sock, _addr, _pkt| Ok(())),
+            Box::new(|_sock, _addr, _pkt| Ok(())),
+        )
+        .with_config(config);
+        server.bind().unwrap();
+        let server_addr = server.server_addr().unwrap();
+        let server = Arc::new(server);
+        {
+            let server = Arc::clone(&server);
+            let _h = thread::spawn(move || server.run().unwrap());
+        }
+
+        // e.g. a spoofed source which would receive the reflected errors
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_millis(300)))
+            .unwrap();
+
+        //
+        // exercise
+        //
+        // ACK is illegal for the server port
+        for _ in 0..20 {
+            sock_client
+                .send_to(&packet::ACK::new(1).encode(), server_addr)
+                .unwrap();
+        }
+
+        //
+        // verify
+        //
+        let mut buf = [0; 1024];
+        let mut count = 0;
+        while let Ok((n, _)) = sock_client.recv_from(&mut buf) {
+            let err = packet::Error::parse(&buf[..n]).unwrap();
+            assert_eq!(err.error_code(), TftpError::IllegalTftpOp.error_code());
+            count += 1;
+        }
+        // the burst is capped (and a few may be refilled)
+        assert!((3..=4).contains(&count), "count: {}", count);
+        assert_eq!(server.stats().errors_sent.iter().sum::<u64>(), count);
+    }
+
+    #[test]
+    fn test_server_active_transfers() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        fs::write(base_dir.path().join("foo.txt"), [b'a'; 1024]).unwrap();
+        fs::write(base_dir.path().join("bar.txt"), [b'b'; 1024]).unwrap();
+        let mut server = TftpServer::create(
+            Ipv4Addr::from_str("127.0.0.1").unwrap(),
+            0,
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config::default(),
+        )
+        .unwrap();
+        server.bind().unwrap();
+        let server_addr = server.server_addr().unwrap();
+        let server = Arc::new(server);
+        {
+            let server = Arc::clone(&server);
+            let _h = thread::spawn(move || server.run().unwrap());
+        }
+
+        //
+        // exercise
+        //
+        // each transfer is paused by not sending ACK for the first block
+        let mut clients = vec![];
+        for filename in ["foo.txt", "bar.txt"] {
+            let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+            sock_client
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            let rrq = ReadPacket::new(filename.to_string(), Mode::OCTET);
+            sock_client.send_to(&rrq.encode(), server_addr).unwrap();
+            let mut buf = [0; 1024];
+            let (n, _) = sock_client.recv_from(&mut buf).unwrap();
+            assert_eq!(packet::Data::parse(&buf[..n]).unwrap().block(), 1);
+            clients.push(sock_client);
+        }
+
+        //
+        // verify
+        //
+        let transfers = server.active_transfers();
+        assert_eq!(transfers.len(), 2, "{:?}", transfers);
+        for (transfer, (filename, client)) in transfers
+            .iter()
+            .zip(["foo.txt", "bar.txt"].iter().zip(clients.iter()))
+        {
+            assert_eq!(transfer.filename, *filename);
+            assert_eq!(transfer.client_addr, client.local_addr().unwrap());
+            assert_eq!(transfer.operation, Operation::Read);
+            assert_eq!(transfer.bytes, 512);
+            assert_eq!(transfer.block_size, 512);
+            assert_eq!(transfer.window_size, 1);
+        }
+        assert_eq!(server.stats().active_transfers, 2);
+    }
+
+    #[test]
+    fn test_server_active_transfers_with_negotiated_options() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        fs::write(base_dir.path().join("foo.txt"), [b'a'; 4096]).unwrap();
+        let config = Config {
+            max_datagram_size: 1028,
+            max_window_size: 4,
+            ..Config::default()
+        };
+        let mut server = TftpServer::create(
+            Ipv4Addr::from_str("127.0.0.1").unwrap(),
+            0,
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            config,
+        )
+        .unwrap();
+        server.bind().unwrap();
+        let server_addr = server.server_addr().unwrap();
+        let server = Arc::new(server);
+        {
+            let server = Arc::clone(&server);
+            let _h = thread::spawn(move || server.run().unwrap());
+        }
+
+        //
+        // exercise
+        //
+        // the transfer is paused by not acknowledging OACK
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let rrq = ReadPacket::new_with_options(
+            "foo.txt".to_string(),
+            Mode::OCTET,
+            vec![
+                ("blksize".to_string(), "8192".to_string()),
+                ("windowsize".to_string(), "16".to_string()),
+            ],
+        );
+        sock_client.send_to(&rrq.encode(), server_addr).unwrap();
+        let mut buf = [0; 1024];
+        let (n, _) = sock_client.recv_from(&mut buf).unwrap();
+        let oack = packet::OACK::parse(&buf[..n]).unwrap();
+        assert_eq!(oack.get("blksize"), Some("1024"));
+
+        //
+        // verify
+        //
+        // the clamped values are reported rather than the requested ones
+        let transfers = server.active_transfers();
+        assert_eq!(transfers.len(), 1, "{:?}", transfers);
+        assert_eq!(transfers[0].block_size, 1024);
+        assert_eq!(transfers[0].window_size, 4);
+    }
+
+    #[test]
+    fn test_server_with_auth() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        fs::write(base_dir.path().join("public.txt"), b"public").unwrap();
+        fs::write(base_dir.path().join("secret.txt"), b"secret").unwrap();
+        let mut server = TftpServer::create(
+            Ipv4Addr::from_str("127.0.0.1").unwrap(),
+            0,
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config::default(),
+        )
+        .unwrap()
+        .with_auth(Box::new(|_addr, request| match request {
+            packet::InitialPacket::RRQ(rrq) if rrq.filename == "secret.txt" => Err((
+                TftpError::AccessViolation,
+                "secret.txt is not for you".to_string(),
+            )),
+            _ => Ok(()),
+        }));
+        server.bind().unwrap();
+        let server_addr = server.server_addr().unwrap();
+        let stats = Arc::clone(&server.stats);
+        let _h = thread::spawn(move || server.run().unwrap());
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut buf = [0; 1024];
+
+        //
+        // exercise and verify
+        //
+        let rrq = ReadPacket::new("secret.txt".to_string(), Mode::OCTET);
+        sock_client.send_to(&rrq.encode(), server_addr).unwrap();
+        let (n, _) = sock_client.recv_from(&mut buf).unwrap();
+        let err = packet::Error::parse(&buf[..n]).unwrap();
+        assert_eq!(err.error_code(), TftpError::AccessViolation.error_code());
+        assert_eq!(err.message(), "secret.txt is not for you");
+
+        let rrq = ReadPacket::new("public.txt".to_string(), Mode::OCTET);
+        sock_client.send_to(&rrq.encode(), server_addr).unwrap();
+        let (n, _) = sock_client.recv_from(&mut buf).unwrap();
+        let data = packet::Data::parse(&buf[..n]).unwrap();
+        assert_eq!(data.data(), b"public");
+
+        // counted when the next request is handled at the latest
+        let code = TftpError::AccessViolation.error_code() as usize;
+        assert_eq!(stats.snapshot().errors_sent[code], 1);
+    }
+
+    #[test]
+    fn test_server_with_max_transfers() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        fs::write(base_dir.path().join("foo.txt"), b"foo").unwrap();
+        let mut server = TftpServer::create(
+            Ipv4Addr::from_str("127.0.0.1").unwrap(),
+            0,
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config {
+                max_transfers: NonZeroU64::new(2),
+                ..Config::default()
+            },
+        )
+        .unwrap();
+        server.bind().unwrap();
+        let server_addr = server.server_addr().unwrap();
+        let h = thread::spawn(move || server.run());
+
+        //
+        // exercise
+        //
+        let mut clients = vec![];
+        for _ in 0..2 {
+            let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+            sock_client
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            let rrq = ReadPacket::new("foo.txt".to_string(), Mode::OCTET);
+            sock_client.send_to(&rrq.encode(), server_addr).unwrap();
+            let mut buf = [0; 1024];
+            let (n, _) = sock_client.recv_from(&mut buf).unwrap();
+            assert_eq!(packet::Data::parse(&buf[..n]).unwrap().data(), b"foo");
+            clients.push(sock_client);
+        }
+        // the server waits for the active transfers
+        thread::sleep(Duration::from_millis(200));
+        assert!(!h.is_finished());
+        for sock_client in clients.iter() {
+            let ack = packet::ACK::new(1);
+            sock_client.send_to(&ack.encode(), server_addr).unwrap();
+        }
+
+        //
+        // verify
+        //
+        for _ in 0..50 {
+            if h.is_finished() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert!(h.is_finished());
+        h.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_server_with_egress_budget() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        fs::write(base_dir.path().join("foo.txt"), b"foo").unwrap();
+        let mut server = TftpServer::create(
+            Ipv4Addr::from_str("127.0.0.1").unwrap(),
+            0,
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config {
+                egress_budget: NonZeroU64::new(5),
+                ..Config::default()
+            },
+        )
+        .unwrap();
+        server.bind().unwrap();
+        let server_addr = server.server_addr().unwrap();
+        let _h = thread::spawn(move || server.run());
+
+        //
+        // exercise and verify
+        //
+        // served until 5 bytes are sent (the last one exceeds it)
+        for _ in 0..2 {
+            let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+            sock_client
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            let rrq = ReadPacket::new("foo.txt".to_string(), Mode::OCTET);
+            sock_client.send_to(&rrq.encode(), server_addr).unwrap();
+            let mut buf = [0; 1024];
+            let (n, handler_addr) = sock_client.recv_from(&mut buf).unwrap();
+            assert_eq!(packet::Data::parse(&buf[..n]).unwrap().data(), b"foo");
+            let ack = packet::ACK::new(1);
+            sock_client.send_to(&ack.encode(), handler_addr).unwrap();
+        }
+
+        // the budget is used up
+        for _ in 0..2 {
+            let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+            sock_client
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            let rrq = ReadPacket::new("foo.txt".to_string(), Mode::OCTET);
+            sock_client.send_to(&rrq.encode(), server_addr).unwrap();
+            let mut buf = [0; 1024];
+            let (n, _) = sock_client.recv_from(&mut buf).unwrap();
+            let err = packet::Error::parse(&buf[..n]).unwrap();
+            assert_eq!(err.error_code(), TftpError::Others.error_code());
+        }
+    }
+
+    #[test]
+    fn test_server_with_max_active_transfers() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        fs::write(base_dir.path().join("foo.txt"), [b'a'; 1024]).unwrap();
+        let mut server = TftpServer::create(
+            Ipv4Addr::from_str("127.0.0.1").unwrap(),
+            0,
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config {
+                max_active_transfers: NonZeroUsize::new(1),
+                ..Config::default()
+            },
+        )
+        .unwrap();
+        server.bind().unwrap();
+        let server_addr = server.server_addr().unwrap();
+        let _h = thread::spawn(move || server.run());
+
+        let clients: Vec<UdpSocket> = (0..3)
+            .map(|_| {
+                let sock = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+                sock.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+                sock
+            })
+            .collect();
+        let rrq = ReadPacket::new("foo.txt".to_string(), Mode::OCTET);
+        let mut buf = [0; 1024];
+
+        //
+        // exercise and verify
+        //
+        // the first transfer is in progress until block 1 is acknowledged
+        clients[0].send_to(&rrq.encode(), server_addr).unwrap();
+        let (n, handler_addr) = clients[0].recv_from(&mut buf).unwrap();
+        assert_eq!(packet::Data::parse(&buf[..n]).unwrap().block(), 1);
+
+        clients[1].send_to(&rrq.encode(), server_addr).unwrap();
+        let (n, _) = clients[1].recv_from(&mut buf).unwrap();
+        let err = packet::Error::parse(&buf[..n]).unwrap();
+        assert_eq!(err.error_code(), TftpError::Others.error_code());
+        assert_eq!(err.message(), "Server busy");
+
+        // finish the first transfer (1024 bytes are followed by an empty block)
+        for block in 1..=3 {
+            if block > 1 {
+                let (n, _) = clients[0].recv_from(&mut buf).unwrap();
+                assert_eq!(packet::Data::parse(&buf[..n]).unwrap().block(), block);
+            }
+            let ack = packet::ACK::new(block);
+            clients[0].send_to(&ack.encode(), handler_addr).unwrap();
+        }
+
+        // accepted after the first transfer finishes
+        thread::sleep(Duration::from_millis(100));
+        clients[2].send_to(&rrq.encode(), server_addr).unwrap();
+        let (n, _) = clients[2].recv_from(&mut buf).unwrap();
+        assert_eq!(packet::Data::parse(&buf[..n]).unwrap().block(), 1);
+    }
+
+    #[test]
+    fn test_server_with_retry_config() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        fs::write(base_dir.path().join("foo.txt"), b"foo").unwrap();
+        let mut server = TftpServer::create(
+            Ipv4Addr::from_str("127.0.0.1").unwrap(),
+            0,
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config {
+                retry_interval: Duration::from_millis(100),
+                max_retries: NonZeroU16::new(1).unwrap(),
+                ..Config::default()
+            },
+        )
+        .unwrap();
+        server.bind().unwrap();
+        let server_addr = server.server_addr().unwrap();
+        let stats = Arc::clone(&server.stats);
+        let _h = thread::spawn(move || server.run());
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        //
+        // exercise
+        //
+        let rrq = ReadPacket::new("foo.txt".to_string(), Mode::OCTET);
+        sock_client.send_to(&rrq.encode(), server_addr).unwrap();
+
+        //
+        // verify
+        //
+        // DATA is retransmitted only once, and then the transfer fails
+        let mut buf = [0; 1024];
+        for _ in 0..2 {
+            let (n, _) = sock_client.recv_from(&mut buf).unwrap();
+            assert_eq!(packet::Data::parse(&buf[..n]).unwrap().block(), 1);
+        }
+        assert!(sock_client.recv_from(&mut buf).is_err());
+        assert_eq!(stats.snapshot().rrq_failed, 1);
+
+        // a zero interval is rejected
+        let result = TftpServer::create(
+            Ipv4Addr::from_str("127.0.0.1").unwrap(),
+            0,
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config {
+                retry_interval: Duration::ZERO,
+                ..Config::default()
+            },
+        );
+        assert!(matches!(result, Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_server_with_read_only() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        fs::write(base_dir.path().join("foo.txt"), b"foo").unwrap();
+        let mut server = TftpServer::create(
+            Ipv4Addr::from_str("127.0.0.1").unwrap(),
+            0,
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config {
+                read_only: true,
+                ..Config::default()
+            },
+        )
+        .unwrap();
+        server.bind().unwrap();
+        let server_addr = server.server_addr().unwrap();
+        let _h = thread::spawn(move || server.run());
+
+        //
+        // exercise and verify
+        //
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut buf = [0; 1024];
+        let wrq = WritePacket::new("bar.txt".to_string(), Mode::OCTET);
+        sock_client.send_to(&wrq.encode(), server_addr).unwrap();
+        let (n, _) = sock_client.recv_from(&mut buf).unwrap();
+        let err = packet::Error::parse(&buf[..n]).unwrap();
+        assert_eq!(err.error_code(), TftpError::AccessViolation.error_code());
+        assert_eq!(err.message(), "Server is read-only");
+        assert!(!base_dir.path().join("bar.txt").exists());
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+
+        // RRQ is served as usual
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let rrq = ReadPacket::new("foo.txt".to_string(), Mode::OCTET);
+        sock_client.send_to(&rrq.encode(), server_addr).unwrap();
+        let (n, handler_addr) = sock_client.recv_from(&mut buf).unwrap();
+        assert_eq!(packet::Data::parse(&buf[..n]).unwrap().data(), b"foo");
+        let ack = packet::ACK::new(1);
+        sock_client.send_to(&ack.encode(), handler_addr).unwrap();
+    }
+
+    #[test]
+    fn test_server_with_write_only() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        fs::write(base_dir.path().join("foo.txt"), b"foo").unwrap();
+        let mut server = TftpServer::create(
+            Ipv4Addr::from_str("127.0.0.1").unwrap(),
+            0,
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config {
+                write_only: true,
+                ..Config::default()
+            },
+        )
+        .unwrap();
+        server.bind().unwrap();
+        let server_addr = server.server_addr().unwrap();
+        let _h = thread::spawn(move || server.run());
+
+        //
+        // exercise and verify
+        //
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut buf = [0; 1024];
+        let rrq = ReadPacket::new("foo.txt".to_string(), Mode::OCTET);
+        sock_client.send_to(&rrq.encode(), server_addr).unwrap();
+        let (n, _) = sock_client.recv_from(&mut buf).unwrap();
+        let err = packet::Error::parse(&buf[..n]).unwrap();
+        assert_eq!(err.error_code(), TftpError::AccessViolation.error_code());
+        assert_eq!(err.message(), "Server is write-only");
+        // no DATA follows
+        sock_client
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        assert!(sock_client.recv_from(&mut buf).is_err());
+
+        // WRQ is accepted as usual
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let wrq = WritePacket::new("bar.txt".to_string(), Mode::OCTET);
+        sock_client.send_to(&wrq.encode(), server_addr).unwrap();
+        let (n, handler_addr) = sock_client.recv_from(&mut buf).unwrap();
+        assert_eq!(packet::ACK::parse(&buf[..n]).unwrap().block(), 0);
+        let data = packet::Data::new(1, b"bar");
+        sock_client.send_to(&data.encode(), handler_addr).unwrap();
+        let (n, _) = sock_client.recv_from(&mut buf).unwrap();
+        assert_eq!(packet::ACK::parse(&buf[..n]).unwrap().block(), 1);
+    }
+
+    #[test]
+    fn test_server_with_clients_behind_nat() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        let content: Vec<u8> = (0..1200_u32).map(|i| (i % 251) as u8).collect();
+        fs::write(base_dir.path().join("foo.txt"), &content).unwrap();
+        let mut server = TftpServer::create(
+            Ipv4Addr::from_str("127.0.0.1").unwrap(),
+            0,
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config::default(),
+        )
+        .unwrap();
+        server.bind().unwrap();
+        let server_addr = server.server_addr().unwrap();
+        let _h = thread::spawn(move || server.run());
+
+        // clients behind a NAT have the same address and differ only in ports
+        let clients: Vec<UdpSocket> = (0..2)
+            .map(|_| {
+                let sock = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+                sock.set_read_timeout(Some(Duration::from_millis(300)))
+                    .unwrap();
+                sock
+            })
+            .collect();
+        let recv_data = |sock: &UdpSocket| {
+            let mut buf = [0; 1024];
+            let (n, handler_addr) = sock.recv_from(&mut buf).unwrap();
+            let data = packet::Data::parse(&buf[..n]).unwrap();
+            (data.block(), data.data().to_vec(), handler_addr)
+        };
+
+        //
+        // exercise and verify
+        //
+        // both request the same file at once
+        let mut handler_addrs = vec![];
+        for sock in clients.iter() {
+            let rrq = ReadPacket::new("foo.txt".to_string(), Mode::OCTET);
+            sock.send_to(&rrq.encode(), server_addr).unwrap();
+            let (block, data, handler_addr) = recv_data(sock);
+            assert_eq!(block, 1);
+            assert_eq!(data, &content[..512]);
+            handler_addrs.push(handler_addr);
+        }
+        // the transfers proceed independently even if ACKs are interleaved
+        let mut received = [content[..512].to_vec(), content[..512].to_vec()];
+        let mut acked = [1_u16, 1];
+        for i in [0, 0, 1, 1] {
+            let ack = packet::ACK::new(acked[i]);
+            clients[i].send_to(&ack.encode(), handler_addrs[i]).unwrap();
+            let (block, data, _) = recv_data(&clients[i]);
+            assert_eq!(block, acked[i] + 1);
+            received[i].extend_from_slice(&data);
+            acked[i] = block;
+            // the other client receives nothing
+            let mut buf = [0; 1024];
+            assert!(clients[1 - i].recv_from(&mut buf).is_err());
+        }
+        for (i, sock) in clients.iter().enumerate() {
+            let ack = packet::ACK::new(acked[i]);
+            sock.send_to(&ack.encode(), handler_addrs[i]).unwrap();
+            assert_eq!(received[i], content);
+        }
+    }
+
+    #[test]
+    fn test_server_with_temp_dir_same_as_base_dir() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let test_file_name = "foo.txt";
+        let mut server = TftpServer::create(
+            Ipv4Addr::from_str("127.0.0.1").unwrap(),
+            0,
+            base_dir.path().to_owned(),
+            // the same directory by another path
+            base_dir.path().join("."),
+            Config::default(),
+        )
+        .unwrap();
+        assert!(server.config.stage_in_place);
+        server.bind().unwrap();
+        let server_addr = server.server_addr().unwrap();
+        let _h = thread::spawn(move || server.run());
+
+        let list_base_dir = || {
+            fs::read_dir(base_dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+
+        //
+        // exercise and verify
+        //
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let wrq = WritePacket::new(test_file_name.to_string(), Mode::OCTET);
+        sock_client.send_to(&wrq.encode(), server_addr).unwrap();
+        let mut buf = [0; 1024];
+        let (n, handler_addr) = sock_client.recv_from(&mut buf).unwrap();
+        assert_eq!(packet::ACK::parse(&buf[..n]).unwrap().block(), 0);
+
+        // staged as a dotfile during the transfer
+        let names = list_base_dir();
+        assert_eq!(names.len(), 1, "{:?}", names);
+        assert!(names[0].starts_with(".foo.txt."), "{:?}", names);
+
+        let data = packet::Data::new(1, b"foo");
+        sock_client.send_to(&data.encode(), handler_addr).unwrap();
+        let (n, _) = sock_client.recv_from(&mut buf).unwrap();
+        assert_eq!(packet::ACK::parse(&buf[..n]).unwrap().block(), 1);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(list_base_dir(), vec![test_file_name.to_string()]);
+        assert_eq!(
+            fs::read(base_dir.path().join(test_file_name)).unwrap(),
+            b"foo"
+        );
+    }
+
+    #[test]
+    fn test_server_stats_by_operation() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        fs::write(base_dir.path().join("foo.txt"), b"foo").unwrap();
+        let mut server = TftpServer::create(
+            Ipv4Addr::from_str("127.0.0.1").unwrap(),
+            0,
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config::default(),
+        )
+        .unwrap();
+        server.bind().unwrap();
+        let server_addr = server.server_addr().unwrap();
+        let server = Arc::new(server);
+        {
+            let server = Arc::clone(&server);
+            let _h = thread::spawn(move || server.run().unwrap());
+        }
+
+        // a client socket for each transfer, because packets from the same address
+        // are delivered to the child socket of the previous transfer until it finishes
+        let clients: Vec<UdpSocket> = (0..3)
+            .map(|_| {
+                let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+                sock_client
+                    .set_read_timeout(Some(Duration::from_secs(1)))
+                    .unwrap();
+                sock_client
+            })
+            .collect();
+        let mut buf = [0; 1024];
+
+        //
+        // exercise
+        //
+        // RRQ
+        let sock_client = &clients[0];
+        let rrq = ReadPacket::new("foo.txt".to_string(), Mode::OCTET);
+        sock_client.send_to(&rrq.encode(), server_addr).unwrap();
+        let (n, _) = sock_client.recv_from(&mut buf).unwrap();
+        assert_eq!(packet::Data::parse(&buf[..n]).unwrap().data(), b"foo");
+        sock_client
+            .send_to(&packet::ACK::new(1).encode(), server_addr)
+            .unwrap();
+
+        // WRQ
+        let sock_client = &clients[1];
+        let wrq = WritePacket::new("bar.txt".to_string(), Mode::OCTET);
+        sock_client.send_to(&wrq.encode(), server_addr).unwrap();
+        let (n, _) = sock_client.recv_from(&mut buf).unwrap();
+        assert_eq!(packet::ACK::parse(&buf[..n]).unwrap().block(), 0);
+        let data = packet::Data::new(1, b"bar");
+        sock_client.send_to(&data.encode(), server_addr).unwrap();
+        let (n, _) = sock_client.recv_from(&mut buf).unwrap();
+        assert_eq!(packet::ACK::parse(&buf[..n]).unwrap().block(), 1);
+
+        // RRQ of a missing file
+        let sock_client = &clients[2];
+        let rrq = ReadPacket::new("missing.txt".to_string(), Mode::OCTET);
+        sock_client.send_to(&rrq.encode(), server_addr).unwrap();
+        let (n, _) = sock_client.recv_from(&mut buf).unwrap();
+        assert!(packet::Error::parse(&buf[..n]).is_ok());
+
+        //
+        // verify
+        //
+        for _ in 0..50 {
+            if server.stats().active_transfers == 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        let stats = server.stats();
+        assert_eq!(stats.total_transfers, 3);
+        assert_eq!(stats.rrq_completed, 1);
+        assert_eq!(stats.rrq_failed, 1);
+        assert_eq!(stats.wrq_completed, 1);
+        assert_eq!(stats.wrq_failed, 0);
+        assert_eq!(stats.bytes_sent, 3);
+        assert_eq!(stats.bytes_received, 3);
+        assert_eq!(stats.errors_sent, [0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(stats.errors, 1);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_server_with_metrics() {
+        use std::io::Read;
+        use std::net::{TcpListener, TcpStream};
+
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        fs::write(base_dir.path().join("foo.txt"), b"foo").unwrap();
+        // find a free port
+        let metrics_addr = TcpListener::bind(("127.0.0.1", 0))
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut server = TftpServer::create(
+            Ipv4Addr::from_str("127.0.0.1").unwrap(),
+            0,
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config {
+                metrics_addr: Some(metrics_addr),
+                ..Config::default()
+            },
+        )
+        .unwrap();
+        server.bind().unwrap();
+        let server_addr = server.server_addr().unwrap();
+        let server = Arc::new(server);
+        {
+            let server = Arc::clone(&server);
+            let _h = thread::spawn(move || server.run().unwrap());
+        }
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let rrq = ReadPacket::new("foo.txt".to_string(), Mode::OCTET);
+        sock_client.send_to(&rrq.encode(), server_addr).unwrap();
+        let mut buf = [0; 1024];
+        let (n, _) = sock_client.recv_from(&mut buf).unwrap();
+        assert_eq!(packet::Data::parse(&buf[..n]).unwrap().data(), b"foo");
+        sock_client
+            .send_to(&packet::ACK::new(1).encode(), server_addr)
+            .unwrap();
+        for _ in 0..50 {
+            if server.stats().rrq_completed == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        //
+        // exercise
+        //
+        let mut stream = TcpStream::connect(metrics_addr).unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        //
+        // verify
+        //
+        let (header, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(header.starts_with("HTTP/1.1 200 OK"), "{}", header);
+        let lines: Vec<&str> = body.lines().collect();
+        assert!(lines.contains(&"tftpff_active_transfers 0"), "{}", body);
+        assert!(
+            lines.contains(&"tftpff_transfers_total{operation=\"read\",result=\"completed\"} 1"),
+            "{}",
+            body
+        );
+        assert!(lines.contains(&"tftpff_sent_bytes_total 3"), "{}", body);
+    }
+
+    #[test]
+    fn test_server_cancel() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        fs::write(base_dir.path().join("foo.txt"), [b'a'; 1024]).unwrap();
+        let mut server = TftpServer::create(
+            Ipv4Addr::from_str("127.0.0.1").unwrap(),
+            0,
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config::default(),
+        )
+        .unwrap();
+        server.bind().unwrap();
+        let server_addr = server.server_addr().unwrap();
+        let server = Arc::new(server);
+        {
+            let server = Arc::clone(&server);
+            let _h = thread::spawn(move || server.run().unwrap());
+        }
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let rrq = ReadPacket::new("foo.txt".to_string(), Mode::OCTET);
+        sock_client.send_to(&rrq.encode(), server_addr).unwrap();
+        let mut buf = [0; 1024];
+        let (n, _) = sock_client.recv_from(&mut buf).unwrap();
+        assert_eq!(packet::Data::parse(&buf[..n]).unwrap().block(), 1);
+
+        //
+        // exercise
+        //
+        assert_eq!(server.cancel(addr_client), 1);
+        // the handler notices the cancellation when it receives the ACK
+        let ack = packet::ACK::new(1);
+        sock_client.send_to(&ack.encode(), server_addr).unwrap();
+
+        //
+        // verify
+        //
+        let (n, _) = sock_client.recv_from(&mut buf).unwrap();
+        let err = packet::Error::parse(&buf[..n]).unwrap();
+        assert_eq!(err.error_code(), TftpError::Others.error_code());
+        assert_eq!(err.message(), "cancelled");
+
+        for _ in 0..50 {
+            // the handler has exited when the transfer is counted as an error
+            if server.stats().errors == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(server.stats().errors, 1);
+        assert!(server.active_transfers().is_empty());
+        assert_eq!(server.cancel(addr_client), 0);
+    }
+
+    #[test]
+    fn test_server_from_socket() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        let test_file_name = "test_from_socket.txt";
+        let content = [b'a'; 100];
+        fs::write(base_dir.path().join(test_file_name), content).unwrap();
+
+        // bound outside of the server
+        let server_sock = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let server_addr = server_sock.local_addr().unwrap();
+        let server = TftpServer::from_socket(
+            server_sock,
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config::default(),
+        )
+        .unwrap();
+        assert_eq!(server.server_addr(), Some(server_addr));
+        let _h = thread::spawn(move || server.run().unwrap());
+
+        //
+        // exercise
+        //
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let rrq = ReadPacket::new(test_file_name.to_string(), Mode::OCTET);
+        sock_client.send_to(&rrq.encode(), server_addr).unwrap();
+
+        //
+        // verify
+        //
+        let mut buf_client = [0; 1024];
+        let (n_client, addr_handler) = sock_client.recv_from(&mut buf_client).unwrap();
+        let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(data.block(), 1);
+        assert_eq!(data.data(), &content);
+        assert_eq!(addr_handler, server_addr);
+        sock_client
+            .send_to(&packet::ACK::new(1).encode(), addr_handler)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_server_with_config_error() {
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        let server = TftpServer::create(
+            Ipv4Addr::from_str("127.0.0.1").unwrap(),
+            0,
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config::default(),
+        )
+        .unwrap();
+        // not bound yet
+        assert!(matches!(
+            server.reject_pending_requests(),
+            Err(Error::Config(_))
+        ));
+
+        // IPv6 may be disabled in the environment
+        if let Ok(sock) = UdpSocket::bind("[::1]:0") {
+            let res = TftpServer::from_socket(
+                sock,
+                base_dir.path().to_owned(),
+                temp_dir.path().to_owned(),
+                Config::default(),
+            );
+            assert!(matches!(res, Err(Error::Config(_))));
+        }
+    }
+
+    #[test]
+    fn test_reject_pending_requests() {
+        //
+        // setup
+        //
+        let mut server = TftpServer::create_with_handlers(
+            Ipv4Addr::from_str("127.0.0.1").unwrap(),
+            0,
+            Box::new(|_sock, _addr, _pkt| Ok(())),
+            Box::new(|_sock, _addr, _pkt| Ok(())),
+        );
+        server.bind().unwrap();
+        let server_addr = server.server_addr().unwrap();
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        //
+        // exercise
+        //
+        // requests sent before run
+        let rrq = ReadPacket::new("foo.txt".to_string(), Mode::OCTET);
+        sock_client.send_to(&rrq.encode(), server_addr).unwrap();
+        sock_client.send_to(b"garbage", server_addr).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let count = server.reject_pending_requests().unwrap();
+
+        //
+        // verify
+        //
+        assert_eq!(count, 1);
+        let mut buf = [0; 1024];
+        let n = sock_client.recv(&mut buf).unwrap();
+        let err = packet::Error::parse(&buf[..n]).unwrap();
+        assert_eq!(err.error_code(), TftpError::Others.error_code());
+        // nothing for garbage
+        assert!(sock_client.recv(&mut buf).is_err());
+        // the socket is back to blocking mode with timeout
+        assert_eq!(server.reject_pending_requests().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_server_with_recv_buffer_size() {
+        // count requests queued in the server socket after a burst
+        let burst = |recv_buffer_size: Option<usize>, n: usize| {
+            let mut server = TftpServer::create_with_handlers(
+                Ipv4Addr::from_str("127.0.0.1").unwrap(),
+                0,
+                Box::new(|_sock, _addr, _pkt| Ok(())),
+                Box::new(|_sock, _addr, _pkt| Ok(())),
+            )
+            .with_config(Config {
+                recv_buffer_size,
+                ..Config::default()
+            });
+            server.bind().unwrap();
+            let server_addr = server.server_addr().unwrap();
+            let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+            for i in 0..n {
+                let rrq = ReadPacket::new(format!("file{}.txt", i), Mode::OCTET);
+                sock_client.send_to(&rrq.encode(), server_addr).unwrap();
+            }
+            thread::sleep(Duration::from_millis(100));
+            server.reject_pending_requests().unwrap()
+        };
+
+        let n = 2000;
+        let default_count = burst(None, n);
+        let large_count = burst(Some(8 * 1024 * 1024), n);
+        eprintln!(
+            "accepted in a burst of {}: default={}, large buffer={}",
+            n, default_count, large_count
+        );
+        assert!(large_count >= default_count);
+        // the buffer can be enlarged only up to net.core.rmem_max without CAP_NET_ADMIN
+        if nix::unistd::geteuid().is_root() {
+            assert_eq!(large_count, n);
+        }
+    }
+
+    #[test]
+    fn test_bind_with_port_zero() {
+        let mut server = TftpServer::create_with_handlers(
+            Ipv4Addr::from_str("127.0.0.1").unwrap(),
+            0,
+            Box::new(|_sock, _addr, _pkt| Ok(())),
+            Box::new(|_sock, _addr, _pkt| Ok(())),
+        );
+        assert!(server.server_addr().is_none());
+
+        server.bind().unwrap();
+
+        let addr = server.server_addr().unwrap();
+        assert_eq!(addr.ip(), Ipv4Addr::from_str("127.0.0.1").unwrap());
+        assert_ne!(addr.port(), 0);
+    }
+
+    #[test]
+    fn test_child_socket_ignores_other_addresses() {
+        //
+        // setup
+        //
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let sock_other = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let child_sock = create_child_socket(
+            SocketAddr::from_str("127.0.0.1:0").unwrap(),
+            sock_client.local_addr().unwrap(),
+            Duration::from_secs(1),
+        )
+        .unwrap();
+        let addr_child = child_sock.local_addr().unwrap();
+
+        //
+        // exercise
+        //
+        sock_other
+            .send_to(&packet::ACK::new(1).encode(), addr_child)
+            .unwrap();
+        sock_client
+            .send_to(&packet::ACK::new(2).encode(), addr_child)
+            .unwrap();
+
+        //
+        // verify
+        //
+        // the packet from the other address is dropped by kernel
+        let mut buf = [0; 1024];
+        let (n, addr) = child_sock.recv_from(&mut buf).unwrap();
+        assert_eq!(addr, sock_client.local_addr().unwrap());
+        assert_eq!(packet::ACK::parse(&buf[..n]).unwrap().block(), 2);
+        child_sock.set_nonblocking(true).unwrap();
+        assert!(child_sock.recv_from(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_acknowledge_stale_blocks() {
+        let mut state = RrqHandlingState::new();
+        for _ in 0..4 {
+            state.push(vec![b'a'; 512]);
+        }
+        assert_eq!(state.acknowledge(2), AckResult::Progress { blocks: 2 });
+        // behind the acknowledged block
+        assert_eq!(state.acknowledge(1), AckResult::Previous);
+        assert_eq!(state.acknowledge(0), AckResult::Previous);
+        assert_eq!(state.acknowledge(u16::MAX), AckResult::Previous);
+        // ahead of the blocks sent
+        assert_eq!(state.acknowledge(5), AckResult::Illegal);
+        assert_eq!(state.acknowledge(4), AckResult::Progress { blocks: 2 });
+    }
+
+    #[test]
+    fn test_block_rollover() {
+        // RRQ: DATA 65535 is followed by DATA 0
+        let oack = packet::OACK::new(vec![("tsize".to_string(), "1024".to_string())]);
+        let mut state = RrqHandlingState::new_with_oack(oack, u16::MAX);
+        assert!(matches!(
+            state.acknowledge(0),
+            AckResult::Progress { blocks: 0 }
+        ));
+        state.push(vec![b'a'; 512]);
+        state.push(vec![b'b'; 512]);
+        let blocks: Vec<u16> = state
+            .prepare_packets(2, 1)
+            .unwrap()
+            .into_iter()
+            .map(|pkt| match pkt {
+                ResponsePacket::Data(data) => data.block(),
+                _ => panic!("unexpected packet: {}", pkt),
+            })
+            .collect();
+        assert_eq!(blocks, [u16::MAX, 0]);
+        assert!(matches!(
+            state.acknowledge(0),
+            AckResult::Progress { blocks: 2 }
+        ));
+        assert!(matches!(state.acknowledge(0), AckResult::Previous));
+
+        // WRQ: ACK 65535 is followed by ACK 0
+        let state = WrqHandlingState::DataAccepted {
+            block: u16::MAX,
+            trial_count: 0,
+        };
+        let mut state = state.next();
+        assert_eq!(state.block(), 0);
+        match state.prepare_packet(1) {
+            Some(ResponsePacket::ACK(ack)) => assert_eq!(ack.block(), 0),
+            _ => panic!("ACK is expected"),
+        }
+    }
+
+    #[test]
+    fn test_rrq_handler() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let handler = create_rrq_handler(base_dir.path().to_owned(), Config::default());
+
+        let test_file_name = "test_wrq_handler.txt";
+        let test_file_content = [b'a'; 513];
+        {
+            // prepare test file
+            let mut test_file = fs::File::create(base_dir.path().join(test_file_name)).unwrap();
+            test_file.write_all(&test_file_content).unwrap();
+        }
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        sock_handler
+            .set_write_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let rrq = packet::ReadPacket::new(test_file_name.to_string(), packet::Mode::OCTET);
+
+        let _h = thread::spawn(move || {
+            handler(sock_handler, addr_client, rrq).unwrap();
+        });
+
+        //
+        // exercise and verify
+        //
+        let mut buf_client = [0; 1024];
+        let mut actual_content: Vec<u8> = vec![];
+
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(data.data().len(), 512);
+        actual_content.append(&mut data.data().to_owned());
+        sock_client
+            .send_to(&packet::ACK::new(data.block()).encode(), addr_handler)
+            .unwrap();
+
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(data.data().len(), 1);
+        actual_content.append(&mut data.data().to_owned());
+        sock_client
+            .send_to(&packet::ACK::new(data.block()).encode(), addr_handler)
+            .unwrap();
+
+        assert_eq!(&actual_content, &test_file_content);
+    }
+
+    #[test]
+    fn test_rrq_handler_with_netascii() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let handler = create_rrq_handler(base_dir.path().to_owned(), Config::default());
+
+        // 600 bytes on disk, 720 bytes on the wire
+        let test_file_name = "test_rrq_handler.txt";
+        fs::write(base_dir.path().join(test_file_name), b"line\n".repeat(120)).unwrap();
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        let rrq = packet::ReadPacket::new(test_file_name.to_string(), packet::Mode::NETASCII);
+
+        let h = thread::spawn(move || handler(sock_handler, addr_client, rrq));
+
+        //
+        // exercise
+        //
+        let mut buf_client = [0; 1024];
+        let mut actual_content: Vec<u8> = vec![];
+        loop {
+            let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+            let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+            actual_content.extend_from_slice(data.data());
+            sock_client
+                .send_to(&packet::ACK::new(data.block()).encode(), addr_handler)
+                .unwrap();
+            if data.data().len() < 512 {
+                break;
+            }
+        }
+
+        //
+        // verify
+        //
+        h.join().unwrap().unwrap();
+        assert_eq!(actual_content, b"line\r\n".repeat(120));
+    }
+
+    #[test]
+    fn test_rrq_handler_with_error_from_client() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let handler = create_rrq_handler(base_dir.path().to_owned(), Config::default());
+
+        let test_file_name = "test_rrq_handler.txt";
+        fs::write(base_dir.path().join(test_file_name), [b'a'; 1024]).unwrap();
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let rrq = packet::ReadPacket::new(test_file_name.to_string(), packet::Mode::OCTET);
+
+        let h = thread::spawn(move || handler(sock_handler, addr_client, rrq));
+
+        let mut buf_client = [0; 1024];
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        assert_eq!(
+            packet::Data::parse(&buf_client[..n_client])
+                .unwrap()
+                .block(),
+            1
+        );
+
+        //
+        // exercise
+        //
+        let err = packet::Error::new(TftpError::DiskNoSpace, "Disk full".to_string());
+        sock_client.send_to(&err.encode(), addr_handler).unwrap();
+
+        //
+        // verify
+        //
+        // the handler finishes without waiting for a timeout
+        let started = Instant::now();
+        assert!(h.join().unwrap().is_err());
+        assert!(started.elapsed() < Duration::from_millis(500));
+        // no reply to ERROR
+        sock_client
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        assert!(sock_client.recv_from(&mut buf_client).is_err());
+    }
+
+    #[test]
+    fn test_rrq_handler_with_options() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let handler = create_rrq_handler(base_dir.path().to_owned(), Config::default());
+
+        let test_file_name = "test_rrq_handler.txt";
+        let test_file_content = [b'a'; 513];
+        {
+            // prepare test file
+            let mut test_file = fs::File::create(base_dir.path().join(test_file_name)).unwrap();
+            test_file.write_all(&test_file_content).unwrap();
+        }
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        sock_handler
+            .set_write_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let rrq = packet::ReadPacket::new_with_options(
+            test_file_name.to_string(),
+            packet::Mode::OCTET,
+            vec![("tsize".to_string(), "0".to_string())],
+        );
+
+        let _h = thread::spawn(move || {
+            handler(sock_handler, addr_client, rrq).unwrap();
+        });
+
+        //
+        // exercise and verify
+        //
+        let mut buf_client = [0; 1024];
+        let mut actual_content: Vec<u8> = vec![];
+
+        // OACK comes first instead of data
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let oack = packet::OACK::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(oack.get("tsize"), Some("513"));
+        sock_client
+            .send_to(&packet::ACK::new(0).encode(), addr_handler)
+            .unwrap();
+
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(data.block(), 1);
+        assert_eq!(data.data().len(), 512);
+        actual_content.append(&mut data.data().to_owned());
+        sock_client
+            .send_to(&packet::ACK::new(data.block()).encode(), addr_handler)
+            .unwrap();
+
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(data.block(), 2);
+        assert_eq!(data.data().len(), 1);
+        actual_content.append(&mut data.data().to_owned());
+        sock_client
+            .send_to(&packet::ACK::new(data.block()).encode(), addr_handler)
+            .unwrap();
+
+        assert_eq!(&actual_content, &test_file_content);
+    }
+
+    #[test]
+    fn test_rrq_handler_with_blksize_clamped() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let config = Config {
+            max_datagram_size: 1028,
+            ..Config::default()
+        };
+        let handler = create_rrq_handler(base_dir.path().to_owned(), config);
+
+        let test_file_name = "test_rrq_handler.txt";
+        let test_file_content = [b'a'; 1500];
+        {
+            // prepare test file
+            let mut test_file = fs::File::create(base_dir.path().join(test_file_name)).unwrap();
+            test_file.write_all(&test_file_content).unwrap();
+        }
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        sock_handler
+            .set_write_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let rrq = packet::ReadPacket::new_with_options(
+            test_file_name.to_string(),
+            packet::Mode::OCTET,
+            vec![("blksize".to_string(), "1468".to_string())],
+        );
+
+        let _h = thread::spawn(move || {
+            handler(sock_handler, addr_client, rrq).unwrap();
+        });
+
+        //
+        // exercise and verify
+        //
+        let mut buf_client = [0; 2048];
+        let mut actual_content: Vec<u8> = vec![];
+
+        // blksize is clamped to fit in max_datagram_size
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let oack = packet::OACK::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(oack.get("blksize"), Some("1024"));
+        sock_client
+            .send_to(&packet::ACK::new(0).encode(), addr_handler)
+            .unwrap();
+
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        assert_eq!(n_client, 1028);
+        let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(data.data().len(), 1024);
+        actual_content.append(&mut data.data().to_owned());
+        sock_client
+            .send_to(&packet::ACK::new(data.block()).encode(), addr_handler)
+            .unwrap();
+
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(data.data().len(), 476);
+        actual_content.append(&mut data.data().to_owned());
+        sock_client
+            .send_to(&packet::ACK::new(data.block()).encode(), addr_handler)
+            .unwrap();
+
+        assert_eq!(&actual_content, &test_file_content);
+    }
+
+    #[test]
+    fn test_rrq_handler_with_unknown_option() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let handler = create_rrq_handler(base_dir.path().to_owned(), Config::default());
+
+        let test_file_name = "test_rrq_handler.txt";
+        {
+            // prepare test file
+            let mut test_file = fs::File::create(base_dir.path().join(test_file_name)).unwrap();
+            test_file.write_all(b"hello").unwrap();
+        }
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let rrq = packet::ReadPacket::new_with_options(
+            test_file_name.to_string(),
+            packet::Mode::OCTET,
+            vec![
+                ("unknown".to_string(), "1".to_string()),
+                ("blksize".to_string(), "1024".to_string()),
+            ],
+        );
+
+        let _h = thread::spawn(move || {
+            handler(sock_handler, addr_client, rrq).unwrap();
+        });
+
+        //
+        // exercise and verify
+        //
+        let mut buf_client = [0; 2048];
+
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let oack = packet::OACK::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(
+            oack.options(),
+            &[("blksize".to_string(), "1024".to_string())]
+        );
+        sock_client
+            .send_to(&packet::ACK::new(0).encode(), addr_handler)
+            .unwrap();
+
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(data.data(), b"hello");
+        sock_client
+            .send_to(&packet::ACK::new(data.block()).encode(), addr_handler)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_rrq_handler_with_wrong_block_acks() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let handler = create_rrq_handler(base_dir.path().to_owned(), Config::default());
+
+        let test_file_name = "test_rrq_handler.txt";
+        {
+            // prepare test file
+            let mut test_file = fs::File::create(base_dir.path().join(test_file_name)).unwrap();
+            test_file.write_all(&[b'a'; 513]).unwrap();
+        }
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let rrq = packet::ReadPacket::new(test_file_name.to_string(), packet::Mode::OCTET);
+
+        let (tx, rx) = sync::mpsc::channel();
+        let _h = thread::spawn(move || {
+            tx.send(handler(sock_handler, addr_client, rrq)).unwrap();
+        });
+
+        //
+        // exercise
+        //
+        let mut buf_client = [0; 1024];
+
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(data.block(), 1);
+
+        // the previous block is acceptable, so it doesn't count
+        sock_client
+            .send_to(&packet::ACK::new(0).encode(), addr_handler)
+            .unwrap();
+        for block in [100, 2000, 30000, 400] {
+            sock_client
+                .send_to(&packet::ACK::new(block).encode(), addr_handler)
+                .unwrap();
+        }
+
+        //
+        // verify
+        //
+        // the handler aborts without waiting for timeout
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let err = packet::Error::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(err.error_code(), TftpError::IllegalTftpOp.error_code());
+        let res = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_rrq_handler_with_packet_loss() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let handler = create_rrq_handler(base_dir.path().to_owned(), Config::default());
+        let test_file_name = "test_rrq_handler.txt";
+        let test_file_content: Vec<u8> = (0..(512 * 20 + 100)).map(|i| i as u8).collect();
+        fs::write(base_dir.path().join(test_file_name), &test_file_content).unwrap();
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        // retransmit soon, but long enough compared with the round trip
+        sock_handler
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let transport = FaultyTransport::start(
+            sock_client.local_addr().unwrap(),
+            sock_handler.local_addr().unwrap(),
+            0.3,
+            Duration::from_millis(1),
+            // a seed with which the transfer succeeds within the retry count
+            2,
+        );
+        let addr_transport = transport.addr();
+        let rrq = packet::ReadPacket::new(test_file_name.to_string(), packet::Mode::OCTET);
+
+        let h = thread::spawn(move || handler(sock_handler, addr_transport, rrq));
+
+        //
+        // exercise
+        //
+        // the client sends ACK only in response to DATA, so that the order of packets
+        // (and drops by the transport) doesn't depend on timing
+        let mut buf = [0; 1024];
+        let mut actual_content = vec![];
+        let mut next_block = 1;
+        loop {
+            match sock_client.recv_from(&mut buf) {
+                Ok((n, _)) => {
+                    let data = packet::Data::parse(&buf[..n]).unwrap();
+                    if data.block() == next_block {
+                        actual_content.extend_from_slice(data.data());
+                        next_block += 1;
+                    }
+                    // acknowledge a retransmitted block again because the last ACK may be lost
+                    let ack = packet::ACK::new(data.block());
+                    sock_client.send_to(&ack.encode(), addr_transport).unwrap();
+                }
+                Err(_) if h.is_finished() => break,
+                Err(_) => continue,
+            }
+        }
+
+        //
+        // verify
+        //
+        h.join().unwrap().unwrap();
+        assert_eq!(actual_content, test_file_content);
+        assert!(transport.dropped() > 0);
+    }
+
+    #[test]
+    fn test_rrq_handler_with_windowsize() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let handler = create_rrq_handler(base_dir.path().to_owned(), Config::default());
+
+        let test_file_name = "test_rrq_handler.txt";
+        let test_file_content: Vec<u8> = (0..512 * 6 + 100).map(|i| (i % 251) as u8).collect();
+        fs::write(base_dir.path().join(test_file_name), &test_file_content).unwrap();
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        // the end of a window is detected by this timeout
+        sock_client
+            .set_read_timeout(Some(Duration::from_millis(300)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let rrq = packet::ReadPacket::new_with_options(
+            test_file_name.to_string(),
+            packet::Mode::OCTET,
+            vec![("windowsize".to_string(), "4".to_string())],
+        );
+
+        let h = thread::spawn(move || handler(sock_handler, addr_client, rrq));
+
+        let mut buf_client = [0; 1024];
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let oack = packet::OACK::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(oack.get("windowsize"), Some("4"));
+        sock_client
+            .send_to(&packet::ACK::new(0).encode(), addr_handler)
+            .unwrap();
+
+        // receive DATA until the handler waits for ACK
+        let mut receive_window = || {
+            let mut blocks = vec![];
+            while let Ok((n_client, _)) = sock_client.recv_from(&mut buf_client) {
+                let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+                blocks.push((data.block(), data.data().to_vec()));
+            }
+            blocks
+        };
+
+        //
+        // exercise and verify
+        //
+        // four blocks are sent without waiting for ACK
+        let blocks = receive_window();
+        let numbers: Vec<u16> = blocks.iter().map(|(block, _)| *block).collect();
+        assert_eq!(numbers, [1, 2, 3, 4]);
+        let mut received: Vec<u8> = blocks.into_iter().flat_map(|(_, data)| data).collect();
+
+        // the client lost block 4, so the window restarts from it
+        sock_client
+            .send_to(&packet::ACK::new(3).encode(), addr_handler)
+            .unwrap();
+        received.truncate(512 * 3);
+        let blocks = receive_window();
+        let numbers: Vec<u16> = blocks.iter().map(|(block, _)| *block).collect();
+        assert_eq!(numbers, [4, 5, 6, 7]);
+        received.extend(blocks.into_iter().flat_map(|(_, data)| data));
+        sock_client
+            .send_to(&packet::ACK::new(7).encode(), addr_handler)
+            .unwrap();
+
+        assert_eq!(received, test_file_content);
+        h.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_rrq_handler_with_stale_acks_in_window() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let handler = create_rrq_handler(base_dir.path().to_owned(), Config::default());
+
+        let test_file_name = "test_rrq_handler.txt";
+        let test_file_content: Vec<u8> = (0..512 * 6 + 100).map(|i| (i % 251) as u8).collect();
+        fs::write(base_dir.path().join(test_file_name), &test_file_content).unwrap();
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        // the end of a window is detected by this timeout
+        sock_client
+            .set_read_timeout(Some(Duration::from_millis(300)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let rrq = packet::ReadPacket::new_with_options(
+            test_file_name.to_string(),
+            packet::Mode::OCTET,
+            vec![("windowsize".to_string(), "4".to_string())],
+        );
+
+        let h = thread::spawn(move || handler(sock_handler, addr_client, rrq));
+
+        let mut buf_client = [0; 1024];
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        assert!(packet::OACK::parse(&buf_client[..n_client]).is_ok());
+        sock_client
+            .send_to(&packet::ACK::new(0).encode(), addr_handler)
+            .unwrap();
+
+        // receive DATA until the handler waits for ACK
+        let mut receive_window = || {
+            let mut received = vec![];
+            while let Ok((n_client, _)) = sock_client.recv_from(&mut buf_client) {
+                let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+                received.push(data.block());
+            }
+            received
+        };
+        assert_eq!(receive_window(), [1, 2, 3, 4]);
+        sock_client
+            .send_to(&packet::ACK::new(4).encode(), addr_handler)
+            .unwrap();
+        assert_eq!(receive_window(), [5, 6, 7]);
+
+        //
+        // exercise
+        //
+        // more stale ACKs than the limit of illegal ones arrive late
+        for block in [3, 2, 1, 0] {
+            sock_client
+                .send_to(&packet::ACK::new(block).encode(), addr_handler)
+                .unwrap();
+        }
+
+        //
+        // verify
+        //
+        // they are ignored without ERROR
+        assert!(receive_window().is_empty());
+        sock_client
+            .send_to(&packet::ACK::new(7).encode(), addr_handler)
+            .unwrap();
+        h.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_rrq_handler_with_adaptive_window() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let config = Config {
+            adaptive_window: true,
+            ..Config::default()
+        };
+        let handler = create_rrq_handler(base_dir.path().to_owned(), config);
+
+        let test_file_name = "test_rrq_handler.txt";
+        let test_file_content = [b'a'; 512 * 20];
+        fs::write(base_dir.path().join(test_file_name), test_file_content).unwrap();
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        // the end of a window is detected by this timeout
+        sock_client
+            .set_read_timeout(Some(Duration::from_millis(300)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let rrq = packet::ReadPacket::new_with_options(
+            test_file_name.to_string(),
+            packet::Mode::OCTET,
+            vec![("windowsize".to_string(), "8".to_string())],
+        );
+
+        let h = thread::spawn(move || handler(sock_handler, addr_client, rrq));
+
+        let mut buf_client = [0; 1024];
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let oack = packet::OACK::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(oack.get("windowsize"), Some("8"));
+        sock_client
+            .send_to(&packet::ACK::new(0).encode(), addr_handler)
+            .unwrap();
+
+        // receive DATA until the handler waits for ACK
+        let mut receive_window = || {
+            let mut blocks = vec![];
+            while let Ok((n_client, _)) = sock_client.recv_from(&mut buf_client) {
+                let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+                blocks.push((data.block(), data.data().len()));
+            }
+            blocks
+        };
+        let ack = |block: u16| {
+            sock_client
+                .send_to(&packet::ACK::new(block).encode(), addr_handler)
+                .unwrap();
+        };
+
+        //
+        // exercise and verify
+        //
+        let mut window_sizes = vec![];
+        let last_acked = loop {
+            let blocks = receive_window();
+            window_sizes.push(blocks.len());
+            let (mut last_block, last_len) = *blocks.last().unwrap();
+            if window_sizes.len() == 4 {
+                // the last block in the window is lost
+                last_block -= 1;
+            }
+            ack(last_block);
+            if last_len < 512 {
+                break last_block;
+            }
+        };
+
+        // grows by one block on clean ACKs, then halved on the loss
+        assert_eq!(&window_sizes[..7], &[1, 2, 3, 4, 2, 3, 4]);
+        // the last block is empty because the size of the file is a multiple of 512
+        assert_eq!(last_acked, 21);
+        h.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_rrq_handler_with_adaptive_window_after_loss_in_middle() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let config = Config {
+            adaptive_window: true,
+            ..Config::default()
+        };
+        let handler = create_rrq_handler(base_dir.path().to_owned(), config);
+
+        let test_file_name = "test_rrq_handler.txt";
+        let test_file_content = [b'a'; 512 * 60];
+        fs::write(base_dir.path().join(test_file_name), test_file_content).unwrap();
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        // the end of a window is detected by this timeout
+        sock_client
+            .set_read_timeout(Some(Duration::from_millis(300)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let rrq = packet::ReadPacket::new_with_options(
+            test_file_name.to_string(),
+            packet::Mode::OCTET,
+            vec![("windowsize".to_string(), "8".to_string())],
+        );
+
+        let h = thread::spawn(move || handler(sock_handler, addr_client, rrq));
+
+        let mut buf_client = [0; 1024];
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        packet::OACK::parse(&buf_client[..n_client]).unwrap();
+        sock_client
+            .send_to(&packet::ACK::new(0).encode(), addr_handler)
+            .unwrap();
+
+        // receive DATA until the handler waits for ACK
+        let mut receive_window = || {
+            let mut blocks = vec![];
+            while let Ok((n_client, _)) = sock_client.recv_from(&mut buf_client) {
+                let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+                blocks.push((data.block(), data.data().len()));
+            }
+            blocks
+        };
+        let ack = |block: u16| {
+            sock_client
+                .send_to(&packet::ACK::new(block).encode(), addr_handler)
+                .unwrap();
+        };
+
+        //
+        // exercise and verify
+        //
+        let mut window_sizes = vec![];
+        loop {
+            let blocks = receive_window();
+            window_sizes.push(blocks.len());
+            let (mut last_block, last_len) = *blocks.last().unwrap();
+            if window_sizes.len() == 8 {
+                // the fourth block in the full window is lost, so the rest are discarded
+                last_block = blocks[2].0;
+            }
+            ack(last_block);
+            if last_len < 512 {
+                break;
+            }
+        }
+
+        // halved once on the loss, and grows again while sending the blocks left over
+        assert_eq!(&window_sizes[..10], &[1, 2, 3, 4, 5, 6, 7, 8, 4, 5]);
+        h.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_rrq_handler_with_512_multiple_bytes() {
+        env_logger::init();
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let handler = create_rrq_handler(base_dir.path().to_owned(), Config::default());
+
+        let test_file_name = "test_wrq_handler.txt";
+        let test_file_content = [b'a'; 1024];
+        {
+            // prepare test file
+            let mut test_file = fs::File::create(base_dir.path().join(test_file_name)).unwrap();
+            test_file.write_all(&test_file_content).unwrap();
+        }
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        sock_handler
+            .set_write_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let rrq = packet::ReadPacket::new(test_file_name.to_string(), packet::Mode::OCTET);
+
+        let _h = thread::spawn(move || {
+            handler(sock_handler, addr_client, rrq).unwrap();
+        });
+
+        //
+        // exercise and verify
+        //
+        let mut buf_client = [0; 1024];
+        let mut actual_content: Vec<u8> = vec![];
+
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(data.data().len(), 512);
+        actual_content.append(&mut data.data().to_owned());
+        sock_client
+            .send_to(&packet::ACK::new(data.block()).encode(), addr_handler)
+            .unwrap();
+
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(data.data().len(), 512);
+        actual_content.append(&mut data.data().to_owned());
+        sock_client
+            .send_to(&packet::ACK::new(data.block()).encode(), addr_handler)
+            .unwrap();
+
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(data.data().len(), 0);
+        actual_content.append(&mut data.data().to_owned());
+        sock_client
+            .send_to(&packet::ACK::new(data.block()).encode(), addr_handler)
+            .unwrap();
+
+        assert_eq!(&actual_content, &test_file_content);
+    }
+
+    #[test]
+    fn test_rrq_handler_with_blksize_multiple_bytes() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let handler = create_rrq_handler(base_dir.path().to_owned(), Config::default());
+
+        let test_file_name = "test_rrq_handler.txt";
+        let test_file_content = [b'a'; 1000];
+        fs::write(base_dir.path().join(test_file_name), test_file_content).unwrap();
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        // the file size is not a multiple of 512 but equal to blksize
+        let rrq = packet::ReadPacket::new_with_options(
+            test_file_name.to_string(),
+            packet::Mode::OCTET,
+            vec![("blksize".to_string(), "1000".to_string())],
+        );
+
+        let h = thread::spawn(move || handler(sock_handler, addr_client, rrq));
+
+        //
+        // exercise and verify
+        //
+        let mut buf_client = [0; 2048];
+
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let oack = packet::OACK::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(oack.get("blksize"), Some("1000"));
+        sock_client
+            .send_to(&packet::ACK::new(0).encode(), addr_handler)
+            .unwrap();
+
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(data.block(), 1);
+        assert_eq!(data.data(), &test_file_content);
+        sock_client
+            .send_to(&packet::ACK::new(1).encode(), addr_handler)
+            .unwrap();
+
+        // the trailing empty block signals the end of the transfer
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(data.block(), 2);
+        assert_eq!(data.data().len(), 0);
+        sock_client
+            .send_to(&packet::ACK::new(2).encode(), addr_handler)
+            .unwrap();
+
+        h.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_rrq_handler_with_large_sparse_file() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let handler = create_rrq_handler(base_dir.path().to_owned(), Config::default());
+
+        // a sparse file with more blocks than 65535, so that the block number rolls over
+        let test_file_name = "test_rrq_handler.img";
+        let test_file_path = base_dir.path().join(test_file_name);
+        let fs_file = fs::File::create(&test_file_path).unwrap();
+        fs_file.set_len(512 * 65536 + 100).unwrap();
+        // mark blocks around the rollover to detect misplaced data
+        fs_file.write_all_at(b"before", 512 * 65534).unwrap();
+        fs_file.write_all_at(b"after", 512 * 65536).unwrap();
+        let test_file_content = fs::read(&test_file_path).unwrap();
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        let rrq = packet::ReadPacket::new(test_file_name.to_string(), packet::Mode::OCTET);
+
+        let h = thread::spawn(move || handler(sock_handler, addr_client, rrq));
+
+        //
+        // exercise
+        //
+        let mut buf_client = [0; 1024];
+        let mut received = Vec::with_capacity(test_file_content.len());
+        let mut blocks = 0_u32;
+        loop {
+            let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+            let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+            blocks += 1;
+            assert_eq!(data.block(), blocks as u16);
+            received.extend_from_slice(data.data());
+            let ack = packet::ACK::new(data.block());
+            sock_client.send_to(&ack.encode(), addr_handler).unwrap();
+            if data.data().len() < 512 {
+                break;
+            }
+        }
+
+        //
+        // verify
+        //
+        h.join().unwrap().unwrap();
+        assert_eq!(blocks, 65537);
+        assert!(received == test_file_content);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_rrq_handler_with_direct_io() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let config = Config {
+            direct_io: true,
+            ..Config::default()
+        };
+        let handler = create_rrq_handler(base_dir.path().to_owned(), config);
+
+        // larger than a chunk of direct IO and not aligned to it
+        let test_file_name = "test_rrq_handler.img";
+        let test_file_content: Vec<u8> = (0..600_000_u32).map(|i| (i % 251) as u8).collect();
+        fs::write(base_dir.path().join(test_file_name), &test_file_content).unwrap();
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let rrq = packet::ReadPacket::new_with_options(
+            test_file_name.to_string(),
+            packet::Mode::OCTET,
+            vec![("blksize".to_string(), "1400".to_string())],
+        );
+
+        let h = thread::spawn(move || handler(sock_handler, addr_client, rrq));
+
+        //
+        // exercise
+        //
+        let mut buf_client = [0; 2048];
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        packet::OACK::parse(&buf_client[..n_client]).unwrap();
+        sock_client
+            .send_to(&packet::ACK::new(0).encode(), addr_handler)
+            .unwrap();
+
+        let mut received = vec![];
+        loop {
+            let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+            let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+            received.extend_from_slice(data.data());
+            sock_client
+                .send_to(&packet::ACK::new(data.block()).encode(), addr_handler)
+                .unwrap();
+            if data.data().len() < 1400 {
+                break;
+            }
+        }
+
+        //
+        // verify
+        //
+        h.join().unwrap().unwrap();
+        assert!(received == test_file_content);
+    }
+
+    #[test]
+    fn test_rrq_handler_with_startblock() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let config = Config {
+            partial_get: true,
+            ..Config::default()
+        };
+        let handler = create_rrq_handler(base_dir.path().to_owned(), config);
+
+        let test_file_name = "test_rrq_handler.txt";
+        let test_file_content: Vec<u8> = (0..2000_u32).map(|i| (i % 251) as u8).collect();
+        fs::write(base_dir.path().join(test_file_name), &test_file_content).unwrap();
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let rrq = packet::ReadPacket::new_with_options(
+            test_file_name.to_string(),
+            packet::Mode::OCTET,
+            vec![("startblock".to_string(), "3".to_string())],
+        );
+
+        let h = thread::spawn(move || handler(sock_handler, addr_client, rrq));
+
+        //
+        // exercise and verify
+        //
+        let mut buf_client = [0; 1024];
+
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let oack = packet::OACK::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(oack.get("startblock"), Some("3"));
+        sock_client
+            .send_to(&packet::ACK::new(0).encode(), addr_handler)
+            .unwrap();
+
+        // the data starts from the offset of block 3
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(data.block(), 3);
+        assert_eq!(data.data(), &test_file_content[1024..1536]);
+        sock_client
+            .send_to(&packet::ACK::new(3).encode(), addr_handler)
+            .unwrap();
+
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(data.block(), 4);
+        assert_eq!(data.data(), &test_file_content[1536..]);
+        sock_client
+            .send_to(&packet::ACK::new(4).encode(), addr_handler)
+            .unwrap();
+
+        h.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_rrq_handler_with_blksize_and_small_file() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let handler = create_rrq_handler(base_dir.path().to_owned(), Config::default());
+
+        let test_file_name = "test_rrq_handler.txt";
+        // larger than the default blksize but smaller than the negotiated one
+        let test_file_content = [b'a'; 700];
+        fs::write(base_dir.path().join(test_file_name), test_file_content).unwrap();
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let rrq = packet::ReadPacket::new_with_options(
+            test_file_name.to_string(),
+            packet::Mode::OCTET,
+            vec![("blksize".to_string(), "1024".to_string())],
+        );
+
+        let h = thread::spawn(move || handler(sock_handler, addr_client, rrq));
+
+        //
+        // exercise and verify
+        //
+        let mut buf_client = [0; 2048];
+
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let oack = packet::OACK::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(oack.get("blksize"), Some("1024"));
+        sock_client
+            .send_to(&packet::ACK::new(0).encode(), addr_handler)
+            .unwrap();
+
+        // the whole file in a single block
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(data.block(), 1);
+        assert_eq!(data.data(), &test_file_content);
+        sock_client
+            .send_to(&packet::ACK::new(1).encode(), addr_handler)
+            .unwrap();
+
+        h.join().unwrap().unwrap();
+        assert!(sock_client.recv_from(&mut buf_client).is_err());
+    }
+
+    #[test]
+    fn test_rrq_handler_with_startblock_beyond_data() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        // `gzip` of "hello hello hello hello\n", whose trailer claims 1024 bytes instead of 24
+        let gz_content = [
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xcb, 0x48, 0xcd, 0xc9,
+            0xc9, 0x57, 0xc8, 0x40, 0x27, 0xb9, 0x00, 0x00, 0x88, 0x59, 0x0b, 0x00, 0x04, 0x00,
+            0x00,
+        ];
+        fs::write(base_dir.path().join("foo.gz"), gz_content).unwrap();
+        let config = Config {
+            serve_gzip: true,
+            partial_get: true,
+            ..Config::default()
+        };
+        let handler = create_rrq_handler(base_dir.path().to_owned(), config);
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let rrq = packet::ReadPacket::new_with_options(
+            "foo".to_string(),
+            packet::Mode::OCTET,
+            vec![("startblock".to_string(), "2".to_string())],
+        );
+
+        //
+        // exercise
+        //
+        let h = thread::spawn(move || handler(sock_handler, addr_client, rrq));
+
+        //
+        // verify
+        //
+        // the client is notified instead of waiting for a timeout
+        let mut buf_client = [0; 1024];
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let err = packet::Error::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(err.error_code(), TftpError::Others.error_code());
+        assert!(h.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_rrq_handler_with_gzip() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        // `gzip` of "hello hello hello hello\n"
+        let gz_content = [
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xcb, 0x48, 0xcd, 0xc9,
+            0xc9, 0x57, 0xc8, 0x40, 0x27, 0xb9, 0x00, 0x00, 0x88, 0x59, 0x0b, 0x18, 0x00, 0x00,
+            0x00,
+        ];
+        fs::write(base_dir.path().join("foo.gz"), gz_content).unwrap();
+        let config = Config {
+            serve_gzip: true,
+            ..Config::default()
+        };
+        let handler = create_rrq_handler(base_dir.path().to_owned(), config);
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        // foo doesn't exist
+        let rrq = packet::ReadPacket::new_with_options(
+            "foo".to_string(),
+            packet::Mode::OCTET,
+            vec![("tsize".to_string(), "0".to_string())],
+        );
+        let h = thread::spawn(move || handler(sock_handler, addr_client, rrq));
+
+        //
+        // exercise and verify
+        //
+        let mut buf_client = [0; 1024];
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let oack = packet::OACK::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(oack.get("tsize"), Some("24"));
+        let ack = packet::ACK::new(0);
+        sock_client.send_to(&ack.encode(), addr_handler).unwrap();
+
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(data.block(), 1);
+        assert_eq!(data.data(), b"hello hello hello hello\n");
+        let ack = packet::ACK::new(1);
+        sock_client.send_to(&ack.encode(), addr_handler).unwrap();
+
+        h.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_rrq_handler_with_silent_miss() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let config = Config {
+            silent_miss_patterns: vec!["pxelinux.cfg/*".to_string()],
+            ..Config::default()
+        };
+        let handler = Arc::new(create_rrq_handler(base_dir.path().to_owned(), config));
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let mut buf_client = [0; 1024];
+        let request = |filename: &str| {
+            let handler = Arc::clone(&handler);
+            let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+            let rrq = packet::ReadPacket::new(filename.to_string(), packet::Mode::OCTET);
+            thread::spawn(move || handler(sock_handler, addr_client, rrq))
+        };
+
+        //
+        // exercise and verify
+        //
+        // a miss matching the pattern
+        let h = request("pxelinux.cfg/01-aa-bb-cc-dd-ee-ff");
+        assert!(h.join().unwrap().is_ok());
+        assert!(sock_client.recv_from(&mut buf_client).is_err());
+
+        // other misses
+        let h = request("missing.txt");
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let err = packet::Error::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(err.error_code(), TftpError::FileNotFound.error_code());
+        assert!(h.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_rrq_handler_with_octet_only() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        fs::write(base_dir.path().join("foo.txt"), b"foo\n").unwrap();
+        let config = Config {
+            octet_only: true,
+            ..Config::default()
+        };
+        let handler = Arc::new(create_rrq_handler(base_dir.path().to_owned(), config));
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut buf_client = [0; 1024];
+        let request = |mode: Mode| {
+            let handler = Arc::clone(&handler);
+            let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+            let addr_handler = sock_handler.local_addr().unwrap();
+            let rrq = packet::ReadPacket::new("foo.txt".to_string(), mode);
+            let h = thread::spawn(move || handler(sock_handler, addr_client, rrq));
+            (h, addr_handler)
+        };
+
+        //
+        // exercise and verify
+        //
+        let (h, _) = request(Mode::NETASCII);
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let err = packet::Error::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(err.error_code(), TftpError::IllegalTftpOp.error_code());
+        assert!(h.join().unwrap().is_err());
+
+        let (h, addr_handler) = request(Mode::OCTET);
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(data.data(), b"foo\n");
+        let ack = packet::ACK::new(1);
+        sock_client.send_to(&ack.encode(), addr_handler).unwrap();
+        h.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_rrq_handler_with_filename_map() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        fs::write(base_dir.path().join("default.cfg"), b"default").unwrap();
+        let config = Config {
+            filename_map: FilenameMap::default().with("pxelinux.cfg/default", "default.cfg"),
+            ..Config::default()
+        };
+        let handler = create_rrq_handler(base_dir.path().to_owned(), config);
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        let rrq = packet::ReadPacket::new("pxelinux.cfg/default".to_string(), Mode::OCTET);
+
+        let h = thread::spawn(move || handler(sock_handler, addr_client, rrq));
+
+        //
+        // exercise and verify
+        //
+        let mut buf_client = [0; 1024];
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(data.block(), 1);
+        assert_eq!(data.data(), b"default");
+        let ack = packet::ACK::new(1);
+        sock_client.send_to(&ack.encode(), addr_handler).unwrap();
+
+        h.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_rrq_handler_with_filename_map_to_subdirectory() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        fs::create_dir(base_dir.path().join("pxelinux.cfg")).unwrap();
+        fs::write(base_dir.path().join("pxelinux.cfg/default"), b"default").unwrap();
+        let config = Config {
+            filename_map: FilenameMap::default()
+                .with("config", "pxelinux.cfg/default")
+                .with("escape", "../default"),
+            ..Config::default()
+        };
+        let handler = Arc::new(create_rrq_handler(base_dir.path().to_owned(), config));
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut buf_client = [0; 1024];
+        let request = |filename: &str| {
+            let handler = Arc::clone(&handler);
+            let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+            let addr_handler = sock_handler.local_addr().unwrap();
+            let rrq = packet::ReadPacket::new(filename.to_string(), Mode::OCTET);
+            let h = thread::spawn(move || handler(sock_handler, addr_client, rrq));
+            (h, addr_handler)
+        };
+
+        //
+        // exercise and verify
+        //
+        let (h, addr_handler) = request("config");
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(data.block(), 1);
+        assert_eq!(data.data(), b"default");
+        let ack = packet::ACK::new(1);
+        sock_client.send_to(&ack.encode(), addr_handler).unwrap();
+        h.join().unwrap().unwrap();
+
+        let (h, _) = request("escape");
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let err = packet::Error::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(err.error_code(), TftpError::AccessViolation.error_code());
+        assert!(h.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_rrq_handler_with_error() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let handler = create_rrq_handler(base_dir.path().to_owned(), Config::default());
+
+        // this file doesn't exist, which should cause TftpError::FileNotFound
+        let test_file_name = "test_wrq_handler.txt";
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        sock_client
+            .set_write_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        sock_handler
+            .set_write_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        let rrq = packet::ReadPacket::new(test_file_name.to_string(), packet::Mode::OCTET);
+
+        let h = thread::spawn(move || handler(sock_handler, addr_client, rrq));
+
+        //
+        // exercise and verify
+        //
+        let mut buf_client = [0; 1024];
+
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let err_pkt = packet::Error::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(err_pkt.error_code(), TftpError::FileNotFound.error_code());
+        assert_eq!(err_pkt.message(), "File not found");
+        assert!(matches!(
+            h.join().unwrap(),
+            Err(Error::Io(err)) if err.kind() == ErrorKind::NotFound
+        ));
+    }
+
+    #[test]
+    fn test_rrq_handler_with_timeout() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let test_file_name = "test_rrq_handler.txt";
+        fs::write(base_dir.path().join(test_file_name), b"hello").unwrap();
+        let handler = create_rrq_handler(base_dir.path().to_owned(), Config::default());
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+
+        let rrq = packet::ReadPacket::new(test_file_name.to_string(), packet::Mode::OCTET);
+
+        //
+        // exercise
+        //
+        // the client never sends ACK
+        let result = handler(sock_handler, addr_client, rrq);
+
+        //
+        // verify
+        //
+        assert!(matches!(result, Err(Error::Timeout { retries: 4 })));
+        assert_eq!(
+            log_transfer_result("RRQ", &addr_client, &result),
+            Some(Level::Warn)
+        );
+        assert_eq!(result.unwrap_err().to_string(), "timeout after 4 retries");
+
+        // other failures are still logged as errors
+        let result = Err(Error::Io(std::io::Error::from(ErrorKind::NotFound)));
+        assert_eq!(
+            log_transfer_result("RRQ", &addr_client, &result),
+            Some(Level::Error)
+        );
+        assert_eq!(log_transfer_result("RRQ", &addr_client, &Ok(())), None);
+    }
+
+    #[test]
+    fn test_rrq_handler_with_timeout_option() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let test_file_name = "test_rrq_handler.txt";
+        fs::write(base_dir.path().join(test_file_name), b"hello").unwrap();
+        let handler = create_rrq_handler(base_dir.path().to_owned(), Config::default());
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(3)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let rrq = packet::ReadPacket::new_with_options(
+            test_file_name.to_string(),
+            packet::Mode::OCTET,
+            vec![("timeout".to_string(), "1".to_string())],
+        );
+
+        let h = thread::spawn(move || handler(sock_handler, addr_client, rrq));
+
+        //
+        // exercise
+        //
+        let mut buf_client = [0; 1024];
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let oack = packet::OACK::parse(&buf_client[..n_client]).unwrap();
+        let started = Instant::now();
+        // OACK is lost
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let elapsed = started.elapsed();
+
+        //
+        // verify
+        //
+        assert_eq!(oack.get("timeout"), Some("1"));
+        assert_eq!(packet::OACK::parse(&buf_client[..n_client]).unwrap(), oack);
+        // retransmitted after the negotiated timeout instead of 50ms of the socket
+        assert!(elapsed >= Duration::from_millis(900), "{:?}", elapsed);
+
+        let ack = packet::ACK::new(0);
+        sock_client.send_to(&ack.encode(), addr_handler).unwrap();
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(data.data(), b"hello");
+        let ack = packet::ACK::new(1);
+        sock_client.send_to(&ack.encode(), addr_handler).unwrap();
+        h.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_rrq_handler_without_last_ack() {
+        let base_dir = temp::create_temp_dir().unwrap();
+        let test_file_name = "test_rrq_handler.txt";
+        fs::write(base_dir.path().join(test_file_name), [b'a'; 700]).unwrap();
+
+        for (policy, succeeded) in [
+            (FinalAckPolicy::Required, false),
+            (FinalAckPolicy::Optional, true),
+        ] {
+            //
+            // setup
+            //
+            let config = Config {
+                final_ack_policy: policy,
+                ..Config::default()
+            };
+            let handler = create_rrq_handler(base_dir.path().to_owned(), config);
+
+            let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+            let addr_client = sock_client.local_addr().unwrap();
+            sock_client
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+            let addr_handler = sock_handler.local_addr().unwrap();
+            sock_handler
+                .set_read_timeout(Some(Duration::from_millis(50)))
+                .unwrap();
+            let rrq = packet::ReadPacket::new(test_file_name.to_string(), packet::Mode::OCTET);
+
+            let h = thread::spawn(move || handler(sock_handler, addr_client, rrq));
+
+            //
+            // exercise
+            //
+            let mut buf_client = [0; 1024];
+            let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+            assert_eq!(
+                packet::Data::parse(&buf_client[..n_client])
+                    .unwrap()
+                    .block(),
+                1
+            );
+            let ack = packet::ACK::new(1);
+            sock_client.send_to(&ack.encode(), addr_handler).unwrap();
+
+            //
+            // verify
+            //
+            // the last block is sent and retransmitted 4 times, but never acknowledged
+            for _ in 0..5 {
+                let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+                let data = packet::Data::parse(&buf_client[..n_client]).unwrap();
+                assert_eq!(data.block(), 2);
+                assert_eq!(data.data().len(), 188);
+            }
+            let result = h.join().unwrap();
+            assert_eq!(result.is_ok(), succeeded, "{:?}: {:?}", policy, result);
+        }
+    }
+
+    #[test]
+    fn test_rrq_handler_with_tiny_timeout() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let test_file_name = "test_rrq_handler.txt";
+        fs::write(base_dir.path().join(test_file_name), b"hello").unwrap();
+        let handler = create_rrq_handler(base_dir.path().to_owned(), Config::default());
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_nanos(1)))
+            .unwrap();
+
+        let rrq = packet::ReadPacket::new(test_file_name.to_string(), packet::Mode::OCTET);
+
+        //
+        // exercise
+        //
+        // the client never sends ACK
+        let started = Instant::now();
+        let result = handler(sock_handler, addr_client, rrq);
+
+        //
+        // verify
+        //
+        // each of 4 retransmissions and the final give-up waits for the clamped interval
+        assert!(matches!(result, Err(Error::Timeout { retries: 4 })));
+        assert!(started.elapsed() >= MIN_RETRY_INTERVAL * 5);
+    }
+
+    #[test]
+    fn test_retry_timer_display() {
+        let sock = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        assert_eq!(
+            RetryTimer::new(&sock).unwrap().to_string(),
+            "none (the socket blocks forever)"
+        );
+
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(
+            RetryTimer::new(&sock).unwrap().to_string(),
+            "5s (the socket read timeout)"
+        );
+
+        sock.set_read_timeout(Some(Duration::from_millis(1)))
+            .unwrap();
+        // the timeout may be rounded up by the kernel
+        assert!(RetryTimer::new(&sock)
+            .unwrap()
+            .to_string()
+            .starts_with("10ms (raised from the socket read timeout "));
+    }
+
+    #[test]
+    fn test_rrq_handler_with_stray_packets_before_deadline() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let test_file_name = "test_rrq_handler.txt";
+        fs::write(base_dir.path().join(test_file_name), b"hello").unwrap();
+        let handler = create_rrq_handler(base_dir.path().to_owned(), Config::default());
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_millis(300)))
+            .unwrap();
+
+        let rrq = packet::ReadPacket::new(test_file_name.to_string(), packet::Mode::OCTET);
+
+        //
+        // exercise
+        //
+        let h = thread::spawn(move || handler(sock_handler, addr_client, rrq));
+
+        // send a duplicated ACK every 50ms instead of acknowledging DATA 1,
+        // which would postpone the retransmission forever if each recv had its own timeout
+        let mut buf = [0; 1024];
+        let mut received_at = vec![];
+        let started = Instant::now();
+        while received_at.len() < 2 && started.elapsed() < Duration::from_secs(3) {
+            if let Ok((n, _)) = sock_client.recv_from(&mut buf) {
+                let data = packet::Data::parse(&buf[..n]).unwrap();
+                assert_eq!(data.block(), 1);
+                received_at.push(Instant::now());
+            }
+            sock_client
+                .send_to(&packet::ACK::new(0).encode(), addr_handler)
+                .unwrap();
+        }
+        sock_client
+            .send_to(&packet::ACK::new(1).encode(), addr_handler)
+            .unwrap();
+
+        //
+        // verify
+        //
+        assert_eq!(received_at.len(), 2);
+        let interval = received_at[1] - received_at[0];
+        assert!(
+            interval >= Duration::from_millis(250) && interval < Duration::from_millis(450),
+            "retransmitted after {:?}",
+            interval
+        );
+        h.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_rrq_handler_with_custom_error_message() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let config = Config {
+            error_messages: ErrorMessages::default().with(TftpError::FileNotFound, "No such file"),
+            ..Config::default()
+        };
+        let handler = create_rrq_handler(base_dir.path().to_owned(), config);
+
+        // this file doesn't exist, which should cause TftpError::FileNotFound
+        let test_file_name = "test_rrq_handler.txt";
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+
+        let rrq = packet::ReadPacket::new(test_file_name.to_string(), packet::Mode::OCTET);
+
+        let _h = thread::spawn(move || {
+            handler(sock_handler, addr_client, rrq).unwrap_err();
+        });
+
+        //
+        // exercise and verify
+        //
+        let mut buf_client = [0; 1024];
+
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let err_pkt = packet::Error::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(err_pkt.error_code(), TftpError::FileNotFound.error_code());
+        assert_eq!(err_pkt.message(), "No such file");
+    }
+
+    #[test]
+    fn test_rrq_and_wrq_handler_with_fifo() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        let test_file_name = "fifo";
+        nix::unistd::mkfifo(
+            &base_dir.path().join(test_file_name),
+            nix::sys::stat::Mode::S_IRWXU,
+        )
+        .unwrap();
+        let rrq_handler = create_rrq_handler(base_dir.path().to_owned(), Config::default());
+        let wrq_handler = create_wrq_handler(
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config::default(),
+        );
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        //
+        // exercise and verify
+        //
+        // the handlers reply immediately without blocking on the fifo
+        let rrq = packet::ReadPacket::new(test_file_name.to_string(), Mode::OCTET);
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let _h = thread::spawn(move || rrq_handler(sock_handler, addr_client, rrq));
+        let mut buf_client = [0; 1024];
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let err_pkt = packet::Error::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(
+            err_pkt.error_code(),
+            TftpError::AccessViolation.error_code()
+        );
+
+        let wrq = packet::WritePacket::new(test_file_name.to_string(), Mode::OCTET);
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let _h = thread::spawn(move || wrq_handler(sock_handler, addr_client, wrq));
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let err_pkt = packet::Error::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(
+            err_pkt.error_code(),
+            TftpError::AccessViolation.error_code()
+        );
+    }
+
+    #[test]
+    fn test_rrq_handler_with_unreadable_file() {
+        if !nix::unistd::Uid::effective().is_root() {
+            // this test requires root to drop privilege (root can read any file)
+            return;
+        }
+
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        crate::privilege::chmod(base_dir.path(), 0o755).unwrap();
+        let test_file_name = "test_rrq_handler.txt";
+        let file_path = base_dir.path().join(test_file_name);
+        fs::write(&file_path, b"secret").unwrap();
+        crate::privilege::chmod(&file_path, 0o000).unwrap();
+        let handler = create_rrq_handler(base_dir.path().to_owned(), Config::default());
+        let user = nix::unistd::User::from_name("nobody").unwrap().unwrap();
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let rrq = packet::ReadPacket::new(test_file_name.to_string(), Mode::OCTET);
+
+        //
+        // exercise
+        //
+        // drop privilege in a child process not to affect other tests
+        let child = match unsafe { nix::unistd::fork() }.unwrap() {
+            nix::unistd::ForkResult::Child => {
+                let code = (|| {
+                    nix::unistd::setgid(user.gid).ok()?;
+                    nix::unistd::setuid(user.uid).ok()?;
+                    handler(sock_handler, addr_client, rrq).err().map(|_| 0)
+                })()
+                .unwrap_or(1);
+                unsafe { nix::libc::_exit(code) }
+            }
+            nix::unistd::ForkResult::Parent { child } => child,
+        };
+
+        //
+        // verify
+        //
+        let mut buf_client = [0; 1024];
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let status = nix::sys::wait::waitpid(child, None).unwrap();
+        assert!(matches!(status, nix::sys::wait::WaitStatus::Exited(_, 0)));
+        assert_eq!(&buf_client[..2], &[0x00, 0x05]);
+        let err_pkt = packet::Error::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(
+            err_pkt.error_code(),
+            TftpError::AccessViolation.error_code()
+        );
+    }
+
+    #[test]
+    fn test_wrq_handler() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        let test_file_name = "test_wrq_handler.txt";
+        let handler = create_wrq_handler(
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config::default(),
+        );
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        sock_handler
+            .set_write_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let wrq = packet::WritePacket::new(test_file_name.to_string(), packet::Mode::OCTET);
+
+        let barrier_client = Arc::new(sync::Barrier::new(2));
+        let barrier_handler = Arc::clone(&barrier_client);
+        let _h = thread::spawn(move || {
+            handler(sock_handler, addr_client, wrq).unwrap();
+            barrier_handler.wait();
+        });
+
+        //
+        // exercise and verify
+        //
+        let mut buf_client = [0; 1024];
+        let content = [b'a'; 513];
+
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let ack = packet::ACK::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(ack.block(), 0);
+
+        let data = packet::Data::new(1, &content[..512]);
+        sock_client.send_to(&data.encode(), addr_handler).unwrap();
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let ack = packet::ACK::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(ack.block(), 1);
+
+        let data = packet::Data::new(2, &content[512..]);
+        sock_client.send_to(&data.encode(), addr_handler).unwrap();
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let ack = packet::ACK::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(ack.block(), 2);
+
+        barrier_client.wait();
+        let mut file = fs::File::open(base_dir.path().join(test_file_name)).unwrap();
+        let mut actual_content = vec![];
+        file.read_to_end(&mut actual_content).unwrap();
+        assert_eq!(&actual_content, &content);
+    }
+
+    #[test]
+    fn test_wrq_handler_with_netascii_across_blocks() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        let test_file_name = "test_wrq_handler.txt";
+        let handler = create_wrq_handler(
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config::default(),
+        );
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let wrq = packet::WritePacket::new(test_file_name.to_string(), packet::Mode::NETASCII);
+
+        let h = thread::spawn(move || handler(sock_handler, addr_client, wrq));
+
+        // CR LF and CR NUL are split at the boundaries of blocks
+        let block1 = [&[b'a'; 511][..], b"\r"].concat();
+        let block2 = [&b"\n"[..], &[b'b'; 510], b"\r"].concat();
+        let block3 = b"\0c".to_vec();
+
+        //
+        // exercise
+        //
+        let mut buf_client = [0; 1024];
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        assert_eq!(
+            packet::ACK::parse(&buf_client[..n_client]).unwrap().block(),
+            0
+        );
+        for (block, data) in [block1, block2, block3].iter().enumerate() {
+            let block = block as u16 + 1;
+            let data = packet::Data::new(block, data);
+            sock_client.send_to(&data.encode(), addr_handler).unwrap();
+            let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+            assert_eq!(
+                packet::ACK::parse(&buf_client[..n_client]).unwrap().block(),
+                block
+            );
+        }
+
+        //
+        // verify
+        //
+        h.join().unwrap().unwrap();
+        let expected = [&[b'a'; 511][..], b"\n", &[b'b'; 510], b"\rc"].concat();
+        assert_eq!(
+            fs::read(base_dir.path().join(test_file_name)).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_wrq_handler_with_malformed_netascii() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        let test_file_name = "test_wrq_handler.txt";
+        let handler = create_wrq_handler(
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config::default(),
+        );
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let wrq = packet::WritePacket::new(test_file_name.to_string(), packet::Mode::NETASCII);
+
+        let h = thread::spawn(move || handler(sock_handler, addr_client, wrq));
+
+        //
+        // exercise
+        //
+        let mut buf_client = [0; 1024];
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        assert_eq!(
+            packet::ACK::parse(&buf_client[..n_client]).unwrap().block(),
+            0
+        );
+        let data = packet::Data::new(1, b"a\rb");
+        sock_client.send_to(&data.encode(), addr_handler).unwrap();
+
+        //
+        // verify
+        //
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let err = packet::Error::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(err.error_code(), TftpError::IllegalTftpOp.error_code());
+        assert!(h.join().unwrap().is_err());
+        assert!(!base_dir.path().join(test_file_name).exists());
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_wrq_handler_with_data_before_ack0() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        let test_file_name = "test_wrq_handler.txt";
+        let handler = create_wrq_handler(
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config::default(),
+        );
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let wrq = packet::WritePacket::new(test_file_name.to_string(), packet::Mode::OCTET);
+        let content = [b'a'; 100];
+
+        //
+        // exercise
+        //
+        // DATA of block 1 is queued before the handler sends ACK of block 0
+        let data = packet::Data::new(1, &content);
+        sock_client.send_to(&data.encode(), addr_handler).unwrap();
+        let h = thread::spawn(move || handler(sock_handler, addr_client, wrq));
+
+        //
+        // verify
+        //
+        let mut buf_client = [0; 1024];
+        for expected in [0, 1] {
+            let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+            let ack = packet::ACK::parse(&buf_client[..n_client]).unwrap();
+            assert_eq!(ack.block(), expected);
+        }
+        h.join().unwrap().unwrap();
+        let actual_content = fs::read(base_dir.path().join(test_file_name)).unwrap();
+        assert_eq!(&actual_content, &content);
+    }
+
+    #[test]
+    fn test_wrq_handler_with_duplicate_data() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        let test_file_name = "test_wrq_handler.txt";
+        let handler = create_wrq_handler(
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config::default(),
+        );
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        let wrq = packet::WritePacket::new(test_file_name.to_string(), packet::Mode::OCTET);
+
+        let h = thread::spawn(move || handler(sock_handler, addr_client, wrq));
+
+        //
+        // exercise
+        //
+        let mut buf_client = [0; 1024];
+        let mut recv_ack = || {
+            let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+            packet::ACK::parse(&buf_client[..n_client]).unwrap().block()
+        };
+        assert_eq!(recv_ack(), 0);
+        // block 1 is sent twice, and block 3 comes ahead of block 2
+        for (block, content) in [(1, [b'a'; 512]), (1, [b'a'; 512]), (3, [b'c'; 512])] {
+            let data = packet::Data::new(block, &content);
+            sock_client.send_to(&data.encode(), addr_handler).unwrap();
+            assert_eq!(recv_ack(), 1);
+        }
+        let data = packet::Data::new(2, b"b");
+        sock_client.send_to(&data.encode(), addr_handler).unwrap();
+        assert_eq!(recv_ack(), 2);
+
+        //
+        // verify
+        //
+        h.join().unwrap().unwrap();
+        let mut expected = vec![b'a'; 512];
+        expected.push(b'b');
+        assert_eq!(
+            fs::read(base_dir.path().join(test_file_name)).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_wrq_handler_with_empty_file() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        let test_file_name = "test_wrq_handler.txt";
+        let handler = create_wrq_handler(
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config::default(),
+        );
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let wrq = packet::WritePacket::new(test_file_name.to_string(), packet::Mode::OCTET);
+
+        //
+        // exercise
+        //
+        let h = thread::spawn(move || handler(sock_handler, addr_client, wrq));
+        let mut buf_client = [0; 1024];
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        assert_eq!(
+            packet::ACK::parse(&buf_client[..n_client]).unwrap().block(),
+            0
+        );
+        // an empty file is sent as a single empty block
+        let data = packet::Data::new(1, &[]);
+        sock_client.send_to(&data.encode(), addr_handler).unwrap();
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        assert_eq!(
+            packet::ACK::parse(&buf_client[..n_client]).unwrap().block(),
+            1
+        );
+
+        //
+        // verify
+        //
+        h.join().unwrap().unwrap();
+        let metadata = fs::metadata(base_dir.path().join(test_file_name)).unwrap();
+        assert!(metadata.is_file());
+        assert_eq!(metadata.len(), 0);
+        // the temporary file is removed
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_wrq_handler_with_removed_temp_dir() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        let test_file_name = "test_wrq_handler.txt";
+        let handler = create_wrq_handler(
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config::default(),
+        );
+        // e.g. by a tmp cleaner
+        fs::remove_dir_all(temp_dir.path()).unwrap();
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let wrq = packet::WritePacket::new(test_file_name.to_string(), packet::Mode::OCTET);
+        let content = [b'a'; 100];
+
+        //
+        // exercise
+        //
+        let h = thread::spawn(move || handler(sock_handler, addr_client, wrq));
+        let mut buf_client = [0; 1024];
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        assert_eq!(
+            packet::ACK::parse(&buf_client[..n_client]).unwrap().block(),
+            0
+        );
+        let data = packet::Data::new(1, &content);
+        sock_client.send_to(&data.encode(), addr_handler).unwrap();
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        assert_eq!(
+            packet::ACK::parse(&buf_client[..n_client]).unwrap().block(),
+            1
+        );
+
+        //
+        // verify
+        //
+        h.join().unwrap().unwrap();
+        assert!(temp_dir.path().is_dir());
+        let actual_content = fs::read(base_dir.path().join(test_file_name)).unwrap();
+        assert_eq!(&actual_content, &content);
+    }
+
+    #[test]
+    fn test_wrq_handler_with_stage_in_place() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        let test_file_name = "test_wrq_handler.txt";
+        let config = Config {
+            stage_in_place: true,
+            ..Config::default()
+        };
+        let handler = create_wrq_handler(
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            config,
+        );
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let wrq = packet::WritePacket::new(test_file_name.to_string(), packet::Mode::OCTET);
+
+        let h = thread::spawn(move || handler(sock_handler, addr_client, wrq));
+
+        //
+        // exercise and verify
+        //
+        let mut buf_client = [0; 1024];
+        let content = [b'a'; 513];
+        let list_base_dir = || {
+            fs::read_dir(base_dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let ack = packet::ACK::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(ack.block(), 0);
+
+        let data = packet::Data::new(1, &content[..512]);
+        sock_client.send_to(&data.encode(), addr_handler).unwrap();
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let ack = packet::ACK::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(ack.block(), 1);
+
+        // only the staging file exists during the transfer
+        let names = list_base_dir();
+        assert_eq!(names.len(), 1, "{:?}", names);
+        assert!(names[0].starts_with(&format!(".{}.", test_file_name)));
+        assert!(names[0].ends_with(".part"));
+        assert!(fs::read_dir(temp_dir.path()).unwrap().next().is_none());
+
+        let data = packet::Data::new(2, &content[512..]);
+        sock_client.send_to(&data.encode(), addr_handler).unwrap();
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let ack = packet::ACK::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(ack.block(), 2);
+
+        // the staging file is renamed to the destination
+        h.join().unwrap().unwrap();
+        assert_eq!(list_base_dir(), vec![test_file_name.to_string()]);
+        assert_eq!(
+            fs::read(base_dir.path().join(test_file_name)).unwrap(),
+            content
+        );
+    }
+
+    #[test]
+    fn test_wrq_handler_with_mirror_dirs() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        let mirror_dir = temp::create_temp_dir().unwrap();
+        let test_file_name = "test_wrq_handler.txt";
+        let config = Config {
+            // the missing one is skipped
+            mirror_dirs: vec![
+                base_dir.path().join("missing"),
+                mirror_dir.path().to_owned(),
+            ],
+            ..Config::default()
+        };
+        let handler = create_wrq_handler(
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            config,
+        );
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        let wrq = packet::WritePacket::new(test_file_name.to_string(), packet::Mode::OCTET);
+
+        let h = thread::spawn(move || handler(sock_handler, addr_client, wrq));
+
+        //
+        // exercise
+        //
+        let mut buf_client = [0; 1024];
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        assert_eq!(
+            packet::ACK::parse(&buf_client[..n_client]).unwrap().block(),
+            0
+        );
+        let data = packet::Data::new(1, b"hello");
+        sock_client.send_to(&data.encode(), addr_handler).unwrap();
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        assert_eq!(
+            packet::ACK::parse(&buf_client[..n_client]).unwrap().block(),
+            1
+        );
+
+        //
+        // verify
+        //
+        h.join().unwrap().unwrap();
+        assert_eq!(
+            fs::read(base_dir.path().join(test_file_name)).unwrap(),
+            b"hello"
+        );
+        assert_eq!(
+            fs::read(mirror_dir.path().join(test_file_name)).unwrap(),
+            b"hello"
+        );
+        // no staging file is left
+        assert_eq!(fs::read_dir(mirror_dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_wrq_handler_with_no_clobber() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        fs::write(base_dir.path().join("existing.txt"), b"original").unwrap();
+        let config = Config {
+            no_clobber: true,
+            ..Config::default()
+        };
+        let handler = Arc::new(create_wrq_handler(
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            config,
+        ));
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut buf_client = [0; 1024];
+        let request = |filename: &str| {
+            let handler = Arc::clone(&handler);
+            let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+            let addr_handler = sock_handler.local_addr().unwrap();
+            let wrq = packet::WritePacket::new(filename.to_string(), Mode::OCTET);
+            let h = thread::spawn(move || handler(sock_handler, addr_client, wrq));
+            (h, addr_handler)
+        };
+
+        //
+        // exercise and verify
+        //
+        // refused before ACK 0
+        let (h, _) = request("existing.txt");
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let err = packet::Error::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(err.error_code(), TftpError::FileExists.error_code());
+        assert!(h.join().unwrap().is_err());
+        assert_eq!(
+            fs::read(base_dir.path().join("existing.txt")).unwrap(),
+            b"original"
+        );
+
+        // a new file is accepted
+        let (h, addr_handler) = request("new.txt");
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        assert_eq!(
+            packet::ACK::parse(&buf_client[..n_client]).unwrap().block(),
+            0
+        );
+        let data = packet::Data::new(1, b"hello");
+        sock_client.send_to(&data.encode(), addr_handler).unwrap();
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        assert_eq!(
+            packet::ACK::parse(&buf_client[..n_client]).unwrap().block(),
+            1
+        );
+        h.join().unwrap().unwrap();
+        assert_eq!(fs::read(base_dir.path().join("new.txt")).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_wrq_handler_with_create_dirs() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        let config = Config {
+            filename_map: FilenameMap::default().with("upload.txt", "a/b/upload.txt"),
+            create_dirs: Some(0o750),
+            ..Config::default()
+        };
+        let handler = create_wrq_handler(
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            config,
+        );
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        let wrq = packet::WritePacket::new("upload.txt".to_string(), Mode::OCTET);
+
+        let h = thread::spawn(move || handler(sock_handler, addr_client, wrq));
+
+        //
+        // exercise
+        //
+        let mut buf_client = [0; 1024];
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        assert_eq!(
+            packet::ACK::parse(&buf_client[..n_client]).unwrap().block(),
+            0
+        );
+        let data = packet::Data::new(1, b"hello");
+        sock_client.send_to(&data.encode(), addr_handler).unwrap();
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        assert_eq!(
+            packet::ACK::parse(&buf_client[..n_client]).unwrap().block(),
+            1
+        );
+
+        //
+        // verify
+        //
+        h.join().unwrap().unwrap();
+        let dir = base_dir.path().join("a/b");
+        assert_eq!(dir.metadata().unwrap().permissions().mode() & 0o777, 0o750);
+        assert_eq!(fs::read(dir.join("upload.txt")).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_wrq_handler_with_options() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        let test_file_name = "test_wrq_handler.txt";
+        let handler = create_wrq_handler(
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config::default(),
+        );
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        sock_handler
+            .set_write_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let wrq = packet::WritePacket::new_with_options(
+            test_file_name.to_string(),
+            packet::Mode::OCTET,
+            vec![("tsize".to_string(), "513".to_string())],
+        );
+
+        let barrier_client = Arc::new(sync::Barrier::new(2));
+        let barrier_handler = Arc::clone(&barrier_client);
+        let _h = thread::spawn(move || {
+            handler(sock_handler, addr_client, wrq).unwrap();
+            barrier_handler.wait();
+        });
+
+        //
+        // exercise and verify
+        //
+        let mut buf_client = [0; 1024];
+        let content = [b'a'; 513];
+
+        // OACK comes instead of ACK of block 0
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let oack = packet::OACK::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(oack.get("tsize"), Some("513"));
+
+        let data = packet::Data::new(1, &content[..512]);
+        sock_client.send_to(&data.encode(), addr_handler).unwrap();
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let ack = packet::ACK::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(ack.block(), 1);
+
+        let data = packet::Data::new(2, &content[512..]);
+        sock_client.send_to(&data.encode(), addr_handler).unwrap();
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let ack = packet::ACK::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(ack.block(), 2);
+
+        barrier_client.wait();
+        let mut file = fs::File::open(base_dir.path().join(test_file_name)).unwrap();
+        let mut actual_content = vec![];
+        file.read_to_end(&mut actual_content).unwrap();
+        assert_eq!(&actual_content, &content);
+    }
+
+    #[test]
+    fn test_wrq_handler_with_oversized_data() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        let test_file_name = "test_wrq_handler.txt";
+        let handler = create_wrq_handler(
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config::default(),
+        );
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let wrq = packet::WritePacket::new_with_options(
+            test_file_name.to_string(),
+            packet::Mode::OCTET,
+            vec![("blksize".to_string(), "1024".to_string())],
+        );
+
+        let h = thread::spawn(move || handler(sock_handler, addr_client, wrq));
+
+        //
+        // exercise
+        //
+        let mut buf_client = [0; 1024];
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let oack = packet::OACK::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(oack.get("blksize"), Some("1024"));
+
+        // larger than the negotiated blksize + 4
+        let data = packet::Data::new(1, &[b'a'; 1025]);
+        sock_client.send_to(&data.encode(), addr_handler).unwrap();
+
+        //
+        // verify
+        //
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let err = packet::Error::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(err.error_code(), TftpError::IllegalTftpOp.error_code());
+        assert_eq!(err.message(), "Packet too large");
+        assert!(h.join().unwrap().is_err());
+        assert!(!base_dir.path().join(test_file_name).exists());
+    }
+
+    #[test]
+    fn test_wrq_handler_with_request_in_transfer() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        let test_file_name = "test_wrq_handler.txt";
+        let handler = create_wrq_handler(
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config::default(),
+        );
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let wrq = packet::WritePacket::new(test_file_name.to_string(), packet::Mode::OCTET);
+
+        let h = thread::spawn(move || handler(sock_handler, addr_client, wrq));
+
+        let mut buf_client = [0; 1024];
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        assert_eq!(
+            packet::ACK::parse(&buf_client[..n_client]).unwrap().block(),
+            0
+        );
+        let data = packet::Data::new(1, &[b'a'; 512]);
+        sock_client.send_to(&data.encode(), addr_handler).unwrap();
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        assert_eq!(
+            packet::ACK::parse(&buf_client[..n_client]).unwrap().block(),
+            1
+        );
+
+        //
+        // exercise
+        //
+        // a new request sent to the handler instead of the server port
+        let wrq = packet::WritePacket::new("another.txt".to_string(), packet::Mode::OCTET);
+        sock_client.send_to(&wrq.encode(), addr_handler).unwrap();
+
+        //
+        // verify
+        //
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let err = packet::Error::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(err.error_code(), TftpError::IllegalTftpOp.error_code());
+        assert_eq!(err.message(), "Unexpected request");
+        assert!(h.join().unwrap().is_err());
+        assert!(!base_dir.path().join(test_file_name).exists());
+        assert!(!base_dir.path().join("another.txt").exists());
+    }
+
+    #[test]
+    fn test_wrq_handler_with_error_from_client() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
         let test_file_name = "test_wrq_handler.txt";
+        let handler = create_wrq_handler(
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config::default(),
+        );
 
         let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
         let addr_client = sock_client.local_addr().unwrap();
         sock_client
             .set_read_timeout(Some(Duration::from_secs(1)))
             .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let wrq = packet::WritePacket::new(test_file_name.to_string(), packet::Mode::OCTET);
+
+        let h = thread::spawn(move || handler(sock_handler, addr_client, wrq));
+
+        let mut buf_client = [0; 1024];
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        assert_eq!(
+            packet::ACK::parse(&buf_client[..n_client]).unwrap().block(),
+            0
+        );
+        let data = packet::Data::new(1, &[b'a'; 512]);
+        sock_client.send_to(&data.encode(), addr_handler).unwrap();
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        assert_eq!(
+            packet::ACK::parse(&buf_client[..n_client]).unwrap().block(),
+            1
+        );
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+
+        //
+        // exercise
+        //
+        let err = packet::Error::new(TftpError::DiskNoSpace, "Disk full".to_string());
+        sock_client.send_to(&err.encode(), addr_handler).unwrap();
+
+        //
+        // verify
+        //
+        // the handler finishes without waiting for a timeout
+        let started = Instant::now();
+        assert!(h.join().unwrap().is_err());
+        assert!(started.elapsed() < Duration::from_millis(500));
+        assert!(!base_dir.path().join(test_file_name).exists());
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+        // no reply to ERROR
         sock_client
-            .set_write_timeout(Some(Duration::from_secs(1)))
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        assert!(sock_client.recv_from(&mut buf_client).is_err());
+    }
+
+    #[test]
+    fn test_wrq_handler_with_short_upload_for_tsize() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        let test_file_name = "test_wrq_handler.txt";
+        let config = Config {
+            verify_tsize: true,
+            ..Config::default()
+        };
+        let handler = create_wrq_handler(
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            config,
+        );
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(1)))
             .unwrap();
+        let wrq = packet::WritePacket::new_with_options(
+            test_file_name.to_string(),
+            packet::Mode::OCTET,
+            vec![("tsize".to_string(), "1000".to_string())],
+        );
+
+        let h = thread::spawn(move || handler(sock_handler, addr_client, wrq));
+
+        //
+        // exercise
+        //
+        let mut buf_client = [0; 1024];
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let oack = packet::OACK::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(oack.get("tsize"), Some("1000"));
+        let data = packet::Data::new(1, &[b'a'; 512]);
+        sock_client.send_to(&data.encode(), addr_handler).unwrap();
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        assert_eq!(
+            packet::ACK::parse(&buf_client[..n_client]).unwrap().block(),
+            1
+        );
+        // the last block is shorter than promised
+        let data = packet::Data::new(2, &[b'a'; 100]);
+        sock_client.send_to(&data.encode(), addr_handler).unwrap();
+
+        //
+        // verify
+        //
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let err = packet::Error::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(err.error_code(), TftpError::Others.error_code());
+        assert!(h.join().unwrap().is_err());
+        assert!(!base_dir.path().join(test_file_name).exists());
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_wrq_handler_with_too_large_tsize() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        let test_file_name = "test_wrq_handler.txt";
+        let handler = create_wrq_handler(
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config::default(),
+        );
+
+        // twice as large as the free space
+        let stat = nix::sys::statvfs::statvfs(temp_dir.path()).unwrap();
+        let tsize = stat.blocks_available() * stat.fragment_size() * 2;
 
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
         let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
         sock_handler
             .set_read_timeout(Some(Duration::from_secs(1)))
@@ -664,11 +6412,17 @@ mod tests {
         sock_handler
             .set_write_timeout(Some(Duration::from_secs(1)))
             .unwrap();
+        let wrq = packet::WritePacket::new_with_options(
+            test_file_name.to_string(),
+            packet::Mode::OCTET,
+            vec![("tsize".to_string(), tsize.to_string())],
+        );
 
-        let rrq = packet::ReadPacket::new(test_file_name.to_string(), packet::Mode::OCTET);
-
+        let barrier_client = Arc::new(sync::Barrier::new(2));
+        let barrier_handler = Arc::clone(&barrier_client);
         let _h = thread::spawn(move || {
-            handler(sock_handler, addr_client, rrq).unwrap();
+            assert!(handler(sock_handler, addr_client, wrq).is_err());
+            barrier_handler.wait();
         });
 
         //
@@ -676,21 +6430,101 @@ mod tests {
         //
         let mut buf_client = [0; 1024];
 
+        // Error comes before any ACK
         let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
-        let err_pkt = packet::Error::parse(&buf_client[..n_client]).unwrap();
-        assert_eq!(err_pkt.error_code(), TftpError::FileNotFound.error_code());
-        assert_eq!(err_pkt.message(), "File not found");
+        let err = packet::Error::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(err.error_code(), TftpError::DiskNoSpace.error_code());
+
+        barrier_client.wait();
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+        assert!(!base_dir.path().join(test_file_name).exists());
     }
 
     #[test]
-    fn test_wrq_handler() {
+    fn test_wrq_handler_with_small_destination() {
         //
         // setup
         //
         let base_dir = temp::create_temp_dir().unwrap();
         let temp_dir = temp::create_temp_dir().unwrap();
         let test_file_name = "test_wrq_handler.txt";
-        let handler = create_wrq_handler(base_dir.path().to_owned(), temp_dir.path().to_owned());
+        let (base_path, temp_path) = (base_dir.path().to_owned(), temp_dir.path().to_owned());
+        // as if the filesystems had only 64 KiB available
+        let handler = create_wrq_handler_with_sink(Config::default(), move |wrq| {
+            let sink = TempFileSink::create(&temp_path, base_path.join(&wrq.filename), wrq.mode)?;
+            Ok(sink.with_free_space(|_| Ok(64 * 1024)))
+        });
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        sock_client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let wrq = packet::WritePacket::new_with_options(
+            test_file_name.to_string(),
+            packet::Mode::OCTET,
+            vec![("tsize".to_string(), (1024 * 1024).to_string())],
+        );
+
+        //
+        // exercise
+        //
+        let h = thread::spawn(move || handler(sock_handler, addr_client, wrq));
+
+        //
+        // verify
+        //
+        // Error comes before any ACK
+        let mut buf_client = [0; 1024];
+        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+        let err = packet::Error::parse(&buf_client[..n_client]).unwrap();
+        assert_eq!(err.error_code(), TftpError::DiskNoSpace.error_code());
+        assert!(h.join().unwrap().is_err());
+        // no partial file is left
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+        assert_eq!(fs::read_dir(base_dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_wrq_handler_with_sink() {
+        //
+        // setup
+        //
+        struct MemorySink {
+            buf: Vec<u8>,
+            uploaded: Arc<Mutex<Option<Vec<u8>>>>,
+        }
+
+        impl WrqSink for MemorySink {
+            fn write_block(&mut self, data: &[u8]) -> std::io::Result<()> {
+                self.buf.extend_from_slice(data);
+                Ok(())
+            }
+
+            fn finish(self) -> std::io::Result<()> {
+                *self.uploaded.lock().unwrap() = Some(self.buf);
+                Ok(())
+            }
+
+            fn abort(self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let uploaded = Arc::new(Mutex::new(None));
+        let handler = {
+            let uploaded = Arc::clone(&uploaded);
+            create_wrq_handler_with_sink(Config::default(), move |_wrq| {
+                Ok(MemorySink {
+                    buf: vec![],
+                    uploaded: Arc::clone(&uploaded),
+                })
+            })
+        };
 
         let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
         let addr_client = sock_client.local_addr().unwrap();
@@ -699,10 +6533,7 @@ mod tests {
         sock_handler
             .set_read_timeout(Some(Duration::from_secs(1)))
             .unwrap();
-        sock_handler
-            .set_write_timeout(Some(Duration::from_secs(1)))
-            .unwrap();
-        let wrq = packet::WritePacket::new(test_file_name.to_string(), packet::Mode::OCTET);
+        let wrq = packet::WritePacket::new("test_wrq_handler.txt".to_string(), Mode::OCTET);
 
         let barrier_client = Arc::new(sync::Barrier::new(2));
         let barrier_handler = Arc::clone(&barrier_client);
@@ -712,7 +6543,7 @@ mod tests {
         });
 
         //
-        // exercise and verify
+        // exercise
         //
         let mut buf_client = [0; 1024];
         let content = [b'a'; 513];
@@ -721,23 +6552,177 @@ mod tests {
         let ack = packet::ACK::parse(&buf_client[..n_client]).unwrap();
         assert_eq!(ack.block(), 0);
 
-        let data = packet::Data::new(1, &content[..512]);
-        sock_client.send_to(&data.encode(), addr_handler).unwrap();
-        let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
-        let ack = packet::ACK::parse(&buf_client[..n_client]).unwrap();
-        assert_eq!(ack.block(), 1);
+        for (i, chunk) in content.chunks(512).enumerate() {
+            let data = packet::Data::new(i as u16 + 1, chunk);
+            sock_client.send_to(&data.encode(), addr_handler).unwrap();
+            let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+            let ack = packet::ACK::parse(&buf_client[..n_client]).unwrap();
+            assert_eq!(ack.block(), i as u16 + 1);
+        }
+
+        //
+        // verify
+        //
+        barrier_client.wait();
+        assert_eq!(uploaded.lock().unwrap().as_deref(), Some(&content[..]));
+    }
+
+    #[test]
+    fn test_wrq_handler_with_sync_interval() {
+        //
+        // setup
+        //
+        struct SyncCountingSink {
+            blocks: u64,
+            synced_at: Arc<Mutex<Vec<u64>>>,
+        }
+
+        impl WrqSink for SyncCountingSink {
+            fn write_block(&mut self, _data: &[u8]) -> std::io::Result<()> {
+                self.blocks += 1;
+                Ok(())
+            }
+
+            fn sync(&mut self) -> std::io::Result<()> {
+                self.synced_at.lock().unwrap().push(self.blocks);
+                Ok(())
+            }
+
+            fn finish(self) -> std::io::Result<()> {
+                Ok(())
+            }
+
+            fn abort(self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let synced_at = Arc::new(Mutex::new(vec![]));
+        let handler = {
+            let synced_at = Arc::clone(&synced_at);
+            let config = Config {
+                sync_interval: std::num::NonZeroU32::new(2),
+                ..Config::default()
+            };
+            create_wrq_handler_with_sink(config, move |_wrq| {
+                Ok(SyncCountingSink {
+                    blocks: 0,
+                    synced_at: Arc::clone(&synced_at),
+                })
+            })
+        };
+
+        let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_client = sock_client.local_addr().unwrap();
+        let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr_handler = sock_handler.local_addr().unwrap();
+        sock_handler
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let wrq = packet::WritePacket::new("test_wrq_handler.txt".to_string(), Mode::OCTET);
+
+        let barrier_client = Arc::new(sync::Barrier::new(2));
+        let barrier_handler = Arc::clone(&barrier_client);
+        let _h = thread::spawn(move || {
+            handler(sock_handler, addr_client, wrq).unwrap();
+            barrier_handler.wait();
+        });
+
+        //
+        // exercise
+        //
+        let mut buf_client = [0; 1024];
+        // 5 full blocks and the last short block
+        let content = [b'a'; 512 * 5 + 10];
 
-        let data = packet::Data::new(2, &content[512..]);
-        sock_client.send_to(&data.encode(), addr_handler).unwrap();
         let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
         let ack = packet::ACK::parse(&buf_client[..n_client]).unwrap();
-        assert_eq!(ack.block(), 2);
+        assert_eq!(ack.block(), 0);
+
+        for (i, chunk) in content.chunks(512).enumerate() {
+            let data = packet::Data::new(i as u16 + 1, chunk);
+            sock_client.send_to(&data.encode(), addr_handler).unwrap();
+            let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+            let ack = packet::ACK::parse(&buf_client[..n_client]).unwrap();
+            assert_eq!(ack.block(), i as u16 + 1);
+        }
 
+        //
+        // verify
+        //
         barrier_client.wait();
-        let mut file = fs::File::open(base_dir.path().join(test_file_name)).unwrap();
-        let mut actual_content = vec![];
-        file.read_to_end(&mut actual_content).unwrap();
-        assert_eq!(&actual_content, &content);
+        assert_eq!(*synced_at.lock().unwrap(), vec![2, 4, 6]);
+    }
+
+    #[test]
+    fn test_wrq_handler_with_concurrent_upload() {
+        //
+        // setup
+        //
+        let base_dir = temp::create_temp_dir().unwrap();
+        let temp_dir = temp::create_temp_dir().unwrap();
+        let test_file_name = "test_wrq_handler.txt";
+        let handler = Arc::new(create_wrq_handler(
+            base_dir.path().to_owned(),
+            temp_dir.path().to_owned(),
+            Config::default(),
+        ));
+
+        let start_upload = || {
+            let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+            sock_client
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            let addr_client = sock_client.local_addr().unwrap();
+            let sock_handler = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+            sock_handler
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            let addr_handler = sock_handler.local_addr().unwrap();
+            let wrq = packet::WritePacket::new(test_file_name.to_string(), Mode::OCTET);
+            let handler = Arc::clone(&handler);
+            let h = thread::spawn(move || handler(sock_handler, addr_client, wrq));
+
+            let mut buf_client = [0; 1024];
+            let (n_client, _) = sock_client.recv_from(&mut buf_client).unwrap();
+            (
+                sock_client,
+                addr_handler,
+                buf_client[..n_client].to_vec(),
+                h,
+            )
+        };
+
+        //
+        // exercise and verify
+        //
+        // the first upload is accepted
+        let (sock_client1, addr_handler1, pkt, h1) = start_upload();
+        assert_eq!(packet::ACK::parse(&pkt).unwrap().block(), 0);
+
+        // the second upload to the same file is rejected while the first is in progress
+        let (_, _, pkt, h2) = start_upload();
+        let err = packet::Error::parse(&pkt).unwrap();
+        assert_eq!(err.error_code(), TftpError::Others.error_code());
+        assert!(h2.join().unwrap().is_err());
+
+        let data = packet::Data::new(1, b"first");
+        sock_client1.send_to(&data.encode(), addr_handler1).unwrap();
+        let mut buf_client = [0; 1024];
+        let (n_client, _) = sock_client1.recv_from(&mut buf_client).unwrap();
+        assert_eq!(
+            packet::ACK::parse(&buf_client[..n_client]).unwrap().block(),
+            1
+        );
+        h1.join().unwrap().unwrap();
+        assert_eq!(
+            fs::read(base_dir.path().join(test_file_name)).unwrap(),
+            b"first"
+        );
+
+        // the file can be uploaded again after the first one finishes
+        let (_, _, pkt, _) = start_upload();
+        assert_eq!(packet::ACK::parse(&pkt).unwrap().block(), 0);
     }
 
     #[test]
@@ -749,7 +6734,11 @@ mod tests {
 
         let temp_dir = temp::create_temp_dir().unwrap();
         let test_file_name = "test_wrq_handler.txt";
-        let handler = create_wrq_handler(base_dir.to_owned(), temp_dir.path().to_owned());
+        let handler = create_wrq_handler(
+            base_dir.to_owned(),
+            temp_dir.path().to_owned(),
+            Config::default(),
+        );
 
         let sock_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
         let addr_client = sock_client.local_addr().unwrap();
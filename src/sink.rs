@@ -0,0 +1,294 @@
+use crate::config::NetasciiStore;
+use crate::error::Result;
+use crate::{file, packet, temp};
+use anyhow::{anyhow, Context};
+use log::{debug, error, info, warn};
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Destination of data uploaded by WRQ.
+/// The handler calls `write_block` for each DATA in order, then `finish` after the last one.
+/// `abort` is called instead of `finish` if the transfer cannot be completed.
+pub trait WrqSink {
+    /// Reserve space for the upload before the transfer starts.
+    /// This is called only if the client tells the size by tsize option.
+    fn reserve(&mut self, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Write the payload of a DATA packet.
+    fn write_block(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Make data written so far durable.
+    /// This is called periodically during the transfer if configured.
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Complete the upload.
+    fn finish(self) -> io::Result<()>;
+
+    /// Discard the upload.
+    fn abort(self) -> io::Result<()>;
+}
+
+/// The default WrqSink.
+/// Data is written to a temporary file first, then moved to the destination in `finish`
+/// so that a partially uploaded file never appears at the destination.
+pub struct TempFileSink {
+    temp_file: file::File,
+    temp_file_path: PathBuf,
+    dest_path: PathBuf,
+    in_place: bool,
+    mirror_dirs: Vec<PathBuf>,
+    free_space: fn(&Path) -> io::Result<u64>,
+}
+
+impl TempFileSink {
+    /// Stage the upload in `temp_dir`. It is copied to the destination in `finish`.
+    pub fn create(
+        temp_dir: impl AsRef<Path>,
+        dest_path: impl AsRef<Path>,
+        mode: packet::Mode,
+    ) -> Result<TempFileSink> {
+        let dest_path = dest_path.as_ref().to_owned();
+        let filename = dest_path
+            .file_name()
+            .with_context(|| format!("Illegal destination path: {:?}", dest_path))?;
+        let temp_file_path = temp_dir.as_ref().join(format!(
+            "{}.{}",
+            filename.to_string_lossy(),
+            temp::generate_random_name()?
+        ));
+        TempFileSink::create_at(temp_file_path, dest_path, mode, false)
+    }
+
+    /// Stage the upload as `.<filename>.<random>.part` in the same directory as the destination.
+    /// It is renamed to the destination in `finish`, which is atomic because it is always
+    /// on the same filesystem.
+    pub fn create_in_place(
+        dest_path: impl AsRef<Path>,
+        mode: packet::Mode,
+    ) -> Result<TempFileSink> {
+        let dest_path = dest_path.as_ref().to_owned();
+        let (dir, filename) = match (dest_path.parent(), dest_path.file_name()) {
+            (Some(dir), Some(filename)) => (dir, filename),
+            _ => return Err(anyhow!("Illegal destination path: {:?}", dest_path).into()),
+        };
+        let temp_file_path = dir.join(format!(
+            ".{}.{}.part",
+            filename.to_string_lossy(),
+            temp::generate_random_name()?
+        ));
+        TempFileSink::create_at(temp_file_path, dest_path, mode, true)
+    }
+
+    /// Set how netascii data is stored.
+    pub fn with_netascii_store(mut self, netascii_store: NetasciiStore) -> TempFileSink {
+        self.temp_file = self.temp_file.with_netascii_store(netascii_store);
+        self
+    }
+
+    /// Copy the uploaded file to these directories after it is saved to the destination.
+    /// Failures of copies are only logged because the upload itself has succeeded.
+    pub fn with_mirror_dirs(mut self, mirror_dirs: Vec<PathBuf>) -> TempFileSink {
+        self.mirror_dirs = mirror_dirs;
+        self
+    }
+
+    /// Use `free_space` to get the available bytes of the filesystem of a directory
+    /// instead of statvfs, e.g. to simulate a small disk in tests.
+    pub fn with_free_space(mut self, free_space: fn(&Path) -> io::Result<u64>) -> TempFileSink {
+        self.free_space = free_space;
+        self
+    }
+
+    fn mirror(&self) {
+        let filename = match self.dest_path.file_name() {
+            Some(filename) => filename.to_string_lossy(),
+            None => return,
+        };
+        for dir in self.mirror_dirs.iter() {
+            let mirror_path = dir.join(filename.as_ref());
+            match copy_atomically(&self.dest_path, dir, &filename) {
+                Ok(()) => info!("mirrored {:?} to {:?}", self.dest_path, mirror_path),
+                Err(err) => warn!(
+                    "Failed to mirror {:?} to {:?}: {:?}",
+                    self.dest_path, mirror_path, err
+                ),
+            }
+        }
+    }
+
+    /// Move the temporary file to the destination.
+    fn save(&mut self) -> io::Result<()> {
+        self.temp_file.flush()?;
+        if self.in_place {
+            fs::rename(&self.temp_file_path, &self.dest_path)?;
+        } else {
+            // avoid using fs::rename (it cannot move if src and dest mount point are different)
+            fs::copy(&self.temp_file_path, &self.dest_path)?;
+            fs::remove_file(&self.temp_file_path).unwrap_or_else(|err| {
+                error!("Failed to delete {:?}: {:?}", self.temp_file_path, err);
+            });
+        }
+        Ok(())
+    }
+
+    fn create_at(
+        temp_file_path: PathBuf,
+        dest_path: PathBuf,
+        mode: packet::Mode,
+        in_place: bool,
+    ) -> Result<TempFileSink> {
+        let temp_file = file::File::create(&temp_file_path, mode)
+            .with_context(|| format!("Failed to create {:?}", temp_file_path))?;
+        debug!("created {:?}", temp_file_path);
+        Ok(TempFileSink {
+            temp_file,
+            temp_file_path,
+            dest_path,
+            in_place,
+            mirror_dirs: vec![],
+            free_space: available_space,
+        })
+    }
+}
+
+impl WrqSink for TempFileSink {
+    fn reserve(&mut self, len: u64) -> io::Result<()> {
+        if let Some(dir) = self.temp_file_path.parent() {
+            check_free_space(dir, len, self.free_space)?;
+        }
+        if !self.in_place {
+            // the upload is copied to the destination later, which may be on another filesystem
+            if let Some(dir) = self.dest_path.parent() {
+                check_free_space(dir, len, self.free_space)?;
+            }
+        }
+        self.temp_file.allocate(len)
+    }
+
+    fn write_block(&mut self, data: &[u8]) -> io::Result<()> {
+        self.temp_file.write_all(data)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.temp_file.sync_data()
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        if let Err(err) = self.save() {
+            // the upload is lost anyway, so don't leave the temporary file behind
+            fs::remove_file(&self.temp_file_path).unwrap_or_else(|err| {
+                error!("Failed to delete {:?}: {:?}", self.temp_file_path, err);
+            });
+            return Err(err);
+        }
+        self.mirror();
+        Ok(())
+    }
+
+    fn abort(self) -> io::Result<()> {
+        fs::remove_file(&self.temp_file_path)
+    }
+}
+
+/// Copy `src` to `dir/filename` via a staging file in `dir`,
+/// so that a partial copy never appears as `filename`.
+fn copy_atomically(src: &Path, dir: &Path, filename: &str) -> Result<()> {
+    let staging_path = dir.join(format!(
+        ".{}.{}.part",
+        filename,
+        temp::generate_random_name()?
+    ));
+    let copied =
+        fs::copy(src, &staging_path).and_then(|_| fs::rename(&staging_path, dir.join(filename)));
+    if copied.is_err() {
+        let _ = fs::remove_file(&staging_path);
+    }
+    Ok(copied?)
+}
+
+/// The bytes available to unprivileged users in the filesystem of `dir`.
+fn available_space(dir: &Path) -> io::Result<u64> {
+    let stat = nix::sys::statvfs::statvfs(dir)?;
+    Ok((stat.blocks_available() as u64).saturating_mul(stat.fragment_size() as u64))
+}
+
+/// Fail with ENOSPC if the filesystem of `dir` doesn't have `len` bytes available.
+fn check_free_space(
+    dir: &Path,
+    len: u64,
+    free_space: fn(&Path) -> io::Result<u64>,
+) -> io::Result<()> {
+    if len > free_space(dir)? {
+        return Err(io::Error::from_raw_os_error(nix::libc::ENOSPC));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_free_space() {
+        let dir = temp::create_temp_dir().unwrap();
+        assert!(check_free_space(dir.path(), 1024, available_space).is_ok());
+
+        let stat = nix::sys::statvfs::statvfs(dir.path()).unwrap();
+        let available = stat.blocks_available() as u64 * stat.fragment_size() as u64;
+        let err = check_free_space(dir.path(), available * 2, available_space).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(nix::libc::ENOSPC));
+    }
+
+    #[test]
+    fn test_finish_with_error() {
+        let temp_dir = temp::create_temp_dir().unwrap();
+        let dest_dir = temp::create_temp_dir().unwrap();
+
+        // the directory of the destination is removed during the upload
+        let dest_path = dest_dir.path().join("sub").join("foo.txt");
+        fs::create_dir(dest_path.parent().unwrap()).unwrap();
+        let mut sink =
+            TempFileSink::create(temp_dir.path(), &dest_path, packet::Mode::OCTET).unwrap();
+        sink.write_block(b"hello").unwrap();
+        fs::remove_dir(dest_path.parent().unwrap()).unwrap();
+        assert!(sink.finish().is_err());
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+
+        // a directory is at the destination, which cannot be replaced
+        let dest_path = dest_dir.path().join("bar");
+        fs::create_dir_all(dest_path.join("baz")).unwrap();
+        let mut sink = TempFileSink::create_in_place(&dest_path, packet::Mode::OCTET).unwrap();
+        sink.write_block(b"hello").unwrap();
+        assert!(sink.finish().is_err());
+        let names: Vec<_> = fs::read_dir(dest_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["bar"]);
+    }
+
+    #[test]
+    fn test_reserve_with_free_space() {
+        let temp_dir = temp::create_temp_dir().unwrap();
+        let dest_dir = temp::create_temp_dir().unwrap();
+        let mut sink = TempFileSink::create(
+            temp_dir.path(),
+            dest_dir.path().join("foo.txt"),
+            packet::Mode::OCTET,
+        )
+        .unwrap()
+        .with_free_space(|_| Ok(1024));
+
+        assert!(sink.reserve(1024).is_ok());
+        let err = sink.reserve(1025).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(nix::libc::ENOSPC));
+        sink.abort().unwrap();
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+    }
+}
@@ -6,7 +6,9 @@ use std::os::unix::io::{FromRawFd, RawFd};
 /// Factory method for std::net::UdpSocket.
 /// The inner socket has ReusePort and ReuseAddr options.
 /// This is necessary because UdpSocket itself doesn't allow set options before bind.
-pub fn create_udp_socket(addr: SocketAddr) -> Result<UdpSocket> {
+/// If `freebind` is true, IP_FREEBIND is also set so that the socket can be bound
+/// to an address which is not assigned to any interface yet (only supported on Linux).
+pub fn create_udp_socket(addr: SocketAddr, freebind: bool) -> Result<UdpSocket> {
     let fd = nix::sys::socket::socket(
         AddressFamily::Inet,
         SockType::Datagram,
@@ -14,14 +16,70 @@ pub fn create_udp_socket(addr: SocketAddr) -> Result<UdpSocket> {
         None,
     )?;
     reuse_port(fd)?;
+    if freebind {
+        set_freebind(fd)?;
+    }
     nix::sys::socket::bind(fd, &SockAddr::new_inet(InetAddr::from_std(&addr)))?;
     unsafe { Ok(UdpSocket::from_raw_fd(fd)) }
 }
 
-fn reuse_port(fd: RawFd) -> Result<()> {
+pub fn reuse_port(fd: RawFd) -> Result<()> {
     let opt = nix::sys::socket::sockopt::ReusePort;
     nix::sys::socket::setsockopt(fd, opt, &true)?;
     let opt = nix::sys::socket::sockopt::ReuseAddr;
     nix::sys::socket::setsockopt(fd, opt, &true)?;
     Ok(())
 }
+
+/// Set SO_RCVBUF so that a burst of datagrams can be queued, and return the actual size.
+/// On Linux, SO_RCVBUFFORCE is tried first to exceed net.core.rmem_max (it requires CAP_NET_ADMIN),
+/// and the returned size is doubled by the kernel for its bookkeeping overhead.
+pub fn set_recv_buffer_size(fd: RawFd, size: usize) -> Result<usize> {
+    #[cfg(target_os = "linux")]
+    {
+        let opt = nix::sys::socket::sockopt::RcvBufForce;
+        if nix::sys::socket::setsockopt(fd, opt, &size).is_ok() {
+            return Ok(nix::sys::socket::getsockopt(
+                fd,
+                nix::sys::socket::sockopt::RcvBuf,
+            )?);
+        }
+    }
+    let opt = nix::sys::socket::sockopt::RcvBuf;
+    nix::sys::socket::setsockopt(fd, opt, &size)?;
+    Ok(nix::sys::socket::getsockopt(fd, opt)?)
+}
+
+#[cfg(target_os = "linux")]
+fn set_freebind(fd: RawFd) -> Result<()> {
+    let opt = nix::sys::socket::sockopt::IpFreebind;
+    nix::sys::socket::setsockopt(fd, opt, &true)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_freebind(_fd: RawFd) -> Result<()> {
+    anyhow::bail!("IP_FREEBIND is not supported on this platform");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_create_udp_socket_with_freebind() {
+        // TEST-NET-1 (RFC 5737), which is not assigned to any interface
+        let addr: SocketAddr = "192.0.2.1:0".parse().unwrap();
+        let sock = create_udp_socket(addr, true).unwrap();
+        assert_eq!(sock.local_addr().unwrap().ip(), addr.ip());
+    }
+
+    #[test]
+    fn test_set_recv_buffer_size() {
+        let sock = create_udp_socket("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        let size = set_recv_buffer_size(sock.as_raw_fd(), 65536).unwrap();
+        assert!(size >= 65536, "size: {}", size);
+    }
+}
@@ -0,0 +1,403 @@
+use crate::error::TftpError;
+use crate::options::TransferOptions;
+use log::info;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+type TransferKey = (SocketAddr, Operation, String);
+
+/// Counters of the server shared by the main loop and handlers.
+/// Counters of transfers (started, finished and active ones) are updated and read together
+/// under the lock of `transfers`, so that a snapshot never sees a transfer counted twice or lost
+/// (e.g. neither active nor finished). Other counters are updated without the lock.
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    active_transfers: AtomicU64,
+    total_transfers: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    errors: AtomicU64,
+    rrq_completed: AtomicU64,
+    rrq_failed: AtomicU64,
+    wrq_completed: AtomicU64,
+    wrq_failed: AtomicU64,
+    /// Indexed by error code.
+    errors_sent: [AtomicU64; 8],
+    transfers: Mutex<HashMap<TransferKey, Arc<Transfer>>>,
+}
+
+/// Values of ServerStats at some point.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    pub active_transfers: u64,
+    pub total_transfers: u64,
+    /// Bytes of data received by WRQ.
+    pub bytes_received: u64,
+    /// Bytes of data sent by RRQ (retransmission is not counted).
+    pub bytes_sent: u64,
+    /// The number of transfers which failed.
+    pub errors: u64,
+    pub rrq_completed: u64,
+    pub rrq_failed: u64,
+    pub wrq_completed: u64,
+    pub wrq_failed: u64,
+    /// The number of ERROR packets sent by the server and handlers, indexed by error code.
+    pub errors_sent: [u64; 8],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Read,
+    Write,
+}
+
+/// Details of an active transfer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferInfo {
+    pub client_addr: SocketAddr,
+    pub filename: String,
+    pub operation: Operation,
+    /// Bytes of data transferred so far.
+    pub bytes: u64,
+    pub started_at: SystemTime,
+    /// The block size in effect, i.e. the accepted blksize (or blksize2) after clamping,
+    /// or 512 if not negotiated.
+    pub block_size: u16,
+    /// The accepted windowsize after clamping, or 1 if not negotiated.
+    pub window_size: u16,
+}
+
+/// A transfer registered to ServerStats. The progress is updated by its handler.
+#[derive(Debug)]
+pub(crate) struct Transfer {
+    client_addr: SocketAddr,
+    filename: String,
+    operation: Operation,
+    bytes: AtomicU64,
+    started_at: SystemTime,
+    cancelled: AtomicBool,
+    block_size: AtomicU16,
+    window_size: AtomicU16,
+}
+
+impl Transfer {
+    fn new(client_addr: SocketAddr, operation: Operation, filename: &str) -> Transfer {
+        Transfer {
+            client_addr,
+            filename: filename.to_string(),
+            operation,
+            bytes: AtomicU64::new(0),
+            started_at: SystemTime::now(),
+            cancelled: AtomicBool::new(false),
+            block_size: AtomicU16::new(TransferOptions::default().block_size()),
+            window_size: AtomicU16::new(TransferOptions::default().window_size()),
+        }
+    }
+
+    fn key(&self) -> TransferKey {
+        (self.client_addr, self.operation, self.filename.clone())
+    }
+
+    pub(crate) fn info(&self) -> TransferInfo {
+        TransferInfo {
+            client_addr: self.client_addr,
+            filename: self.filename.clone(),
+            operation: self.operation,
+            bytes: self.bytes.load(Ordering::Relaxed),
+            started_at: self.started_at,
+            block_size: self.block_size.load(Ordering::Relaxed),
+            window_size: self.window_size.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Whether the transfer was cancelled by `ServerStats::cancel`.
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+impl ServerStats {
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let _transfers = self.transfers.lock().unwrap();
+        StatsSnapshot {
+            active_transfers: self.active_transfers.load(Ordering::Relaxed),
+            total_transfers: self.total_transfers.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            rrq_completed: self.rrq_completed.load(Ordering::Relaxed),
+            rrq_failed: self.rrq_failed.load(Ordering::Relaxed),
+            wrq_completed: self.wrq_completed.load(Ordering::Relaxed),
+            wrq_failed: self.wrq_failed.load(Ordering::Relaxed),
+            errors_sent: self
+                .errors_sent
+                .each_ref()
+                .map(|count| count.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Details of active transfers in the order of start.
+    pub fn active_transfers(&self) -> Vec<TransferInfo> {
+        let mut infos: Vec<TransferInfo> = self
+            .transfers
+            .lock()
+            .unwrap()
+            .values()
+            .map(|transfer| transfer.info())
+            .collect();
+        infos.sort_by_key(|info| info.started_at);
+        infos
+    }
+
+    pub(crate) fn transfer_started(
+        &self,
+        client_addr: SocketAddr,
+        operation: Operation,
+        filename: &str,
+    ) -> Arc<Transfer> {
+        let transfer = Arc::new(Transfer::new(client_addr, operation, filename));
+        let mut transfers = self.transfers.lock().unwrap();
+        self.total_transfers.fetch_add(1, Ordering::Relaxed);
+        self.active_transfers.fetch_add(1, Ordering::Relaxed);
+        transfers.insert(transfer.key(), Arc::clone(&transfer));
+        transfer
+    }
+
+    pub(crate) fn transfer_finished(&self, transfer: &Transfer, success: bool) {
+        let mut transfers = self.transfers.lock().unwrap();
+        transfers.remove(&transfer.key());
+        self.active_transfers.fetch_sub(1, Ordering::Relaxed);
+        if !success {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        let counter = match (transfer.operation, success) {
+            (Operation::Read, true) => &self.rrq_completed,
+            (Operation::Read, false) => &self.rrq_failed,
+            (Operation::Write, true) => &self.wrq_completed,
+            (Operation::Write, false) => &self.wrq_failed,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Bytes sent by RRQ so far, which is cheaper than taking a snapshot.
+    pub(crate) fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn error_sent(&self, err: TftpError) {
+        self.errors_sent[err.error_code() as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Find the transfer registered by `transfer_started`.
+    /// An unregistered one is returned if not found (e.g. a handler called directly).
+    pub(crate) fn transfer(
+        &self,
+        client_addr: SocketAddr,
+        operation: Operation,
+        filename: &str,
+    ) -> Arc<Transfer> {
+        let key = (client_addr, operation, filename.to_string());
+        match self.transfers.lock().unwrap().get(&key) {
+            Some(transfer) => Arc::clone(transfer),
+            None => Arc::new(Transfer::new(client_addr, operation, filename)),
+        }
+    }
+
+    /// Request handlers of active transfers with the client to stop.
+    /// Returns the number of transfers cancelled.
+    pub(crate) fn cancel(&self, client_addr: SocketAddr) -> usize {
+        let transfers = self.transfers.lock().unwrap();
+        let targets: Vec<&Arc<Transfer>> = transfers
+            .values()
+            .filter(|transfer| transfer.client_addr == client_addr)
+            .collect();
+        for transfer in targets.iter() {
+            transfer.cancelled.store(true, Ordering::Relaxed);
+        }
+        targets.len()
+    }
+
+    /// Record the options negotiated for the transfer.
+    pub(crate) fn set_options(&self, transfer: &Transfer, options: &TransferOptions) {
+        transfer
+            .block_size
+            .store(options.block_size(), Ordering::Relaxed);
+        transfer
+            .window_size
+            .store(options.window_size(), Ordering::Relaxed);
+    }
+
+    /// Count data sent or received by the transfer.
+    pub(crate) fn add_bytes(&self, transfer: &Transfer, n: usize) {
+        transfer.bytes.fetch_add(n as u64, Ordering::Relaxed);
+        match transfer.operation {
+            Operation::Read => self.bytes_sent.fetch_add(n as u64, Ordering::Relaxed),
+            Operation::Write => self.bytes_received.fetch_add(n as u64, Ordering::Relaxed),
+        };
+    }
+}
+
+/// Log a summary of stats every `interval` until `term` is set.
+pub(crate) fn spawn_announcer(
+    stats: Arc<ServerStats>,
+    interval: Duration,
+    term: Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut last = StatsSnapshot::default();
+        loop {
+            thread::sleep(interval);
+            if term.load(Ordering::Relaxed) {
+                break;
+            }
+            let current = stats.snapshot();
+            info!("{}", announce_line(&current, &last));
+            last = current;
+        }
+    })
+}
+
+/// Format the rate of `bytes` transferred in `elapsed` human-readably (e.g. "1.50 MiB/s").
+pub fn format_throughput(bytes: u64, elapsed: Duration) -> String {
+    let secs = elapsed.as_secs_f64();
+    if secs == 0.0 {
+        return "-".to_string();
+    }
+    let rate = bytes as f64 / secs;
+    let units = ["KiB/s", "MiB/s", "GiB/s"];
+    if rate < 1024.0 {
+        return format!("{:.0} B/s", rate);
+    }
+    let mut value = rate / 1024.0;
+    let mut unit = units[0];
+    for next in units[1..].iter() {
+        if value < 1024.0 {
+            break;
+        }
+        value /= 1024.0;
+        unit = next;
+    }
+    format!("{:.2} {}", value, unit)
+}
+
+/// Errors are counted since the last announce, others are totals.
+fn announce_line(current: &StatsSnapshot, last: &StatsSnapshot) -> String {
+    format!(
+        "status: active={} transfers={} bytes_in={} bytes_out={} errors={}",
+        current.active_transfers,
+        current.total_transfers,
+        current.bytes_received,
+        current.bytes_sent,
+        current.errors - last.errors
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_throughput() {
+        assert_eq!(format_throughput(512, Duration::from_secs(2)), "256 B/s");
+        assert_eq!(
+            format_throughput(10 * 1024, Duration::from_secs(4)),
+            "2.50 KiB/s"
+        );
+        assert_eq!(
+            format_throughput(1536 * 1024, Duration::from_millis(1000)),
+            "1.50 MiB/s"
+        );
+        assert_eq!(
+            format_throughput(3 * 1024 * 1024 * 1024, Duration::from_millis(500)),
+            "6.00 GiB/s"
+        );
+        assert_eq!(format_throughput(1024, Duration::ZERO), "-");
+    }
+
+    #[test]
+    fn test_announce_line() {
+        let stats = ServerStats::default();
+        let client_addr = "127.0.0.1:10000".parse().unwrap();
+        let rrq = stats.transfer_started(client_addr, Operation::Read, "foo.txt");
+        let wrq = stats.transfer_started(client_addr, Operation::Write, "bar.txt");
+        stats.add_bytes(&rrq, 1024);
+        stats.add_bytes(&wrq, 10);
+        stats.transfer_finished(&rrq, false);
+        let last = stats.snapshot();
+        assert_eq!(
+            announce_line(&last, &StatsSnapshot::default()),
+            "status: active=1 transfers=2 bytes_in=10 bytes_out=1024 errors=1"
+        );
+
+        stats.transfer_finished(&wrq, true);
+        assert_eq!(
+            announce_line(&stats.snapshot(), &last),
+            "status: active=0 transfers=2 bytes_in=10 bytes_out=1024 errors=0"
+        );
+    }
+
+    #[test]
+    fn test_concurrent_transfers() {
+        const THREADS: u64 = 8;
+        const TRANSFERS: u64 = 200;
+        let stats = Arc::new(ServerStats::default());
+        let done = Arc::new(AtomicBool::new(false));
+
+        // check that every snapshot taken in the middle of transfers is consistent
+        let reader = {
+            let stats = Arc::clone(&stats);
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    let s = stats.snapshot();
+                    let finished = s.rrq_completed + s.rrq_failed + s.wrq_completed + s.wrq_failed;
+                    assert_eq!(s.active_transfers + finished, s.total_transfers);
+                    assert_eq!(s.rrq_failed + s.wrq_failed, s.errors);
+                }
+            })
+        };
+
+        let workers: Vec<_> = (0..THREADS)
+            .map(|i| {
+                let stats = Arc::clone(&stats);
+                thread::spawn(move || {
+                    let client_addr = SocketAddr::from(([127, 0, 0, 1], 10000 + i as u16));
+                    for j in 0..TRANSFERS {
+                        let operation = if j % 2 == 0 {
+                            Operation::Read
+                        } else {
+                            Operation::Write
+                        };
+                        let transfer =
+                            stats.transfer_started(client_addr, operation, &format!("{}", j));
+                        stats.add_bytes(&transfer, 512);
+                        stats.transfer_finished(&transfer, j % 4 < 2);
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        reader.join().unwrap();
+
+        let s = stats.snapshot();
+        let per_kind = THREADS * TRANSFERS / 4;
+        assert_eq!(s.total_transfers, THREADS * TRANSFERS);
+        assert_eq!(s.active_transfers, 0);
+        assert_eq!(s.rrq_completed, per_kind);
+        assert_eq!(s.wrq_completed, per_kind);
+        assert_eq!(s.rrq_failed, per_kind);
+        assert_eq!(s.wrq_failed, per_kind);
+        assert_eq!(s.errors, 2 * per_kind);
+        assert_eq!(s.bytes_sent, 512 * THREADS * TRANSFERS / 2);
+        assert_eq!(s.bytes_received, 512 * THREADS * TRANSFERS / 2);
+        assert!(stats.active_transfers().is_empty());
+    }
+}
@@ -1,8 +1,11 @@
-use anyhow::{Context, Result};
+use crate::error::{Error, Result};
+use anyhow::Context;
 use log::{debug, error};
 use rand::prelude::*;
 use std::env::temp_dir;
+use std::io;
 use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub struct TempDir {
     path: PathBuf,
@@ -37,13 +40,39 @@ impl Drop for TempDir {
 }
 
 pub fn generate_random_name() -> Result<String> {
-    let epoch_seconds = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)?
+    generate_name(&mut thread_rng(), SystemTime::now())
+}
+
+/// Generate a name in the same format as generate_random_name from the given rng and time.
+/// This is a seam for tests which need predictable names.
+pub fn generate_name<R: Rng + ?Sized>(rng: &mut R, now: SystemTime) -> Result<String> {
+    let epoch_seconds = now
+        .duration_since(UNIX_EPOCH)
+        .map_err(|err| Error::Io(io::Error::other(err)))?
         .as_secs();
-    let rand_str = String::from_iter(('a'..='z').choose_multiple(&mut thread_rng(), 7));
+    let rand_str = String::from_iter(('a'..='z').choose_multiple(rng, 7));
     Ok(format!("{}-{}", epoch_seconds, rand_str))
 }
 
 pub fn create_temp_dir() -> Result<TempDir> {
     TempDir::new()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use std::time::Duration;
+
+    #[test]
+    fn test_generate_name_with_seed() {
+        let now = UNIX_EPOCH + Duration::from_secs(1642636800);
+        let name = generate_name(&mut StdRng::seed_from_u64(42), now).unwrap();
+        assert!(name.starts_with("1642636800-"), "{}", name);
+        // the same seed generates the same name, and another seed doesn't
+        let same = generate_name(&mut StdRng::seed_from_u64(42), now).unwrap();
+        assert_eq!(name, same);
+        let other = generate_name(&mut StdRng::seed_from_u64(43), now).unwrap();
+        assert_ne!(name, other);
+    }
+}
@@ -0,0 +1,91 @@
+//! Support for tests.
+
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// A UDP relay between a client and a handler, which drops a fraction of datagrams
+/// and delays the others, to test loss recovery with real sockets.
+///
+/// The handler is given `addr()` as the client address, and the client sends packets to `addr()`.
+/// Drops are decided by a seeded rng, so a test is deterministic as long as
+/// the order of datagrams doesn't depend on timing (e.g. only one packet is in flight).
+pub struct FaultyTransport {
+    addr: SocketAddr,
+    dropped: Arc<AtomicUsize>,
+    term: Arc<AtomicBool>,
+    relay: Option<JoinHandle<()>>,
+}
+
+impl FaultyTransport {
+    pub fn start(
+        client_addr: SocketAddr,
+        handler_addr: SocketAddr,
+        loss_rate: f64,
+        latency: Duration,
+        seed: u64,
+    ) -> FaultyTransport {
+        let sock = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        sock.set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        let addr = sock.local_addr().unwrap();
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let term = Arc::new(AtomicBool::new(false));
+
+        let relay = {
+            let dropped = Arc::clone(&dropped);
+            let term = Arc::clone(&term);
+            thread::spawn(move || {
+                let mut rng = StdRng::seed_from_u64(seed);
+                let mut buf = vec![0; 65536];
+                while !term.load(Ordering::Relaxed) {
+                    let (n, src) = match sock.recv_from(&mut buf) {
+                        Ok(res) => res,
+                        Err(_) => continue,
+                    };
+                    let dest = if src == handler_addr {
+                        client_addr
+                    } else {
+                        handler_addr
+                    };
+                    if rng.gen_bool(loss_rate) {
+                        dropped.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                    thread::sleep(latency);
+                    let _ = sock.send_to(&buf[..n], dest);
+                }
+            })
+        };
+
+        FaultyTransport {
+            addr,
+            dropped,
+            term,
+            relay: Some(relay),
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The number of datagrams dropped so far.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for FaultyTransport {
+    fn drop(&mut self) {
+        self.term.store(true, Ordering::Relaxed);
+        if let Some(relay) = self.relay.take() {
+            let _ = relay.join();
+        }
+    }
+}
@@ -0,0 +1,85 @@
+/// The number of DATA blocks sent before waiting for an ACK in RRQ (RFC 7440).
+///
+/// A fixed window always has the negotiated windowsize.
+/// An adaptive window starts from one block and is controlled by AIMD up to the negotiated windowsize:
+/// it grows by one block when a whole window is acknowledged, and is halved on loss.
+/// It sends fewer blocks than the negotiated windowsize, so the client must acknowledge a burst
+/// shorter than that (e.g. when DATA stop arriving) rather than waiting for a full window.
+#[derive(Debug)]
+pub struct Window {
+    size: u16,
+    max: u16,
+    adaptive: bool,
+}
+
+impl Window {
+    pub fn new(max: u16, adaptive: bool) -> Window {
+        let max = max.max(1);
+        Window {
+            size: if adaptive { 1 } else { max },
+            max,
+            adaptive,
+        }
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// All blocks in the window are acknowledged.
+    pub fn on_ack(&mut self) {
+        if self.adaptive {
+            self.size = self.size.saturating_add(1).min(self.max);
+        }
+    }
+
+    /// Some blocks in the window seem to be lost (a partial ACK or timeout).
+    pub fn on_loss(&mut self) {
+        if self.adaptive {
+            self.size = (self.size / 2).max(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adaptive_window() {
+        let mut window = Window::new(4, true);
+        assert_eq!(window.size(), 1);
+        window.on_ack();
+        window.on_ack();
+        assert_eq!(window.size(), 3);
+        window.on_ack();
+        window.on_ack();
+        assert_eq!(window.size(), 4);
+        window.on_loss();
+        assert_eq!(window.size(), 2);
+        window.on_loss();
+        window.on_loss();
+        assert_eq!(window.size(), 1);
+    }
+
+    #[test]
+    fn test_adaptive_window_at_max_windowsize() {
+        let mut window = Window::new(u16::MAX, true);
+        for _ in 0..u16::MAX {
+            window.on_ack();
+        }
+        assert_eq!(window.size(), u16::MAX);
+        window.on_ack();
+        assert_eq!(window.size(), u16::MAX);
+    }
+
+    #[test]
+    fn test_fixed_window() {
+        let mut window = Window::new(4, false);
+        assert_eq!(window.size(), 4);
+        window.on_loss();
+        assert_eq!(window.size(), 4);
+        window.on_ack();
+        assert_eq!(window.size(), 4);
+    }
+}
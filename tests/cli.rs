@@ -0,0 +1,276 @@
+//! Tests which run the tftpff binary.
+//!
+//! These tests need root privilege (the binary drops privilege to the given user)
+//! and `curl` with tftp support as a client, so they are skipped otherwise.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Lines};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process::{Child, ChildStderr, Command, Stdio};
+use std::str::FromStr;
+use tftpff::temp;
+
+fn can_run() -> bool {
+    let has_curl = match Command::new("curl").arg("--version").output() {
+        Ok(output) => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .any(|line| line.starts_with("Protocols:") && line.contains("tftp")),
+        Err(_) => false,
+    };
+    has_curl && nix::unistd::geteuid().is_root()
+}
+
+/// Kill the server when a test finishes.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Start the server and returns it with the address, logs before serving and following logs.
+fn start_server(
+    args: &[&str],
+    current_dir: &Path,
+) -> (
+    Server,
+    SocketAddr,
+    Vec<String>,
+    Lines<BufReader<ChildStderr>>,
+) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_tftpff"))
+        .args(["--addr", "127.0.0.1", "--port", "0"])
+        .args(args)
+        .current_dir(current_dir)
+        .env("RUST_LOG", "info")
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let server = Server(child);
+
+    let mut logs = vec![];
+    let mut lines = BufReader::new(stderr).lines();
+    for line in lines.by_ref() {
+        let line = line.unwrap();
+        if let Some((_, addr)) = line.split_once("start serving on ") {
+            let addr = SocketAddr::from_str(addr.trim()).unwrap();
+            return (server, addr, logs, lines);
+        }
+        logs.push(line);
+    }
+    panic!("server exited before serving: {:?}", logs);
+}
+
+fn get_with_curl(addr: SocketAddr, filename: &str, output: &Path) {
+    let status = Command::new("curl")
+        .args(["--silent", "--show-error", "--max-time", "30"])
+        .arg(format!("tftp://{}/{}", addr, filename))
+        .arg("--output")
+        .arg(output)
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+#[test]
+fn test_relative_base_dir() {
+    if !can_run() {
+        eprintln!("skip test_relative_base_dir: root privilege and curl are required");
+        return;
+    }
+
+    //
+    // setup
+    //
+    let work_dir = temp::create_temp_dir().unwrap();
+    let client_dir = temp::create_temp_dir().unwrap();
+    fs::create_dir(work_dir.path().join("files")).unwrap();
+    fs::write(work_dir.path().join("files").join("foo.txt"), b"hello").unwrap();
+
+    //
+    // exercise
+    //
+    let (_server, addr, logs, _) = start_server(&["--dir", "files"], work_dir.path());
+    let output = client_dir.path().join("foo.txt");
+    get_with_curl(addr, "foo.txt", &output);
+
+    //
+    // verify
+    //
+    assert_eq!(fs::read(output).unwrap(), b"hello");
+    let expected = work_dir.path().join("files").canonicalize().unwrap();
+    assert!(
+        logs.iter()
+            .any(|line| line.contains(&format!("base directory: {:?}", expected))),
+        "{:?}",
+        logs
+    );
+}
+
+#[test]
+fn test_rrq_checksum() {
+    if !can_run() {
+        eprintln!("skip test_rrq_checksum: root privilege and curl are required");
+        return;
+    }
+
+    //
+    // setup
+    //
+    let base_dir = temp::create_temp_dir().unwrap();
+    let client_dir = temp::create_temp_dir().unwrap();
+    fs::write(base_dir.path().join("foo.txt"), b"123456789").unwrap();
+    let dir = base_dir.path().to_str().unwrap();
+
+    //
+    // exercise
+    //
+    let (_server, addr, _, lines) =
+        start_server(&["--dir", dir, "--rrq-checksum"], base_dir.path());
+    get_with_curl(addr, "foo.txt", &client_dir.path().join("foo.txt"));
+
+    //
+    // verify
+    //
+    let line = lines
+        .map(|line| line.unwrap())
+        .find(|line| line.contains("crc32="))
+        .unwrap();
+    assert!(
+        line.contains(r#"sent "foo.txt" with crc32=cbf43926"#),
+        "{}",
+        line
+    );
+}
+
+#[test]
+fn test_log_interval() {
+    if !can_run() {
+        eprintln!("skip test_log_interval: root privilege and curl are required");
+        return;
+    }
+
+    //
+    // setup
+    //
+    let base_dir = temp::create_temp_dir().unwrap();
+    let client_dir = temp::create_temp_dir().unwrap();
+    fs::write(base_dir.path().join("foo.txt"), b"123456789").unwrap();
+    let dir = base_dir.path().to_str().unwrap();
+
+    //
+    // exercise
+    //
+    let (_server, addr, _, lines) =
+        start_server(&["--dir", dir, "--log-interval", "1"], base_dir.path());
+    get_with_curl(addr, "foo.txt", &client_dir.path().join("foo.txt"));
+
+    //
+    // verify
+    //
+    // the status is logged every second, so it should be found soon
+    let found = lines
+        .take(20)
+        .map(|line| line.unwrap())
+        .any(|line| line.contains("status: active=0 transfers=1 bytes_in=0 bytes_out=9 errors=0"));
+    assert!(found);
+}
+
+#[test]
+fn test_survive_closed_stderr() {
+    if !can_run() {
+        eprintln!("skip test_survive_closed_stderr: root privilege and curl are required");
+        return;
+    }
+
+    //
+    // setup
+    //
+    let base_dir = temp::create_temp_dir().unwrap();
+    let client_dir = temp::create_temp_dir().unwrap();
+    fs::write(base_dir.path().join("foo.txt"), b"hello").unwrap();
+    let dir = base_dir.path().to_str().unwrap();
+    let (mut server, addr, _, lines) =
+        start_server(&["--dir", dir, "--rrq-checksum"], base_dir.path());
+
+    //
+    // exercise
+    //
+    // the server gets EPIPE when logging the checksum
+    drop(lines);
+    get_with_curl(addr, "foo.txt", &client_dir.path().join("foo1.txt"));
+
+    //
+    // verify
+    //
+    get_with_curl(addr, "foo.txt", &client_dir.path().join("foo2.txt"));
+    assert_eq!(
+        fs::read(client_dir.path().join("foo2.txt")).unwrap(),
+        b"hello"
+    );
+    assert!(server.0.try_wait().unwrap().is_none());
+}
+
+#[test]
+fn test_max_window_size_out_of_range() {
+    //
+    // exercise
+    //
+    let output = Command::new(env!("CARGO_BIN_EXE_tftpff"))
+        .args(["--dir", ".", "--max-window-size", "32768"])
+        .output()
+        .unwrap();
+
+    //
+    // verify
+    //
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("expected a number up to 32767"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_chroot_without_user() {
+    if !nix::unistd::geteuid().is_root() {
+        eprintln!("skip test_chroot_without_user: root privilege is required");
+        return;
+    }
+
+    //
+    // setup
+    //
+    let base_dir = temp::create_temp_dir().unwrap();
+    let dir = base_dir.path().to_str().unwrap();
+
+    //
+    // exercise
+    //
+    // the default user is root
+    let output = Command::new(env!("CARGO_BIN_EXE_tftpff"))
+        .args([
+            "--addr",
+            "127.0.0.1",
+            "--port",
+            "0",
+            "--dir",
+            dir,
+            "--chroot",
+        ])
+        .output()
+        .unwrap();
+
+    //
+    // verify
+    //
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("other than root"), "{}", stderr);
+}
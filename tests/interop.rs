@@ -0,0 +1,168 @@
+//! Interoperability tests with an external tftp client.
+//!
+//! These tests use `curl` (which supports tftp) and are skipped if it is not installed
+//! or built without tftp support.
+
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+use tftpff::config::Config;
+use tftpff::server::TftpServer;
+use tftpff::temp;
+
+fn curl_supports_tftp() -> bool {
+    match Command::new("curl").arg("--version").output() {
+        Ok(output) => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .any(|line| line.starts_with("Protocols:") && line.contains("tftp")),
+        Err(_) => false,
+    }
+}
+
+fn start_server(base_dir: &Path, temp_dir: &Path) -> SocketAddr {
+    let mut server = TftpServer::create(
+        Ipv4Addr::from_str("127.0.0.1").unwrap(),
+        0,
+        base_dir.to_owned(),
+        temp_dir.to_owned(),
+        Config::default(),
+    )
+    .unwrap();
+    server.bind().unwrap();
+    let addr = server.server_addr().unwrap();
+    thread::spawn(move || server.run().unwrap());
+    addr
+}
+
+fn run_curl(args: &[&str]) {
+    let status = Command::new("curl")
+        .args(["--silent", "--show-error", "--max-time", "30"])
+        .args(args)
+        .status()
+        .unwrap();
+    assert!(status.success(), "curl failed: {:?}", args);
+}
+
+/// Content which spans several blocks and contains bytes affected by netascii.
+fn test_content() -> Vec<u8> {
+    (0..(512 * 4 + 100))
+        .map(|i| match i % 64 {
+            10 => b'\n',
+            20 => b'\r',
+            _ => b'a' + (i % 26) as u8,
+        })
+        .collect()
+}
+
+#[test]
+fn test_get_with_curl() {
+    if !curl_supports_tftp() {
+        eprintln!("skip test_get_with_curl: curl with tftp support is not found");
+        return;
+    }
+
+    //
+    // setup
+    //
+    let base_dir = temp::create_temp_dir().unwrap();
+    let server_temp_dir = temp::create_temp_dir().unwrap();
+    let client_dir = temp::create_temp_dir().unwrap();
+    let content = test_content();
+    fs::write(base_dir.path().join("get.bin"), &content).unwrap();
+    let addr = start_server(base_dir.path(), server_temp_dir.path());
+
+    //
+    // exercise
+    //
+    let output = client_dir.path().join("get.bin");
+    run_curl(&[
+        &format!("tftp://{}/get.bin", addr),
+        "--output",
+        output.to_str().unwrap(),
+    ]);
+
+    //
+    // verify
+    //
+    assert_eq!(fs::read(output).unwrap(), content);
+}
+
+#[test]
+fn test_put_with_curl() {
+    if !curl_supports_tftp() {
+        eprintln!("skip test_put_with_curl: curl with tftp support is not found");
+        return;
+    }
+
+    //
+    // setup
+    //
+    let base_dir = temp::create_temp_dir().unwrap();
+    let server_temp_dir = temp::create_temp_dir().unwrap();
+    let client_dir = temp::create_temp_dir().unwrap();
+    let content = test_content();
+    let input = client_dir.path().join("put.bin");
+    fs::write(&input, &content).unwrap();
+    let addr = start_server(base_dir.path(), server_temp_dir.path());
+
+    //
+    // exercise
+    //
+    run_curl(&[
+        &format!("tftp://{}/put.bin", addr),
+        "--upload-file",
+        input.to_str().unwrap(),
+    ]);
+
+    //
+    // verify
+    //
+    // the server saves the file after sending the last ACK, so wait for it
+    let dest = base_dir.path().join("put.bin");
+    for _ in 0..50 {
+        if fs::read(&dest).ok().as_ref() == Some(&content) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(fs::read(dest).unwrap(), content);
+}
+
+#[test]
+fn test_get_with_curl_and_blksize() {
+    if !curl_supports_tftp() {
+        eprintln!("skip test_get_with_curl_and_blksize: curl with tftp support is not found");
+        return;
+    }
+
+    //
+    // setup
+    //
+    let base_dir = temp::create_temp_dir().unwrap();
+    let server_temp_dir = temp::create_temp_dir().unwrap();
+    let client_dir = temp::create_temp_dir().unwrap();
+    let content = test_content();
+    fs::write(base_dir.path().join("get.bin"), &content).unwrap();
+    let addr = start_server(base_dir.path(), server_temp_dir.path());
+
+    //
+    // exercise
+    //
+    let output = client_dir.path().join("get.bin");
+    run_curl(&[
+        &format!("tftp://{}/get.bin", addr),
+        "--tftp-blksize",
+        "1024",
+        "--output",
+        output.to_str().unwrap(),
+    ]);
+
+    //
+    // verify
+    //
+    assert_eq!(fs::read(output).unwrap(), content);
+}